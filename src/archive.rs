@@ -0,0 +1,64 @@
+//! Reading of zip archives, for both Yomichan and Kobo dictionaries.
+//!
+//! Archives made on Windows often store their member names in the system's
+//! legacy encoding rather than UTF-8, which for Japanese dictionaries
+//! usually means Shift_JIS.  So member names are always decoded from their
+//! raw bytes here, rather than trusting the zip crate's decoding of them.
+//! Zip64 archives (for members or archives over 4GB, or with more than
+//! 65535 members) are handled by the zip crate itself.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+pub type Archive = zip::ZipArchive<BufReader<File>>;
+
+pub fn open(path: &Path) -> std::io::Result<Archive> {
+    zip::ZipArchive::new(BufReader::new(File::open(path)?)).map_err(|e| match e {
+        zip::result::ZipError::Io(e) => e,
+        e => std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("not a valid zip file ({})", e),
+        ),
+    })
+}
+
+/// Decodes a raw member name: as UTF-8 if it's valid UTF-8, otherwise as
+/// Shift_JIS, and as a last resort as UTF-8 with the invalid parts
+/// replaced.
+pub fn member_name(raw: &[u8]) -> String {
+    if let Ok(name) = std::str::from_utf8(raw) {
+        return name.into();
+    }
+    let (name, had_errors) = encoding_rs::SHIFT_JIS.decode_without_bom_handling(raw);
+    if !had_errors {
+        return name.into();
+    }
+    String::from_utf8_lossy(raw).into()
+}
+
+/// Returns the (decoded) names of all the archive's members, in archive
+/// order.  The position of a name is the member's index.
+pub fn member_names(zip: &mut Archive) -> std::io::Result<Vec<String>> {
+    let mut names = Vec::with_capacity(zip.len());
+    for i in 0..zip.len() {
+        names.push(member_name(zip.by_index_raw(i)?.name_raw()));
+    }
+    Ok(names)
+}
+
+/// Returns the index of the member with the given name, if there is one.
+pub fn find_member(zip: &mut Archive, name: &str) -> std::io::Result<Option<usize>> {
+    Ok(member_names(zip)?.iter().position(|n| n == name))
+}
+
+/// Builds a name -> index map of the archive's members, for repeated
+/// lookups.
+pub fn member_index(zip: &mut Archive) -> std::io::Result<HashMap<String, usize>> {
+    Ok(member_names(zip)?
+        .drain(..)
+        .enumerate()
+        .map(|(i, name)| (name, i))
+        .collect())
+}
@@ -0,0 +1,99 @@
+//! A cache of the parsed bundled JMDict data, so that the xml doesn't
+//! have to be parsed again on every run.
+//!
+//! The cache is a bincode snapshot of the parsed entries, stored in the
+//! temporary directory (the system's, or the one given with --tmpdir).
+//! Its file name includes a hash of everything that affects the parsed
+//! result, so a stale cache is never used: it's simply not found, and a
+//! new one is written.
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+
+use crate::jmdict::{Parser, PriorityModel, WordEntry};
+
+/// Bump this whenever the parser or the parsed types change in a way
+/// that affects the cached data.
+const CACHE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct ParsedJmdict {
+    pub entries: Vec<WordEntry>,
+
+    // The tags that the parser didn't recognize, with the number of
+    // times each was found.  See `jmdict::Parser::unknown_tags()`.
+    pub unknown_tags: Vec<(String, usize)>,
+}
+
+/// Parses the gzipped JMDict xml data, or loads the result of a previous
+/// run from the cache if there is one.  An unreadable cache is ignored,
+/// and failing to write one only causes a warning.
+pub fn load_jmdict(
+    data: &[u8],
+    priority_model: &PriorityModel,
+    use_cache: bool,
+    cache_dir: &Path,
+) -> ParsedJmdict {
+    let path = cache_path(data, priority_model, cache_dir);
+    if use_cache {
+        if let Ok(bytes) = std::fs::read(&path) {
+            if let Ok(parsed) = bincode::deserialize(&bytes) {
+                return parsed;
+            }
+        }
+    }
+
+    let mut parser = Parser::from_reader(BufReader::new(GzDecoder::new(data)));
+    parser.set_priority_model(priority_model.clone());
+    let entries = parser.by_ref().collect();
+    let parsed = ParsedJmdict {
+        entries,
+        unknown_tags: parser
+            .unknown_tags()
+            .map(|(tag, count)| (tag.into(), count))
+            .collect(),
+    };
+
+    if use_cache {
+        if let Err(e) = write_cache(&parsed, &path) {
+            warning!(
+                "couldn't write the JMDict cache to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    parsed
+}
+
+fn cache_path(data: &[u8], priority_model: &PriorityModel, cache_dir: &Path) -> PathBuf {
+    let mut hasher = Md5::new();
+    hasher.update(CACHE_VERSION.to_le_bytes());
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.update(format!("{:?}", priority_model).as_bytes());
+    hasher.update(data);
+    let hash: String = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    cache_dir.join(format!("kobo_jp_dict-jmdict-{}.bin", hash))
+}
+
+/// Writes the cache via a temporary file, so that an interrupted write
+/// never leaves a partial cache behind.
+fn write_cache(parsed: &ParsedJmdict, path: &Path) -> std::io::Result<()> {
+    let bytes = bincode::serialize(parsed).map_err(std::io::Error::other)?;
+    let dir = path.parent().unwrap();
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(&bytes)?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
@@ -0,0 +1,179 @@
+//! The configuration file, given with `--config`.
+//!
+//! It's a json object with settings for individual Yomichan dictionaries,
+//! keyed by their titles (matched like with `--dict_order`):
+//!
+//! ```json
+//! {
+//!     "dictionaries": {
+//!         "大辞林": { "header": "off" },
+//!         "明鏡国語辞典": { "header": { "regex": "^[^\n]*【[^\n]*】\n" } },
+//!         "新明解国語辞典": { "dividers": ["^[❶❷❸❹❺❻❼❽❾❿]", "^[①②③④⑤⑥⑦⑧⑨⑩]"] },
+//!         "JMDict": { "label": false, "terms": "english" }
+//!     }
+//! }
+//! ```
+//!
+//! `header` sets how entry headers are stripped from the start of each
+//! definition: `"off"`, `"first-line"` (the default, which drops the first
+//! line if it contains the word), or a regex, whose match at the start of a
+//! definition is dropped.
+//!
+//! `dividers` is a list of regexes that split definitions into numbered
+//! senses, one per nesting level, from the outermost in.  They're in
+//! multi-line mode, so `^` matches at the start of each line.
+//!
+//! `label` is the name shown above the dictionary's definitions in entries
+//! with several dictionaries, in place of its title, or `false` to show no
+//! name at all.
+//!
+//! `terms` overrides `-j`/`-m` for the entries whose definitions start with
+//! the dictionary's: `"english"`, `"move"`, or `"japanese"` terms are used
+//! in their headers and conjugation tables.  For example, with Japanese
+//! terms for a monolingual dictionary and English ones for JMDict, entries
+//! that fall back to JMDict's definitions keep English headers.
+//!
+//! There's also a top-level `priority` object, with the weights used to
+//! rank words by JMDict's priority markers (see `jmdict::PriorityModel`):
+//!
+//! ```json
+//! { "priority": { "nf_step": 500, "tier1": 6000, "tier2": 18000, "other": 24000, "unmarked": 100000 } }
+//! ```
+//!
+//! Lower values rank higher, and weights that aren't given keep their
+//! defaults.
+
+use std::collections::HashMap;
+
+use regex::{Regex, RegexBuilder};
+use serde_json::Value;
+
+use crate::jmdict::PriorityModel;
+use crate::yomichan::HeaderStripping;
+use crate::LangMode;
+
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    dictionaries: HashMap<String, DictConfig>, // Normalized title -> config
+    pub priority_model: PriorityModel,
+}
+
+/// Settings for a single Yomichan dictionary.  Unset settings use the
+/// defaults.
+#[derive(Clone, Debug, Default)]
+pub struct DictConfig {
+    pub header: Option<HeaderStripping>,
+    pub dividers: Option<Vec<Regex>>,
+    pub label: Option<Option<String>>, // `Some(None)` hides the label.
+    pub terms: Option<LangMode>,
+}
+
+impl Config {
+    pub fn from_json(text: &str) -> Result<Config, String> {
+        let json: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let mut config = Config::default();
+
+        if let Some(priority) = json.get("priority") {
+            let priority = priority
+                .as_object()
+                .ok_or_else(|| "\"priority\": expected an object".to_string())?;
+            let model = &mut config.priority_model;
+            for (name, weight) in priority.iter() {
+                let weight = weight
+                    .as_u64()
+                    .filter(|&w| w <= u32::MAX as u64 / 2)
+                    .ok_or_else(|| format!("\"priority\": invalid weight for \"{}\"", name))?
+                    as u32;
+                match name.as_str() {
+                    "nf_step" => model.nf_step = weight,
+                    "tier1" => model.tier1 = weight,
+                    "tier2" => model.tier2 = weight,
+                    "other" => model.other = weight,
+                    "unmarked" => model.unmarked = weight,
+                    _ => return Err(format!("\"priority\": unknown weight \"{}\"", name)),
+                }
+            }
+        }
+
+        let dictionaries = match json.get("dictionaries") {
+            Some(dictionaries) => dictionaries
+                .as_object()
+                .ok_or_else(|| "\"dictionaries\": expected an object".to_string())?,
+            None => return Ok(config),
+        };
+        for (title, dict_json) in dictionaries.iter() {
+            let mut dict_config = DictConfig::default();
+            if let Some(header) = dict_json.get("header") {
+                dict_config.header = Some(match (header.as_str(), header.get("regex")) {
+                    (Some("off"), _) => HeaderStripping::Off,
+                    (Some("first-line"), _) => HeaderStripping::FirstLine,
+                    (None, Some(Value::String(regex))) => HeaderStripping::Regex(
+                        Regex::new(regex).map_err(|e| format!("\"{}\": {}", title, e))?,
+                    ),
+                    _ => return Err(format!("\"{}\": invalid \"header\" setting", title)),
+                });
+            }
+            if let Some(dividers) = dict_json.get("dividers") {
+                let dividers = dividers
+                    .as_array()
+                    .ok_or_else(|| format!("\"{}\": \"dividers\" must be a list", title))?;
+                let mut regexes = Vec::new();
+                for divider in dividers.iter() {
+                    let divider = divider
+                        .as_str()
+                        .ok_or_else(|| format!("\"{}\": dividers must be strings", title))?;
+                    regexes.push(
+                        RegexBuilder::new(divider)
+                            .multi_line(true)
+                            .build()
+                            .map_err(|e| format!("\"{}\": {}", title, e))?,
+                    );
+                }
+                dict_config.dividers = Some(regexes);
+            }
+            if let Some(label) = dict_json.get("label") {
+                dict_config.label = Some(match label {
+                    Value::String(label) => Some(label.clone()),
+                    Value::Bool(false) => None,
+                    _ => return Err(format!("\"{}\": invalid \"label\" setting", title)),
+                });
+            }
+            if let Some(terms) = dict_json.get("terms") {
+                dict_config.terms = Some(match terms.as_str() {
+                    Some("english") => LangMode::English,
+                    Some("move") => LangMode::EnglishAlt,
+                    Some("japanese") => LangMode::Japanese,
+                    _ => return Err(format!("\"{}\": invalid \"terms\" setting", title)),
+                });
+            }
+            config
+                .dictionaries
+                .insert(crate::normalize_dict_title(title), dict_config);
+        }
+
+        Ok(config)
+    }
+
+    /// The settings for the dictionary with the given (normalized) title.
+    pub fn dictionary(&self, title: &str) -> DictConfig {
+        self.dictionaries.get(title).cloned().unwrap_or_default()
+    }
+
+    /// The `label` settings of all dictionaries that have one, keyed by
+    /// normalized title.
+    pub fn labels(&self) -> HashMap<String, Option<String>> {
+        self.dictionaries
+            .iter()
+            .filter_map(|(title, dict)| Some((title.clone(), dict.label.clone()?)))
+            .collect()
+    }
+
+    /// The `terms` settings of all dictionaries that have one, keyed by
+    /// normalized title.
+    pub fn terms(&self) -> HashMap<String, LangMode> {
+        self.dictionaries
+            .iter()
+            .filter_map(|(title, dict)| Some((title.clone(), dict.terms?)))
+            .collect()
+    }
+}
@@ -0,0 +1,170 @@
+//! Conjugation tables for verbs and i-adjectives.
+//!
+//! Unlike the inflection rules, which generate every conjugated form the
+//! Kobo should be able to look up, this produces a handful of basic forms
+//! of a word for display at the end of its entry.
+
+use crate::jmdict::ConjugationClass;
+
+/// The forms in a conjugation table, in order.
+pub const FORMS: &[&str] = &[
+    "negative",
+    "past",
+    "te-form",
+    "potential",
+    "passive",
+    "causative",
+    "volitional",
+    "polite",
+];
+
+/// Returns the basic conjugated forms of a word, as (form, conjugation)
+/// pairs with the form names from `FORMS`.
+///
+/// Forms that don't exist for the word (such as the passive of an
+/// adjective) are left out, and words of other conjugation classes get an
+/// empty table.
+pub fn conjugation_table(word: &str, conj: ConjugationClass) -> Vec<(&'static str, String)> {
+    FORMS
+        .iter()
+        .filter_map(|form| conjugate(word, conj, form).map(|c| (*form, c)))
+        .collect()
+}
+
+fn conjugate(word: &str, conj: ConjugationClass, form: &str) -> Option<String> {
+    use ConjugationClass::*;
+
+    match conj {
+        IchidanVerb | KureruVerb => {
+            let stem = word.strip_suffix('る')?;
+            let ending = match form {
+                "negative" => "ない",
+                "past" => "た",
+                "te-form" => "て",
+                "potential" | "passive" => "られる",
+                "causative" => "させる",
+                "volitional" => "よう",
+                "polite" => "ます",
+                _ => return None,
+            };
+            Some(format!("{}{}", stem, ending))
+        }
+
+        GodanVerbU | GodanVerbTsu | GodanVerbRu | GodanVerbKu | GodanVerbGu | GodanVerbNu
+        | GodanVerbBu | GodanVerbMu | GodanVerbSu | IkuVerb | AruVerb | SharuVerb => {
+            let last = word.chars().last()?;
+            let stem = &word[..(word.len() - last.len_utf8())];
+            let (a, i, e, o, past) = match (conj, last) {
+                (IkuVerb, 'く') => ("か", "き", "け", "こ", "った"),
+                (SharuVerb, 'る') => ("ら", "い", "れ", "ろ", "った"),
+                (_, 'う') => ("わ", "い", "え", "お", "った"),
+                (_, 'つ') => ("た", "ち", "て", "と", "った"),
+                (_, 'る') => ("ら", "り", "れ", "ろ", "った"),
+                (_, 'く') => ("か", "き", "け", "こ", "いた"),
+                (_, 'ぐ') => ("が", "ぎ", "げ", "ご", "いだ"),
+                (_, 'ぬ') => ("な", "に", "ね", "の", "んだ"),
+                (_, 'ぶ') => ("ば", "び", "べ", "ぼ", "んだ"),
+                (_, 'む') => ("ま", "み", "め", "も", "んだ"),
+                (_, 'す') => ("さ", "し", "せ", "そ", "した"),
+                _ => return None,
+            };
+            match form {
+                // ある has no negative of its own, and uses ない instead.
+                "negative" if conj == AruVerb => {
+                    Some(format!("{}ない", word.strip_suffix("ある")?))
+                }
+                "potential" if conj == AruVerb => None,
+                "negative" => Some(format!("{}{}ない", stem, a)),
+                "past" => Some(format!("{}{}", stem, past)),
+                "te-form" => Some(format!("{}{}", stem, te_form(past))),
+                "potential" => Some(format!("{}{}る", stem, e)),
+                "passive" => Some(format!("{}{}れる", stem, a)),
+                "causative" => Some(format!("{}{}せる", stem, a)),
+                "volitional" => Some(format!("{}{}う", stem, o)),
+                "polite" => Some(format!("{}{}ます", stem, i)),
+                _ => None,
+            }
+        }
+
+        SuruVerb => {
+            let stem = word.strip_suffix("する")?;
+            let ending = match form {
+                "negative" => "しない",
+                "past" => "した",
+                "te-form" => "して",
+                "potential" => "できる",
+                "passive" => "される",
+                "causative" => "させる",
+                "volitional" => "しよう",
+                "polite" => "します",
+                _ => return None,
+            };
+            Some(format!("{}{}", stem, ending))
+        }
+
+        // Verbs like 愛する, which mostly conjugate like godan す verbs.
+        SuruVerbSC => {
+            let stem = word.strip_suffix("する")?;
+            let ending = match form {
+                "negative" => "さない",
+                "past" => "した",
+                "te-form" => "して",
+                "potential" => "せる",
+                "passive" => "される",
+                "causative" => "させる",
+                "volitional" => "そう",
+                "polite" => "します",
+                _ => return None,
+            };
+            Some(format!("{}{}", stem, ending))
+        }
+
+        KuruVerb => {
+            // The stem's reading changes, but in kanji it's always 来.
+            let (stem, ko, ki) = if let Some(stem) = word.strip_suffix("来る") {
+                (stem, "来", "来")
+            } else {
+                (word.strip_suffix("くる")?, "こ", "き")
+            };
+            Some(match form {
+                "negative" => format!("{}{}ない", stem, ko),
+                "past" => format!("{}{}た", stem, ki),
+                "te-form" => format!("{}{}て", stem, ki),
+                "potential" | "passive" => format!("{}{}られる", stem, ko),
+                "causative" => format!("{}{}させる", stem, ko),
+                "volitional" => format!("{}{}よう", stem, ko),
+                "polite" => format!("{}{}ます", stem, ki),
+                _ => return None,
+            })
+        }
+
+        IAdjective | IrregularIAdjective => {
+            let stem = if conj == IrregularIAdjective {
+                format!("{}よ", word.strip_suffix("いい")?)
+            } else {
+                word.strip_suffix('い')?.to_string()
+            };
+            Some(match form {
+                "negative" => format!("{}くない", stem),
+                "past" => format!("{}かった", stem),
+                "te-form" => format!("{}くて", stem),
+                "polite" => format!("{}です", word),
+                _ => return None,
+            })
+        }
+
+        // Copulas, classical verbs, and other irregular words are too varied
+        // to tabulate.
+        Other | Copula | GodanVerbHu | IrregularVerb => None,
+    }
+}
+
+/// Converts a past-tense ending to the corresponding te-form ending.
+fn te_form(past: &str) -> String {
+    let mut te = past.to_string();
+    match te.pop() {
+        Some('だ') => te.push('で'),
+        _ => te.push('て'),
+    }
+    te
+}
@@ -0,0 +1,68 @@
+//! Numbers and counters.
+//!
+//! Counters (JMDict's "ctr" words, like 本 or 匹) are almost always written
+//! right after a number, and Kobo tends to select the number along with
+//! them, so counter entries get extra look-up keys with common numbers
+//! attached.  Numbers themselves can also be given entries of their own,
+//! so that e.g. 三百 can be looked up.
+
+/// The numbers that are attached to counters for look-up keys.
+pub const COUNTER_NUMERALS: &[&str] = &[
+    "一", "二", "三", "四", "五", "六", "七", "八", "九", "十", "百", "千", "万", "何", "数", "幾",
+];
+
+const DIGITS: &[(&str, &str)] = &[
+    ("", ""),
+    ("一", "いち"),
+    ("二", "に"),
+    ("三", "さん"),
+    ("四", "よん"),
+    ("五", "ご"),
+    ("六", "ろく"),
+    ("七", "なな"),
+    ("八", "はち"),
+    ("九", "きゅう"),
+];
+
+/// Returns the kanji numeral and its reading for a number from 1 to 9999.
+pub fn kanji_number(n: u32) -> (String, String) {
+    assert!(n > 0 && n < 10000);
+
+    let mut kanji = String::new();
+    let mut reading = String::new();
+
+    // Thousands, hundreds, and tens, which leave out the digit for one and
+    // have some sound changes.
+    let places = [
+        (n / 1000, "千", "せん"),
+        (n / 100 % 10, "百", "ひゃく"),
+        (n / 10 % 10, "十", "じゅう"),
+    ];
+    for (digit, unit, unit_reading) in places.iter() {
+        let digit = *digit as usize;
+        if digit == 0 {
+            continue;
+        }
+        let (digit_reading, unit_reading) = match (digit, *unit) {
+            (3, "千") => ("さん", "ぜん"),
+            (8, "千") => ("はっ", "せん"),
+            (3, "百") => ("さん", "びゃく"),
+            (6, "百") => ("ろっ", "ぴゃく"),
+            (8, "百") => ("はっ", "ぴゃく"),
+            _ => (DIGITS[digit].1, *unit_reading),
+        };
+        if digit > 1 {
+            kanji.push_str(DIGITS[digit].0);
+            reading.push_str(digit_reading);
+        }
+        kanji.push_str(unit);
+        reading.push_str(unit_reading);
+    }
+
+    // Ones.
+    let ones = (n % 10) as usize;
+    kanji.push_str(DIGITS[ones].0);
+    reading.push_str(DIGITS[ones].1);
+
+    (kanji, reading)
+}
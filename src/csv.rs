@@ -0,0 +1,98 @@
+//! Parses simple two-column CSV word lists, for building dictionaries in
+//! languages other than Japanese.
+//!
+//! Each row is `word,definition`, where the definition is html.  A row can
+//! have multiple lookup words by separating them with `|`, e.g.
+//! `Haus|Häuser,<b>das Haus</b> house`.  Fields containing commas, quotes,
+//! or newlines can be quoted in the usual CSV way.
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub words: Vec<String>,
+    pub definition: String,
+}
+
+pub fn parse(path: &Path) -> std::io::Result<Vec<Entry>> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    let text = text.trim_start_matches('\u{feff}');
+
+    let mut entries = Vec::new();
+    for (i, row) in parse_rows(text).iter().enumerate() {
+        if row.iter().all(|f| f.trim().is_empty()) {
+            continue;
+        }
+        if row.len() != 2 {
+            warning!(
+                "skipping row {} of {}, which has {} fields instead of 2.",
+                i + 1,
+                path.display(),
+                row.len()
+            );
+            continue;
+        }
+
+        let words: Vec<String> = row[0]
+            .split('|')
+            .map(|w| w.trim())
+            .filter(|w| !w.is_empty())
+            .map(|w| w.into())
+            .collect();
+        if words.is_empty() {
+            continue;
+        }
+
+        entries.push(Entry {
+            words,
+            definition: row[1].trim().into(),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Splits CSV text into rows of fields.
+fn parse_rows(text: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    in_quotes = false;
+                }
+            } else {
+                field.push(c);
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_quotes = true,
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            _ => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+
+    rows
+}
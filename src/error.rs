@@ -0,0 +1,142 @@
+//! The error type for everything that stops a command.  These are
+//! returned up to `main()`, which reports them and picks the exit code;
+//! nothing else calls `process::exit`.
+
+use std::fmt;
+use std::io;
+
+pub enum Error {
+    /// Reading or writing a file failed.
+    Io { context: String, source: io::Error },
+
+    /// A file was read, but its contents aren't valid.
+    Invalid { context: String, message: String },
+
+    /// The command line doesn't make sense, e.g. the output path is one
+    /// of the inputs.
+    Usage(String),
+
+    /// A dictionary was checked (`verify`, `validate`, or `build
+    /// --verify`), and has problems.
+    Verification(String),
+
+    /// Anything else that stops a command, e.g. an external tool that
+    /// failed.
+    Failed(String),
+}
+
+impl Error {
+    pub fn invalid<C: Into<String>, M: Into<String>>(context: C, message: M) -> Error {
+        Error::Invalid {
+            context: context.into(),
+            message: message.into(),
+        }
+    }
+
+    pub fn usage<M: Into<String>>(message: M) -> Error {
+        Error::Usage(message.into())
+    }
+
+    pub fn verification<M: Into<String>>(message: M) -> Error {
+        Error::Verification(message.into())
+    }
+
+    pub fn failed<M: Into<String>>(message: M) -> Error {
+        Error::Failed(message.into())
+    }
+
+    /// The exit code of the process when it stops because of this error.
+    /// (Clap uses 2 for command line errors, and 1 is used for everything
+    /// else.)
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io { source, .. } if source.kind() == io::ErrorKind::NotFound => 3,
+            Error::Io { source, .. } if source.kind() == io::ErrorKind::InvalidData => 5,
+            Error::Io { .. } => 4,
+            Error::Invalid { .. } => 5,
+            Error::Usage(_) => 2,
+            Error::Verification(_) => 5,
+            Error::Failed(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (context, reason) = match self {
+            Error::Io { context, source } => (
+                context,
+                match source.kind() {
+                    io::ErrorKind::NotFound => "file not found".into(),
+                    io::ErrorKind::PermissionDenied => "permission denied".into(),
+                    _ => source.to_string(),
+                },
+            ),
+            Error::Invalid { context, message } => (context, message.clone()),
+            Error::Usage(message) | Error::Verification(message) | Error::Failed(message) => {
+                return write!(f, "{}", message)
+            }
+        };
+        if context.is_empty() {
+            write!(f, "{}", reason)
+        } else {
+            write!(f, "{}: {}", context, reason)
+        }
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Error {
+        Error::Io {
+            context: String::new(),
+            source,
+        }
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Error {
+        match e {
+            zip::result::ZipError::Io(source) => source.into(),
+            e => Error::invalid("", e.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        match e.classify() {
+            serde_json::error::Category::Io => io::Error::from(e).into(),
+            _ => Error::invalid("", format!("invalid json ({})", e)),
+        }
+    }
+}
+
+/// Adds a description of what was being done to an error, e.g.
+/// "failed to open Yomichan dictionary 'dict.zip'".  Errors that already
+/// have a description are left as they are.
+pub trait Context<T> {
+    fn context<C: Into<String>, F: FnOnce() -> C>(self, f: F) -> Result<T, Error>;
+}
+
+impl<T, E: Into<Error>> Context<T> for Result<T, E> {
+    fn context<C: Into<String>, F: FnOnce() -> C>(self, f: F) -> Result<T, Error> {
+        self.map_err(|e| match e.into() {
+            Error::Io { context, source } if context.is_empty() => Error::Io {
+                context: f().into(),
+                source,
+            },
+            Error::Invalid { context, message } if context.is_empty() => Error::Invalid {
+                context: f().into(),
+                message,
+            },
+            e => e,
+        })
+    }
+}
@@ -0,0 +1,126 @@
+//! Downloading fresh copies of the source data, for the `fetch`
+//! subcommand.
+//!
+//! The downloaded files are kept in a data directory, and are used by
+//! builds in place of the bundled copies, which only get updated with new
+//! releases of this tool.
+
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use md5::{Digest, Md5};
+
+use crate::error::{Context, Error};
+
+/// A data file that can be fetched.
+pub struct Source {
+    /// The name it's fetched by, e.g. "jmdict".
+    pub name: &'static str,
+
+    pub url: &'static str,
+
+    /// The name of the file in the data directory.
+    pub file_name: &'static str,
+
+    /// The root element of the (gzipped) xml data, used to check that the
+    /// right file was downloaded.
+    root_element: &'static str,
+}
+
+pub const SOURCES: &[Source] = &[Source {
+    name: "jmdict",
+    url: "https://www.edrdg.org/pub/Nihongo/JMdict_e.gz",
+    file_name: "JMdict_e.gz",
+    root_element: "<JMdict>",
+}];
+
+/// The default data directory, following each platform's conventions.
+pub fn default_data_dir() -> PathBuf {
+    let env_dir = |name: &str| std::env::var_os(name).map(PathBuf::from);
+    let base = if cfg!(windows) {
+        env_dir("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        env_dir("HOME").map(|home| home.join("Library").join("Application Support"))
+    } else {
+        env_dir("XDG_DATA_HOME")
+            .or_else(|| env_dir("HOME").map(|home| home.join(".local").join("share")))
+    };
+    base.unwrap_or_else(std::env::temp_dir).join("kobo_jp_dict")
+}
+
+/// The path of the fetched copy of the given source, if there is one.
+pub fn local_path(data_dir: &Path, name: &str) -> Option<PathBuf> {
+    let source = SOURCES.iter().find(|source| source.name == name)?;
+    let path = data_dir.join(source.file_name);
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Downloads a source into the data directory, replacing any previously
+/// fetched copy.  Returns its path and md5 hash.
+///
+/// The download is checked before it replaces anything: its length must
+/// match the one the server reported, it must decompress without errors
+/// (which verifies gzip's CRC-32 checksum), and it must be the expected
+/// kind of xml.
+pub fn fetch(source: &Source, data_dir: &Path) -> Result<(PathBuf, String), Error> {
+    let download_error = || format!("failed to download the {} data", source.name);
+
+    let response = ureq::get(source.url)
+        .call()
+        .map_err(|e| io::Error::other(e.to_string()))
+        .context(download_error)?;
+    let expected_len: Option<usize> = response
+        .header("Content-Length")
+        .and_then(|len| len.parse().ok());
+    let mut data = Vec::new();
+    response
+        .into_reader()
+        .read_to_end(&mut data)
+        .context(download_error)?;
+
+    let invalid = |message: String| Error::invalid(format!("{} isn't valid", source.url), message);
+    if let Some(len) = expected_len {
+        if data.len() != len {
+            return Err(invalid(format!(
+                "got {} bytes instead of {}",
+                data.len(),
+                len
+            )));
+        }
+    }
+    let mut xml = Vec::new();
+    GzDecoder::new(&data[..])
+        .read_to_end(&mut xml)
+        .map_err(|e| invalid(format!("it can't be decompressed ({})", e)))?;
+    let root = source.root_element.as_bytes();
+    if !xml.windows(root.len()).take(1 << 20).any(|w| w == root) {
+        return Err(invalid(format!(
+            "it has no {} element",
+            source.root_element
+        )));
+    }
+
+    let hash: String = Md5::digest(&data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    // Written via a temporary file, so that a failed write never leaves a
+    // partial file to be used by builds.
+    let path = data_dir.join(source.file_name);
+    let write_error = || format!("failed to write '{}'", path.display());
+    std::fs::create_dir_all(data_dir).context(write_error)?;
+    let mut file = tempfile::NamedTempFile::new_in(data_dir).context(write_error)?;
+    file.write_all(&data).context(write_error)?;
+    file.persist(&path)
+        .map_err(|e| e.error)
+        .context(write_error)?;
+
+    Ok((path, hash))
+}
@@ -0,0 +1,90 @@
+//! Filtering of entries by their tags and headwords, for --include_tags,
+//! --exclude_tags, and --exclude_pattern.
+//!
+//! Tags can be given either as JMDict tags in the "element:entity" format
+//! of `WordEntry::tags` (e.g. "misc:vulg"), or as just the entity (e.g.
+//! "vulg"), which also matches Yomichan tags.
+
+use regex::Regex;
+
+use crate::jmdict::WordEntry;
+use crate::yomichan::TermEntry;
+
+#[derive(Debug, Clone, Default)]
+pub struct EntryFilter {
+    pub include_tags: Vec<String>,
+    pub exclude_tags: Vec<String>,
+    pub exclude_pattern: Option<Regex>,
+}
+
+impl EntryFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include_tags.is_empty()
+            && self.exclude_tags.is_empty()
+            && self.exclude_pattern.is_none()
+    }
+
+    /// Removes the senses of a JMDict word that have excluded tags, and
+    /// returns whether the word should be kept.  It isn't if no senses are
+    /// left, if it doesn't have any of the included tags, or if one of its
+    /// writings or readings matches the excluded pattern.
+    pub fn filter_jmdict(&self, entry: &mut WordEntry) -> bool {
+        if let Some(ref pattern) = self.exclude_pattern {
+            if entry
+                .writings
+                .iter()
+                .chain(entry.readings.iter())
+                .any(|form| pattern.is_match(form))
+            {
+                return false;
+            }
+        }
+
+        if !self.include_tags.is_empty()
+            && !entry
+                .tags
+                .iter()
+                .chain(entry.definitions.iter().flat_map(|sense| sense.tags.iter()))
+                .any(|tag| matches_any(tag, &self.include_tags))
+        {
+            return false;
+        }
+
+        if !self.exclude_tags.is_empty() {
+            let exclude_tags = &self.exclude_tags;
+            entry
+                .definitions
+                .retain(|sense| !sense.tags.iter().any(|tag| matches_any(tag, exclude_tags)));
+        }
+
+        !entry.definitions.is_empty()
+    }
+
+    /// Returns whether a Yomichan term entry should be kept.
+    pub fn keep_yomichan(&self, entry: &TermEntry) -> bool {
+        if let Some(ref pattern) = self.exclude_pattern {
+            if pattern.is_match(&entry.writing) || pattern.is_match(&entry.reading) {
+                return false;
+            }
+        }
+        if !self.include_tags.is_empty()
+            && !entry
+                .tags
+                .iter()
+                .any(|tag| matches_any(tag, &self.include_tags))
+        {
+            return false;
+        }
+        !entry
+            .tags
+            .iter()
+            .any(|tag| matches_any(tag, &self.exclude_tags))
+    }
+}
+
+/// Whether a tag matches any of the given tags, either in full or by its
+/// entity.
+fn matches_any(tag: &str, tags: &[String]) -> bool {
+    let entity = tag.split_once(':').map(|(_, entity)| entity).unwrap_or(tag);
+    tags.iter().any(|t| t == tag || t == entity)
+}
@@ -0,0 +1,538 @@
+//! Escaping of text for inclusion in the generated html.
+//!
+//! Kobo's renderer parses dictionary entries as XHTML, so any text from the
+//! source dictionaries that ends up in the generated markup needs to be
+//! escaped, or a stray `<` or `&` can break the rest of the prefix file.
+//! Html that's taken as-is from elsewhere is sanitized for the same reason.
+
+/// Escapes text for use as the content of an html element.
+pub fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes text for use in a double-quoted html attribute value.
+pub fn escape_attr(text: &str) -> String {
+    escape_text(text).replace('"', "&quot;")
+}
+
+/// Escapes multi-line text for use as the content of an html element,
+/// converting line breaks to `<br/>`.
+pub fn escape_lines(text: &str) -> String {
+    escape_text(text).replace('\n', "<br/>")
+}
+
+/// Encodes data as a `data:` URI with the given mime type, for embedding
+/// e.g. images in html.
+pub fn data_uri(mime: &str, data: &[u8]) -> String {
+    const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut uri = format!("data:{};base64,", mime);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | ((*b as u32) << (16 - i * 8)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                uri.push(BASE64[((n >> (18 - i * 6)) & 0x3f) as usize] as char);
+            } else {
+                uri.push('=');
+            }
+        }
+    }
+    uri
+}
+
+/// The elements that are kept by `sanitize()`.  Kobo's renderer only
+/// supports basic html, and the `<w>`, `<a name>`, and `<var>` elements
+/// that structure the dictionary itself mustn't appear in entries.  The
+/// svg elements are the ones used by the pitch accent graphs (see
+/// `--pitch_graphs`).
+const ALLOWED_TAGS: &[&str] = &[
+    "a",
+    "b",
+    "big",
+    "blockquote",
+    "br",
+    "circle",
+    "code",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "img",
+    "li",
+    "ol",
+    "p",
+    "polyline",
+    "pre",
+    "rp",
+    "rt",
+    "ruby",
+    "s",
+    "small",
+    "span",
+    "strong",
+    "sub",
+    "sup",
+    "svg",
+    "table",
+    "tbody",
+    "td",
+    "th",
+    "thead",
+    "tr",
+    "u",
+    "ul",
+];
+
+/// The attributes that are kept by `sanitize()`.  They're matched
+/// case-insensitively, but written as they're spelled here, since svg
+/// attributes like `viewBox` are case-sensitive in XHTML.
+const ALLOWED_ATTRIBUTES: &[&str] = &[
+    "alt", "class", "colspan", "href", "rowspan", "src", "style", "title", // Html.
+    "cx", "cy", "fill", "height", "points", "r", "stroke", "viewBox", "width",
+    "xmlns", // Svg.
+];
+
+/// Elements that have no closing tag, or are written without one.
+const VOID_TAGS: &[&str] = &["br", "circle", "hr", "img", "polyline"];
+
+/// Elements that are dropped together with their contents.
+const DROPPED_TAGS: &[&str] = &["head", "object", "script", "style", "iframe", "title"];
+
+/// Dropped elements whose contents are raw text rather than markup, so
+/// that tags inside them don't nest.
+const RAW_TEXT_TAGS: &[&str] = &["script", "style", "iframe", "title"];
+
+/// Sanitizes html from an untrusted source for inclusion in an entry.
+///
+/// Only elements and attributes that Kobo's renderer supports are kept,
+/// scripts and styles are dropped with their contents, stray `<` and `&`
+/// are escaped, and unclosed tags are balanced, so that the result is
+/// always well-formed XHTML.  Elements that aren't supported are removed,
+/// but their contents are kept.
+pub fn sanitize(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut open: Vec<String> = Vec::new();
+    let mut rest = html;
+    while !rest.is_empty() {
+        let start = match rest.find('<') {
+            Some(start) => start,
+            None => {
+                push_sanitized_text(&mut out, rest);
+                break;
+            }
+        };
+        push_sanitized_text(&mut out, &rest[..start]);
+        rest = &rest[start..];
+
+        // Comments and doctypes.
+        if rest.starts_with("<!") || rest.starts_with("<?") {
+            let end = if rest.starts_with("<!--") {
+                rest.find("-->").map(|i| i + "-->".len())
+            } else {
+                rest.find('>').map(|i| i + 1)
+            };
+            rest = &rest[end.unwrap_or(rest.len())..];
+            continue;
+        }
+
+        let tag = match parse_tag(rest) {
+            Some(tag) => tag,
+            None => {
+                // Not a tag, just a stray `<`.
+                out.push_str("&lt;");
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        rest = &rest[tag.len..];
+
+        if tag.closing {
+            // Close the matching element, and any left unclosed within it.
+            if let Some(i) = open.iter().rposition(|name| *name == tag.name) {
+                for name in open.drain(i..).rev() {
+                    out.push_str(&format!("</{}>", name));
+                }
+            }
+        } else if DROPPED_TAGS.contains(&tag.name.as_str()) {
+            if !tag.self_closing {
+                rest = &rest[dropped_content_len(rest, &tag.name)..];
+            }
+        } else if ALLOWED_TAGS.contains(&tag.name.as_str()) {
+            out.push('<');
+            out.push_str(&tag.name);
+            for (name, value) in tag.attributes.iter() {
+                let is_script = value
+                    .trim_start()
+                    .to_ascii_lowercase()
+                    .starts_with("javascript:");
+                let allowed = ALLOWED_ATTRIBUTES
+                    .iter()
+                    .find(|allowed| allowed.eq_ignore_ascii_case(name));
+                if let (Some(name), false) = (allowed, is_script) {
+                    out.push_str(&format!(" {}=\"{}\"", name, escape_attr(value)));
+                }
+            }
+            if VOID_TAGS.contains(&tag.name.as_str()) {
+                out.push_str("/>");
+            } else if tag.self_closing {
+                out.push_str(&format!("></{}>", tag.name));
+            } else {
+                out.push('>');
+                open.push(tag.name);
+            }
+        }
+    }
+
+    for name in open.drain(..).rev() {
+        out.push_str(&format!("</{}>", name));
+    }
+    out
+}
+
+/// Appends text to sanitized html, escaping anything that isn't valid in
+/// text content.  Entities are kept as they are.
+fn push_sanitized_text(out: &mut String, text: &str) {
+    let mut rest = text;
+    while let Some(i) = rest.find(['&', '<', '>']) {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if rest.starts_with('&') {
+            match entity_len(rest) {
+                Some(len) => {
+                    out.push_str(&rest[..len]);
+                    rest = &rest[len..];
+                    continue;
+                }
+                None => out.push_str("&amp;"),
+            }
+        } else if rest.starts_with('<') {
+            out.push_str("&lt;");
+        } else {
+            out.push_str("&gt;");
+        }
+        rest = &rest[1..];
+    }
+    out.push_str(rest);
+}
+
+/// Returns the length of the html entity at the start of `text`, if there
+/// is one.
+fn entity_len(text: &str) -> Option<usize> {
+    let end = text.find(';').filter(|&end| end <= 10)?;
+    let entity = &text[1..end];
+    let valid = if let Some(hex) = entity
+        .strip_prefix("#x")
+        .or_else(|| entity.strip_prefix("#X"))
+    {
+        !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit())
+    } else if let Some(digits) = entity.strip_prefix('#') {
+        !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+    } else {
+        entity.starts_with(|c: char| c.is_ascii_alphabetic())
+            && entity.chars().all(|c| c.is_ascii_alphanumeric())
+    };
+    if valid {
+        Some(end + 1)
+    } else {
+        None
+    }
+}
+
+/// Returns the length of the contents of a dropped element, including its
+/// end tag.  Unclosed elements run to the end of the text.
+fn dropped_content_len(text: &str, name: &str) -> usize {
+    let nests = !RAW_TEXT_TAGS.contains(&name);
+    let mut depth = 0;
+    let mut pos = 0;
+    while let Some(i) = text[pos..].find('<') {
+        let start = pos + i;
+        pos = start + 1;
+        let after = &text[pos..];
+        let (closing, after) = match after.strip_prefix('/') {
+            Some(after) => (true, after),
+            None => (false, after),
+        };
+        if !starts_with_tag_name(after, name) || !(closing || nests) {
+            continue;
+        }
+        let tag = parse_tag(&text[start..]);
+        if closing && depth == 0 {
+            return match tag {
+                Some(tag) => start + tag.len,
+                None => text[start..]
+                    .find('>')
+                    .map_or(text.len(), |j| start + j + 1),
+            };
+        } else if closing {
+            depth -= 1;
+        } else if let Some(tag) = tag {
+            if !tag.self_closing {
+                depth += 1;
+            }
+            pos = start + tag.len;
+        }
+    }
+    text.len()
+}
+
+/// Whether `text` starts with the tag name `name`, in any case, followed
+/// by something that ends the name.
+fn starts_with_tag_name(text: &str, name: &str) -> bool {
+    let bytes = text.as_bytes();
+    bytes.len() >= name.len()
+        && bytes[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+        && match bytes.get(name.len()) {
+            Some(&b) => b == b'>' || b == b'/' || b.is_ascii_whitespace(),
+            None => true,
+        }
+}
+
+/// A parsed html tag.
+struct Tag {
+    name: String,                      // Lowercase.
+    attributes: Vec<(String, String)>, // (lowercase name, decoded value)
+    closing: bool,
+    self_closing: bool,
+    len: usize, // Length in bytes of the tag's text.
+}
+
+/// Parses the html tag at the start of `text`, if there is one.
+fn parse_tag(text: &str) -> Option<Tag> {
+    let mut rest = text.strip_prefix('<')?;
+    let closing = rest.starts_with('/');
+    if closing {
+        rest = &rest[1..];
+    }
+    let name_len = rest
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(rest.len());
+    if name_len == 0 || !rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let name = rest[..name_len].to_ascii_lowercase();
+    rest = &rest[name_len..];
+
+    let mut attributes = Vec::new();
+    let mut self_closing = false;
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix('>') {
+            rest = after;
+            break;
+        } else if let Some(after) = rest.strip_prefix("/>") {
+            self_closing = true;
+            rest = after;
+            break;
+        } else if rest.starts_with('/') {
+            rest = &rest[1..];
+            continue;
+        } else if rest.is_empty() || rest.starts_with('<') {
+            // Unterminated tag.
+            return None;
+        }
+
+        let attr_len = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '>' || c == '/')
+            .unwrap_or(rest.len());
+        if attr_len == 0 {
+            // A stray `=` or similar.
+            rest = &rest[1..];
+            continue;
+        }
+        let attr_name = rest[..attr_len].to_ascii_lowercase();
+        rest = rest[attr_len..].trim_start();
+        let value = match rest.strip_prefix('=') {
+            Some(after) => {
+                let after = after.trim_start();
+                match after.chars().next() {
+                    Some(quote) if quote == '"' || quote == '\'' => {
+                        let end = after[1..].find(quote)? + 1;
+                        rest = &after[(end + 1)..];
+                        &after[1..end]
+                    }
+                    _ => {
+                        let end = after
+                            .find(|c: char| c.is_whitespace() || c == '>')
+                            .unwrap_or(after.len());
+                        rest = &after[end..];
+                        &after[..end]
+                    }
+                }
+            }
+            None => "",
+        };
+        attributes.push((attr_name, decode_entities(value)));
+    }
+
+    Some(Tag {
+        name,
+        attributes,
+        closing,
+        self_closing,
+        len: text.len() - rest.len(),
+    })
+}
+
+/// Converts html to plain text, keeping line breaks for block elements and
+/// `<br>`s, and decoding entities.
+pub fn to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&decode_entities(&rest[..start]));
+        let end = match rest[start..].find('>') {
+            Some(i) => start + i + 1,
+            None => {
+                rest = "";
+                break;
+            }
+        };
+        let tag_name: String = rest[(start + 1)..end]
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        match tag_name.as_str() {
+            "br" | "p" | "div" | "li" | "hr" | "ol" | "ul" | "tr"
+                if !text.is_empty() && !text.ends_with('\n') =>
+            {
+                text.push('\n');
+            }
+            _ => {}
+        }
+        rest = &rest[end..];
+    }
+    text.push_str(&decode_entities(rest));
+
+    // Collapse runs of blank lines and trim each line.
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Decodes the html entities in text.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) if end <= 10 => end,
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            "mdash" => Some('—'),
+            "ndash" => Some('–'),
+            _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                u32::from_str_radix(&entity[2..], 16)
+                    .ok()
+                    .and_then(std::char::from_u32)
+            }
+            _ if entity.starts_with('#') => entity[1..]
+                .parse::<u32>()
+                .ok()
+                .and_then(std::char::from_u32),
+            _ => None,
+        };
+        match c {
+            Some(c) => {
+                decoded.push(c);
+                rest = &rest[(end + 1)..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_script_with_contents() {
+        assert_eq!(sanitize("a<script>x()</script>b"), "ab");
+    }
+
+    #[test]
+    fn dropped_tags_match_any_case() {
+        assert_eq!(sanitize("a<SCRIPT>x()</Script>b"), "ab");
+        assert_eq!(sanitize("a<Style>p {}</STYLE >b"), "ab");
+    }
+
+    #[test]
+    fn end_tag_needs_whole_name() {
+        assert_eq!(sanitize("a<script>x = '</scripts>'</script>b"), "ab");
+    }
+
+    #[test]
+    fn unclosed_dropped_tag_runs_to_end() {
+        assert_eq!(sanitize("a<script>x()"), "a");
+        assert_eq!(sanitize("a<style>p {} </style"), "a");
+    }
+
+    #[test]
+    fn nested_dropped_tags() {
+        assert_eq!(sanitize("a<object><object>x</object>y</object>b"), "ab");
+        // Script contents are raw text, so nothing nests.
+        assert_eq!(sanitize("a<script>'<script>'</script>b"), "ab");
+    }
+
+    #[test]
+    fn attributes_containing_gt() {
+        assert_eq!(sanitize("a<object data=\"x>y\">z</object>b"), "ab");
+        assert_eq!(
+            sanitize("a<object><object data='</object>'>z</object></object>b"),
+            "ab"
+        );
+        assert_eq!(
+            sanitize("<b title=\"x>y\">z</b>"),
+            "<b title=\"x&gt;y\">z</b>"
+        );
+    }
+
+    #[test]
+    fn self_closing_dropped_tag() {
+        assert_eq!(sanitize("a<object/>b"), "ab");
+    }
+}
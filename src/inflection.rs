@@ -0,0 +1,161 @@
+//! Inflection rules, used to generate lookup keys for the conjugated forms
+//! of words.
+//!
+//! The rules are a json object mapping conjugation classes (named after
+//! their JMDict part-of-speech codes, e.g. "v1" or "adj-i") to lists of
+//! rules.  Each rule has a `trail`, which is the ending of the dictionary
+//! form, and `endings`, which are what the trail is replaced with to make
+//! the conjugated forms:
+//!
+//! ```json
+//! {
+//!     "adj-i": [
+//!         { "trail": "い", "endings": ["く", "かった", "くない"] }
+//!     ]
+//! }
+//! ```
+//!
+//! Besides the conjugation classes, rules can be given for any other
+//! JMDict part-of-speech code, such as "adj-na", and are used for all words
+//! tagged with it.
+//!
+//! The bundled rules are in `dictionaries/inflection_rules.json`, and the
+//! optional rules for classical Japanese are in
+//! `dictionaries/classical_inflection_rules.json`.
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::jmdict::{ConjugationClass, WordEntry};
+
+const BUNDLED_RULES: &str = include_str!("../dictionaries/inflection_rules.json");
+const CLASSICAL_RULES: &str = include_str!("../dictionaries/classical_inflection_rules.json");
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub trail: String,
+    pub endings: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct InflectionRules {
+    classes: HashMap<String, Vec<Rule>>,
+}
+
+impl InflectionRules {
+    /// The rules that ship with the tool.
+    pub fn bundled() -> InflectionRules {
+        InflectionRules::from_json(BUNDLED_RULES).expect("Bundled inflection rules are invalid.")
+    }
+
+    /// Rules for the endings of classical Japanese, such as 〜ぬ, 〜けり, and
+    /// nidan verbs.
+    pub fn classical() -> InflectionRules {
+        InflectionRules::from_json(CLASSICAL_RULES)
+            .expect("Bundled classical inflection rules are invalid.")
+    }
+
+    pub fn from_json(text: &str) -> Result<InflectionRules, String> {
+        let json: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let object = json
+            .as_object()
+            .ok_or_else(|| "expected an object of conjugation classes".to_string())?;
+
+        let mut classes = HashMap::new();
+        for (class, rules_json) in object.iter() {
+            let rules_json = rules_json
+                .as_array()
+                .ok_or_else(|| format!("\"{}\": expected a list of rules", class))?;
+            let mut rules = Vec::new();
+            for rule in rules_json.iter() {
+                let trail = rule
+                    .get("trail")
+                    .and_then(|t| t.as_str())
+                    .ok_or_else(|| format!("\"{}\": rule without a \"trail\" string", class))?;
+                let endings = rule
+                    .get("endings")
+                    .and_then(|e| e.as_array())
+                    .ok_or_else(|| format!("\"{}\": rule without an \"endings\" list", class))?
+                    .iter()
+                    .map(|e| {
+                        e.as_str()
+                            .map(|e| e.to_string())
+                            .ok_or_else(|| format!("\"{}\": endings must be strings", class))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                rules.push(Rule {
+                    trail: trail.into(),
+                    endings,
+                });
+            }
+            classes.insert(class.clone(), rules);
+        }
+
+        Ok(InflectionRules { classes })
+    }
+
+    /// Adds the rules of `other` to the rules of the same classes.
+    pub fn add(&mut self, other: InflectionRules) {
+        for (class, rules) in other.classes {
+            self.classes.entry(class).or_default().extend(rules);
+        }
+    }
+
+    /// Replaces the rules of each conjugation class in `other`, keeping the
+    /// rules of the other classes as they are.
+    pub fn override_with(&mut self, other: InflectionRules) {
+        self.classes.extend(other.classes);
+    }
+
+    /// Returns the rules for a word: those of its conjugation class, and
+    /// those of the other part-of-speech codes it's tagged with.
+    pub fn for_word(&self, entry: &WordEntry) -> Vec<&Rule> {
+        let conj_code = class_code(entry.conj);
+        let mut codes: Vec<&str> = entry
+            .tags
+            .iter()
+            .filter_map(|tag| tag.strip_prefix("pos:"))
+            .filter(|code| Some(*code) != conj_code)
+            .collect();
+        codes.sort_unstable();
+        codes.extend(conj_code);
+
+        codes
+            .iter()
+            .filter_map(|code| self.classes.get(*code))
+            .flatten()
+            .collect()
+    }
+}
+
+/// The JMDict part-of-speech code used to name each conjugation class in
+/// the rules.
+fn class_code(conj: ConjugationClass) -> Option<&'static str> {
+    use ConjugationClass::*;
+    Some(match conj {
+        Other => return None,
+        Copula => "cop",
+        IchidanVerb => "v1",
+        GodanVerbU => "v5u",
+        GodanVerbTsu => "v5t",
+        GodanVerbRu => "v5r",
+        GodanVerbKu => "v5k",
+        GodanVerbGu => "v5g",
+        GodanVerbNu => "v5n",
+        GodanVerbHu => "v4h",
+        GodanVerbBu => "v5b",
+        GodanVerbMu => "v5m",
+        GodanVerbSu => "v5s",
+        SuruVerb => "vs-i",
+        SuruVerbSC => "vs-s",
+        KuruVerb => "vk",
+        IkuVerb => "v5k-s",
+        KureruVerb => "v1-s",
+        AruVerb => "v5r-i",
+        SharuVerb => "v5aru",
+        IrregularVerb => "v-irr",
+        IAdjective => "adj-i",
+        IrregularIAdjective => "adj-ix",
+    })
+}
@@ -0,0 +1,133 @@
+//! The kinds of input files that dictionary entries are read from.
+//!
+//! Each kind has a source that implements `InputSource`, which reads its
+//! file into `SourceEntries`.  Term, name, and kanji entries go through
+//! the matching with JMDict and the generation of entry text, while
+//! finished entries (from other Kobo dictionaries and CSV files) are added
+//! to the dictionary as they are.
+//!
+//! JMDict itself isn't an input source: it's the table that the other
+//! sources' entries are matched against, and is loaded separately (see
+//! `cache::load_jmdict()`).
+
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::error::{Context, Error};
+use crate::kobo;
+use crate::yomichan::{KanjiEntry, TermEntry};
+use crate::{csv, kobo_ja, yomichan};
+
+#[derive(Default)]
+pub struct SourceEntries {
+    pub terms: Vec<TermEntry>,
+    pub names: Vec<TermEntry>,
+    pub kanji: Vec<KanjiEntry>,
+    pub finished: Vec<kobo::Entry>,
+}
+
+pub trait InputSource {
+    /// The path of the file, for messages.
+    fn path(&self) -> &Path;
+
+    fn load(&self) -> Result<SourceEntries, Error>;
+}
+
+/// A Yomichan .zip dictionary.
+pub struct YomichanSource<'a> {
+    pub path: PathBuf,
+    pub config: &'a Config,
+    pub embed_images: bool,
+
+    /// Whether all of its term entries are names (`--names_dict`).
+    pub is_names_dict: bool,
+}
+
+impl InputSource for YomichanSource<'_> {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn load(&self) -> Result<SourceEntries, Error> {
+        let (terms, names, kanji) = yomichan::parse(
+            &self.path,
+            self.config,
+            self.embed_images,
+            self.is_names_dict,
+        )?;
+        Ok(SourceEntries {
+            terms,
+            names,
+            kanji,
+            ..SourceEntries::default()
+        })
+    }
+}
+
+/// An existing Kobo dicthtml dictionary, for `--kobo_merge`.
+pub struct KoboSource {
+    pub path: PathBuf,
+
+    /// The priority of its look-up keys, which places its entries relative
+    /// to the generated ones.
+    pub priority: u32,
+}
+
+impl InputSource for KoboSource {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn load(&self) -> Result<SourceEntries, Error> {
+        let entries = kobo_ja::parse(&self.path)
+            .context(|| format!("failed to read Kobo dictionary '{}'", self.path.display()))?;
+        let finished = entries
+            .into_iter()
+            .map(|entry| {
+                let mut keys = vec![(entry.key, self.priority)];
+                for variant in entry.variants {
+                    if !keys.iter().any(|k| k.0 == variant) {
+                        keys.push((variant, self.priority));
+                    }
+                }
+                kobo::Entry {
+                    keys,
+                    definition: format!("<hr/>{}", entry.definition),
+                    jmdict_seq: None,
+                }
+            })
+            .collect();
+        Ok(SourceEntries {
+            finished,
+            ..SourceEntries::default()
+        })
+    }
+}
+
+/// A CSV word list, for `--csv`.
+pub struct CsvSource {
+    pub path: PathBuf,
+}
+
+impl InputSource for CsvSource {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn load(&self) -> Result<SourceEntries, Error> {
+        let entries = csv::parse(&self.path)
+            .context(|| format!("failed to read CSV file '{}'", self.path.display()))?;
+        let finished = entries
+            .into_iter()
+            .map(|entry| kobo::Entry {
+                keys: entry.words.into_iter().map(|w| (w, 1000)).collect(),
+                definition: format!("<hr/>{}", entry.definition),
+                jmdict_seq: None,
+            })
+            .collect();
+        Ok(SourceEntries {
+            finished,
+            ..SourceEntries::default()
+        })
+    }
+}
@@ -0,0 +1,128 @@
+//! Installing built dictionaries onto a connected Kobo device.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Finds the mount points of connected Kobo devices, by looking for
+/// drives with a `.kobo` directory in the usual mount locations.
+pub fn find_devices() -> Vec<PathBuf> {
+    let mut candidates: Vec<PathBuf> = Vec::new();
+
+    if cfg!(windows) {
+        for letter in b'D'..=b'Z' {
+            candidates.push(format!("{}:\\", letter as char).into());
+        }
+    } else {
+        let mut mount_dirs: Vec<PathBuf> = vec!["/Volumes".into(), "/media".into(), "/mnt".into()];
+        if let Ok(user) = std::env::var("USER") {
+            mount_dirs.push(Path::new("/media").join(&user));
+            mount_dirs.push(Path::new("/run/media").join(&user));
+        }
+        for dir in mount_dirs.iter() {
+            if let Ok(read_dir) = fs::read_dir(dir) {
+                for item in read_dir.flatten() {
+                    candidates.push(item.path());
+                }
+            }
+        }
+    }
+
+    let mut devices: Vec<PathBuf> = candidates
+        .drain(..)
+        .filter(|path| path.join(".kobo").is_dir())
+        .collect();
+    devices.sort();
+    devices.dedup();
+    devices
+}
+
+/// Copies the dictionary file onto the device, returning the path it was
+/// installed to.
+///
+/// Recent firmware loads custom dictionaries from `.kobo/custom-dict`, so
+/// that's used when it exists.  Otherwise the dictionary is installed into
+/// `.kobo/dict`, which on older firmware also requires the language to be
+/// listed in the `ExtraLocales` setting (see `check_extra_locales()`).
+pub fn install(dict_path: &Path, device: &Path, name: &str) -> io::Result<PathBuf> {
+    let custom_dir = device.join(".kobo").join("custom-dict");
+    let dir = if custom_dir.is_dir() {
+        custom_dir
+    } else {
+        device.join(".kobo").join("dict")
+    };
+    fs::create_dir_all(&dir)?;
+
+    let dest = dir.join(name);
+    fs::copy(dict_path, &dest)?;
+    Ok(dest)
+}
+
+/// Returns the language code of a dictionary filename, e.g. "ja" for
+/// "dicthtml-ja-en.zip".
+pub fn dict_language(name: &str) -> Option<&str> {
+    let code = name.strip_prefix("dicthtml-")?.strip_suffix(".zip")?;
+    code.split('-').next().filter(|s| !s.is_empty())
+}
+
+/// Checks whether the device's `ExtraLocales` setting includes the given
+/// language, and adds it if `update` is true.
+///
+/// Returns whether the language is (now) included.
+pub fn check_extra_locales(device: &Path, lang: &str, update: bool) -> io::Result<bool> {
+    let conf_path = device.join(".kobo").join("Kobo").join("Kobo eReader.conf");
+    let conf = match fs::read_to_string(&conf_path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut lines: Vec<String> = conf.lines().map(|l| l.into()).collect();
+    let mut app_prefs_idx = None;
+    let mut in_app_prefs = false;
+    for (i, line) in lines.iter_mut().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_app_prefs = trimmed == "[ApplicationPreferences]";
+            if in_app_prefs {
+                app_prefs_idx = Some(i);
+            }
+            continue;
+        }
+        if in_app_prefs && trimmed.starts_with("ExtraLocales=") {
+            let locales: Vec<&str> = trimmed["ExtraLocales=".len()..]
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .collect();
+            if locales.contains(&lang) {
+                return Ok(true);
+            } else if !update {
+                return Ok(false);
+            }
+            let mut locales = locales.join(",");
+            if !locales.is_empty() {
+                locales.push(',');
+            }
+            locales.push_str(lang);
+            *line = format!("ExtraLocales={}", locales);
+            fs::write(&conf_path, lines.join("\n") + "\n")?;
+            return Ok(true);
+        }
+    }
+
+    if !update {
+        return Ok(false);
+    }
+    let new_line = format!("ExtraLocales={}", lang);
+    match app_prefs_idx {
+        Some(i) => lines.insert(i + 1, new_line),
+        None => {
+            lines.push("[ApplicationPreferences]".into());
+            lines.push(new_line);
+        }
+    }
+    fs::create_dir_all(conf_path.parent().unwrap())?;
+    fs::write(&conf_path, lines.join("\n") + "\n")?;
+    Ok(true)
+}
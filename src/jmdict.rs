@@ -3,20 +3,32 @@
 //! The `Parser` type takes a buffered reader, and acts as an iterator
 //! that yields a `WordEntry` for each entry in the dictionary, parsing
 //! the input as it goes.
+//!
+//! Tags like parts of speech are written as xml entities in JMDict, e.g.
+//! `<pos>&v5u;</pos>`, and are identified by the entity's name.  If the
+//! file declares its entities in a DTD, tags whose entities have already
+//! been expanded to their descriptions are identified too.
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::BufRead;
 
 use quick_xml::events::Event;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 
 /// A parser for the JMDict xml format.
 pub struct Parser<R: BufRead> {
     xml_parser: quick_xml::Reader<R>,
     buf: Vec<u8>,
     cur_entry: WordEntry,
-    kanji_priorities: Vec<String>,
-    kana_priorities: Vec<String>,
+    kanji_priority: Option<u32>, // Of the best priority marker of the writings, if any.
+    kana_priority: Option<u32>,  // The same, for the readings.
+    form_priorities: HashMap<String, u32>, // Writing or reading -> priority of its best marker.
+    priority_model: PriorityModel,
+    sense_pos: Vec<String>, // Part-of-speech tags of the previous sense.
     cur_xml_elem: Elem,
+    entities: HashMap<String, String>, // Entity description -> name, from the DTD.
+    unknown_tags: BTreeMap<String, usize>, // Tag -> number of times it was found.
 }
 
 impl<R: BufRead> Parser<R> {
@@ -25,18 +37,183 @@ impl<R: BufRead> Parser<R> {
             xml_parser: quick_xml::Reader::from_reader(reader),
             buf: Vec::new(),
             cur_entry: WordEntry::new(),
-            kanji_priorities: Vec::new(),
-            kana_priorities: Vec::new(),
+            kanji_priority: None,
+            kana_priority: None,
+            form_priorities: HashMap::new(),
+            priority_model: PriorityModel::default(),
+            sense_pos: Vec::new(),
             cur_xml_elem: Elem::None,
+            entities: HashMap::new(),
+            unknown_tags: BTreeMap::new(),
         }
     }
+
+    /// Sets how word priorities are computed from JMDict's priority
+    /// markers.
+    pub fn set_priority_model(&mut self, model: PriorityModel) {
+        self.priority_model = model;
+    }
+
+    /// The part-of-speech, misc, field, dialect, and writing/reading info
+    /// tags found so far that the parser doesn't recognize, in the same
+    /// format as `WordEntry::tags` and in sorted order, with the number of
+    /// times each was found.
+    pub fn unknown_tags(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.unknown_tags
+            .iter()
+            .map(|(tag, count)| (tag.as_str(), *count))
+    }
 }
 
-#[derive(Debug, Clone)]
+// These are free functions rather than methods, so that they only borrow
+// the parser's fields that they need while the rest of it is in use.
+
+/// Returns the name of the entity that a tag's text refers to, e.g.
+/// "v5u" for "&v5u;", or the text itself if it isn't an entity.
+fn entity_name<'a>(entities: &'a HashMap<String, String>, text: &'a str) -> &'a str {
+    let text = text.trim();
+    if text.starts_with('&') && text.ends_with(';') {
+        &text[1..(text.len() - 1)]
+    } else if let Some(name) = entities.get(text) {
+        name
+    } else {
+        text
+    }
+}
+
+/// Records the tag as unknown if its code isn't in `known`.
+fn check_tag(unknown_tags: &mut BTreeMap<String, usize>, elem: &str, code: &str, known: &[&str]) {
+    if !known.contains(&code) {
+        add_unknown_tag(unknown_tags, elem, code);
+    }
+}
+
+fn add_unknown_tag(unknown_tags: &mut BTreeMap<String, usize>, elem: &str, code: &str) {
+    *unknown_tags
+        .entry(format!("{}:{}", elem, code))
+        .or_insert(0) += 1;
+}
+
+/// Keeps the best (lowest) of two priorities.
+fn best_priority(priority: &mut Option<u32>, other: u32) {
+    *priority = Some(priority.map_or(other, |p| p.min(other)));
+}
+
+/// The same, for the priority of a writing or reading.
+fn best_form_priority(priorities: &mut HashMap<String, u32>, form: &str, other: u32) {
+    match priorities.get_mut(form) {
+        Some(p) => *p = (*p).min(other),
+        None => {
+            priorities.insert(form.into(), other);
+        }
+    }
+}
+
+// The misc, field, dialect, and writing/reading info codes that JMDict
+// uses, as of when this was written.  Most of them are only carried through
+// as tags, but any others are reported, since they may need handling.
+const KNOWN_MISC: &[&str] = &[
+    "abbr",
+    "arch",
+    "char",
+    "chn",
+    "col",
+    "company",
+    "creat",
+    "dated",
+    "dei",
+    "derog",
+    "doc",
+    "euph",
+    "ev",
+    "fam",
+    "fem",
+    "fict",
+    "form",
+    "given",
+    "group",
+    "hist",
+    "hon",
+    "hum",
+    "id",
+    "joc",
+    "leg",
+    "m-sl",
+    "male",
+    "myth",
+    "net-sl",
+    "obj",
+    "obs",
+    "on-mim",
+    "organization",
+    "oth",
+    "person",
+    "place",
+    "poet",
+    "pol",
+    "product",
+    "proverb",
+    "quote",
+    "rare",
+    "relig",
+    "sens",
+    "serv",
+    "ship",
+    "sl",
+    "station",
+    "surname",
+    "uk",
+    "unclass",
+    "vulg",
+    "work",
+    "X",
+    "yoji",
+];
+const KNOWN_FIELDS: &[&str] = &[
+    "agric", "anat", "archeol", "archit", "art", "astron", "audvid", "aviat", "baseb", "biochem",
+    "biol", "bot", "boxing", "Buddh", "bus", "cards", "chem", "chmyth", "Christn", "civeng",
+    "cloth", "comp", "cryst", "dent", "ecol", "econ", "elec", "electr", "embryo", "engr", "ent",
+    "figskt", "film", "finc", "fish", "food", "gardn", "genet", "geogr", "geol", "geom", "go",
+    "golf", "gramm", "grmyth", "hanaf", "horse", "internet", "jpmyth", "kabuki", "law", "ling",
+    "logic", "MA", "mahj", "manga", "math", "mech", "med", "met", "mil", "min", "mining", "motor",
+    "music", "noh", "ornith", "paleo", "pathol", "pharm", "phil", "photo", "physics", "physiol",
+    "politics", "print", "prowres", "psy", "psyanal", "psych", "rail", "rommyth", "Shinto",
+    "shogi", "ski", "sports", "stat", "stockm", "sumo", "surg", "telec", "tradem", "tv", "vet",
+    "vidg", "zool",
+];
+const KNOWN_DIALECTS: &[&str] = &[
+    "bra", "hob", "ksb", "ktb", "kyb", "kyu", "nab", "osb", "rkb", "thb", "tsb", "tsug",
+];
+const KNOWN_FORM_INFO: &[&str] = &[
+    "ateji", "gikun", "iK", "ik", "io", "oK", "ok", "rK", "rk", "sK", "sk",
+];
+
+lazy_static! {
+    static ref ENTITY_DECLARATION: Regex =
+        Regex::new(r#"<!ENTITY\s+([^\s"]+)\s+"([^"]*)"\s*>"#).unwrap();
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WordEntry {
+    // The entry's JMDict sequence number (its `ent_seq`), which stays the
+    // same across JMDict releases.
+    pub sequence: Option<u32>,
+
     pub writings: Vec<String>, // Kanji-based writings of the word.
     pub readings: Vec<String>, // Furigana and kana-based writings of the word.
-    pub definitions: Vec<String>,
+
+    // The writings that readings are restricted to, for readings that
+    // only apply to some of the word's writings.  Readings that apply to
+    // none of them (such as the katakana reading of a word that's
+    // otherwise written in kanji) map to an empty list.
+    pub reading_restrictions: HashMap<String, Vec<String>>,
+
+    // Information codes for individual writings and readings, from
+    // `<ke_inf>` and `<re_inf>`, e.g. "iK" (irregular kanji), "rK"
+    // (rarely-used kanji form), or "ateji".
+    pub form_info: HashMap<String, Vec<String>>,
+
+    pub definitions: Vec<Sense>,
     pub conj: ConjugationClass,
     pub pos: PartOfSpeech,
     pub usually_kana: bool, // When true, indicates that the word is usually written in kana alone.
@@ -45,6 +222,11 @@ pub struct WordEntry {
     // A lower numerical value indicates a more common word.
     pub priority: u32,
 
+    // The same, but for the individual writings and readings of the word,
+    // since some forms are much more common than others.  Forms that
+    // aren't listed have the priority of the whole word.
+    pub form_priorities: HashMap<String, u32>,
+
     // Set of tags found, in the format "parent_element:entity".
     // For example, if "<pos>&conj;</pos>" is found in the xml, then there
     // will be an entry "pos:conj" in this set.
@@ -57,16 +239,203 @@ pub struct WordEntry {
 impl WordEntry {
     pub fn new() -> WordEntry {
         WordEntry {
+            sequence: None,
             writings: Vec::new(),
             readings: Vec::new(),
+            reading_restrictions: HashMap::new(),
+            form_info: HashMap::new(),
             definitions: Vec::new(),
             conj: ConjugationClass::Other,
             pos: PartOfSpeech::Unknown,
             usually_kana: false,
             priority: 100000,
+            form_priorities: HashMap::new(),
             tags: HashSet::new(),
         }
     }
+
+    /// The priority of one of the word's writings or readings.
+    pub fn form_priority(&self, form: &str) -> u32 {
+        self.form_priorities
+            .get(form)
+            .copied()
+            .unwrap_or(self.priority)
+    }
+
+    /// Whether the writing or reading is rarely used, irregular,
+    /// outdated, or only meant for searching.
+    pub fn is_rare_form(&self, form: &str) -> bool {
+        match self.form_info.get(form) {
+            Some(codes) => codes.iter().any(|code| {
+                matches!(
+                    code.as_str(),
+                    "iK" | "ik" | "io" | "oK" | "ok" | "rK" | "rk" | "sK" | "sk"
+                )
+            }),
+            None => false,
+        }
+    }
+
+    /// A copy of the entry without its rare writings and readings (see
+    /// `is_rare_form()`).  At least one reading is always kept.
+    pub fn without_rare_forms(&self) -> WordEntry {
+        let mut entry = self.clone();
+        entry.writings.retain(|w| !self.is_rare_form(w));
+        if self.readings.iter().any(|r| !self.is_rare_form(r)) {
+            entry.readings.retain(|r| !self.is_rare_form(r));
+        }
+        entry
+    }
+
+    /// Whether the word is only used archaically: every sense is either
+    /// marked archaic or only has archaic parts of speech (see
+    /// `Sense::is_archaic()`).
+    pub fn is_archaic(&self) -> bool {
+        !self.definitions.is_empty() && self.definitions.iter().all(|sense| sense.is_archaic())
+    }
+
+    /// Whether the reading is a valid reading of the given writing.
+    pub fn reading_applies(&self, reading: &str, writing: &str) -> bool {
+        match self.reading_restrictions.get(reading) {
+            Some(writings) => writings.iter().any(|w| w == writing),
+            None => true,
+        }
+    }
+
+    /// The first reading that applies to the given writing, or the first
+    /// reading if there's no such reading.
+    pub fn reading_of(&self, writing: &str) -> &str {
+        self.readings
+            .iter()
+            .find(|reading| self.reading_applies(reading, writing))
+            .unwrap_or(&self.readings[0])
+    }
+}
+
+/// A single sense (meaning) of a word, with its English glosses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sense {
+    pub glosses: Vec<String>,
+
+    // Tags that apply to this sense, in the same format as
+    // `WordEntry::tags`.  JMDict only lists the part of speech when it
+    // changes from the previous sense, but here every sense has its own.
+    pub tags: Vec<String>,
+
+    // Cross-references to related words and to antonyms, in JMDict's
+    // format: the word, optionally followed by its reading and the number
+    // of the referenced sense, separated by "・", e.g. "丸・まる・1".
+    pub xrefs: Vec<String>,
+    pub antonyms: Vec<String>,
+
+    // Notes on the sense's usage, e.g. "usu. in the negative".
+    pub notes: Vec<String>,
+
+    // For loanwords, the words they come from.
+    pub sources: Vec<LoanwordSource>,
+}
+
+impl Sense {
+    pub fn new() -> Sense {
+        Sense {
+            glosses: Vec::new(),
+            tags: Vec::new(),
+            xrefs: Vec::new(),
+            antonyms: Vec::new(),
+            notes: Vec::new(),
+            sources: Vec::new(),
+        }
+    }
+
+    /// The glosses as a single string, separated by semicolons.
+    pub fn text(&self) -> String {
+        self.glosses.join("; ")
+    }
+
+    /// The part-of-speech codes of this sense, e.g. "n" or "vs".
+    pub fn pos_codes(&self) -> impl Iterator<Item = &str> {
+        self.tags.iter().filter_map(|tag| tag.strip_prefix("pos:"))
+    }
+
+    /// Whether the sense is marked archaic, or only has archaic parts of
+    /// speech: nidan and yodan verbs, and ku, shiku, and nari adjectives.
+    pub fn is_archaic(&self) -> bool {
+        let archaic_pos = |code: &str| {
+            code.starts_with("v2")
+                || code.starts_with("v4")
+                || matches!(code, "adj-ku" | "adj-shiku" | "adj-nari")
+        };
+        self.tags.iter().any(|tag| tag == "misc:arch")
+            || (self.pos_codes().next().is_some() && self.pos_codes().all(archaic_pos))
+    }
+}
+
+/// How word priorities are computed from JMDict's priority markers, which
+/// say which frequency band (nf01 to nf48, of 500 words each) and which
+/// word lists (news1/2, ichi1/2, gai1/2, spec1/2) a word is in.  The
+/// priority of a word is that of its best marker.
+#[derive(Debug, Clone)]
+pub struct PriorityModel {
+    pub nf_step: u32,  // Per frequency band, so nf01 is 0, nf02 is `nf_step`, etc.
+    pub tier1: u32,    // news1, ichi1, and gai1.
+    pub tier2: u32,    // news2, ichi2, and gai2.
+    pub other: u32,    // spec1, spec2, and any other markers.
+    pub unmarked: u32, // Words without any markers.
+}
+
+impl Default for PriorityModel {
+    fn default() -> PriorityModel {
+        PriorityModel {
+            nf_step: 500,
+            tier1: 6000,
+            tier2: 18000,
+            other: 24000,
+            unmarked: 100000,
+        }
+    }
+}
+
+impl PriorityModel {
+    /// The priority of a single priority marker, e.g. "nf12" or "news1".
+    /// A word or form has the priority of its best marker, or `unmarked`
+    /// if it has none.
+    pub fn marker_priority(&self, marker: &str) -> u32 {
+        let priority = match marker.strip_prefix("nf") {
+            Some(band) => band.parse::<u32>().unwrap_or(1).saturating_sub(1) * self.nf_step,
+            None => match marker {
+                "news1" | "ichi1" | "gai1" => self.tier1,
+                "news2" | "ichi2" | "gai2" => self.tier2,
+                _ => self.other,
+            },
+        };
+        priority.min(self.unmarked)
+    }
+}
+
+/// The origin of a loanword, e.g. German "Arbeit" for アルバイト.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoanwordSource {
+    pub lang: String,  // ISO 639-2 language code, e.g. "ger".
+    pub word: String,  // Can be empty if JMDict only gives the language.
+    pub partial: bool, // Only part of the loanword comes from this word.
+    pub wasei: bool,   // Made in Japan from foreign words, like "salaryman".
+}
+
+impl LoanwordSource {
+    fn from_element(e: &quick_xml::events::BytesStart) -> LoanwordSource {
+        let attribute = |name: &str| {
+            e.try_get_attribute(name)
+                .ok()
+                .flatten()
+                .map(|a| String::from_utf8_lossy(&a.value).into_owned())
+        };
+        LoanwordSource {
+            lang: attribute("xml:lang").unwrap_or_else(|| "eng".into()),
+            word: String::new(),
+            partial: attribute("ls_type").as_deref() == Some("part"),
+            wasei: attribute("ls_wasei").as_deref() == Some("y"),
+        }
+    }
 }
 
 /// Indicates the conjugation rules that a word follows.
@@ -74,7 +443,7 @@ impl WordEntry {
 /// The `Other` variant indicates a word that either doesn't conjugate (such
 /// as nouns, na-adjectives, etc.), or a word whose conjugations rules are
 /// unclear due to being e.g. archaic.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub enum ConjugationClass {
     // Default.  Assumed not to conjugate.
     Other,
@@ -116,7 +485,7 @@ pub enum ConjugationClass {
 /// much deeper than what's represented here.  This is just a broad
 /// surface-level categorization.  More detailed breakdowns can be accessed
 /// in `WordEntry::tags` when needed.
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Ord, PartialOrd)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Serialize, Deserialize)]
 pub enum PartOfSpeech {
     Unknown,
     Copula,
@@ -134,28 +503,41 @@ pub enum PartOfSpeech {
 //================================================================
 // Parser implementation.
 
-impl<R: BufRead> Iterator for Parser<R> {
-    type Item = WordEntry;
-
-    fn next(&mut self) -> Option<WordEntry> {
-        fn add_tag(entry: &mut WordEntry, elem: &str, tag: &str) {
-            let tag = tag.trim();
-            if tag.starts_with("&") && tag.ends_with(";") {
-                entry
-                    .tags
-                    .insert(format!("{}:{}", elem, (&tag[1..(tag.len() - 1)])));
+impl<R: BufRead> Parser<R> {
+    /// Reads the next entry, using `buf` as the xml event buffer.
+    fn read_entry(&mut self, buf: &mut Vec<u8>) -> Option<WordEntry> {
+        fn add_tag(entry: &mut WordEntry, elem: &str, code: &str) {
+            if !code.is_empty() {
+                let tag = format!("{}:{}", elem, code);
+                if let Some(sense) = entry.definitions.last_mut() {
+                    sense.tags.push(tag.clone());
+                }
+                entry.tags.insert(tag);
             }
         }
 
         loop {
-            match self.xml_parser.read_event_into(&mut self.buf) {
+            buf.clear();
+            match self.xml_parser.read_event_into(buf) {
                 Ok(Event::Start(ref e)) => match e.name().as_ref() {
+                    b"ent_seq" => {
+                        self.cur_xml_elem = Elem::Sequence;
+                    }
                     b"keb" => {
                         self.cur_xml_elem = Elem::Keb;
                     }
                     b"reb" => {
                         self.cur_xml_elem = Elem::Reb;
                     }
+                    b"ke_inf" => {
+                        self.cur_xml_elem = Elem::WritingInfo;
+                    }
+                    b"re_inf" => {
+                        self.cur_xml_elem = Elem::ReadingInfo;
+                    }
+                    b"re_restr" => {
+                        self.cur_xml_elem = Elem::ReadingRestriction;
+                    }
                     b"pos" => {
                         self.cur_xml_elem = Elem::Pos;
                     }
@@ -177,11 +559,22 @@ impl<R: BufRead> Iterator for Parser<R> {
                     b"sense" => {
                         self.cur_xml_elem = Elem::Sense;
 
-                        // Start new definition within the entry.
-                        if self.cur_entry.definitions.is_empty()
-                            || self.cur_entry.definitions.last().unwrap().trim().len() > 0
-                        {
-                            self.cur_entry.definitions.push("".into());
+                        // Start new sense within the entry.
+                        self.cur_entry.definitions.push(Sense::new());
+                    }
+                    b"xref" => {
+                        self.cur_xml_elem = Elem::Xref;
+                    }
+                    b"ant" => {
+                        self.cur_xml_elem = Elem::Antonym;
+                    }
+                    b"s_inf" => {
+                        self.cur_xml_elem = Elem::SenseNote;
+                    }
+                    b"lsource" => {
+                        self.cur_xml_elem = Elem::LoanwordSource;
+                        if let Some(sense) = self.cur_entry.definitions.last_mut() {
+                            sense.sources.push(LoanwordSource::from_element(e));
                         }
                     }
                     b"gloss" => {
@@ -197,23 +590,50 @@ impl<R: BufRead> Iterator for Parser<R> {
                     }
                     _ => {}
                 },
+                Ok(Event::Empty(ref e)) => match e.name().as_ref() {
+                    // The reading doesn't apply to any of the writings.
+                    b"re_nokanji" => {
+                        if let Some(reading) = self.cur_entry.readings.last() {
+                            self.cur_entry
+                                .reading_restrictions
+                                .insert(reading.clone(), Vec::new());
+                        }
+                    }
+                    // A loanword source with only the language given.
+                    b"lsource" => {
+                        if let Some(sense) = self.cur_entry.definitions.last_mut() {
+                            sense.sources.push(LoanwordSource::from_element(e));
+                        }
+                    }
+                    _ => {}
+                },
                 Ok(Event::End(ref e)) => {
                     self.cur_xml_elem = Elem::None;
                     if e.name().as_ref() == b"gloss" {
                         // Jump back out into "sense" element.
                         self.cur_xml_elem = Elem::Sense;
                     } else if e.name().as_ref() == b"sense" {
-                        // Remove last two characters, which will just be "; ".
-                        self.cur_entry.definitions.last_mut().unwrap().pop();
-                        self.cur_entry.definitions.last_mut().unwrap().pop();
-                    } else if e.name().as_ref() == b"entry" {
-                        // Clean up the definitions list.
-                        if !self.cur_entry.definitions.is_empty()
-                            && self.cur_entry.definitions.last().unwrap().trim().is_empty()
-                        {
-                            self.cur_entry.definitions.pop();
+                        let mut sense = self.cur_entry.definitions.pop().unwrap();
+
+                        // Carry over the part of speech from the previous
+                        // sense if this one doesn't list its own.
+                        let pos: Vec<String> = sense
+                            .tags
+                            .iter()
+                            .filter(|tag| tag.starts_with("pos:"))
+                            .cloned()
+                            .collect();
+                        if pos.is_empty() {
+                            sense.tags.splice(0..0, self.sense_pos.iter().cloned());
+                        } else {
+                            self.sense_pos = pos;
                         }
 
+                        // Drop senses without any English glosses.
+                        if !sense.glosses.is_empty() {
+                            self.cur_entry.definitions.push(sense);
+                        }
+                    } else if e.name().as_ref() == b"entry" {
                         // If there are no kanji writings, make sure it's
                         // marked as "usually kana", because JMDict forgets
                         // this sometimes (or possibly just assumes it's
@@ -223,90 +643,176 @@ impl<R: BufRead> Iterator for Parser<R> {
                         }
 
                         // Calculate word priority.
-                        let priorities = if self.cur_entry.usually_kana {
-                            &self.kana_priorities
+                        let unmarked = self.priority_model.unmarked;
+                        let priority = if self.cur_entry.usually_kana {
+                            self.kana_priority
                         } else {
-                            &self.kanji_priorities
+                            self.kanji_priority
                         };
-                        for p_text in priorities.iter() {
-                            let p = if p_text.starts_with("nf") {
-                                (&p_text[2..]).parse::<u32>().unwrap().saturating_sub(1) * 500
-                            } else {
-                                match p_text.as_str() {
-                                    "news1" | "ichi1" | "gai1" => 6000,
-                                    "news2" | "ichi2" | "gai2" => 18000,
-                                    _ => 24000,
-                                }
-                            };
-                            self.cur_entry.priority = self.cur_entry.priority.min(p);
+                        self.cur_entry.priority = priority.unwrap_or(unmarked);
+
+                        // And the priorities of the individual forms.  If
+                        // only some of the word's writings or readings
+                        // have markers, the others are less common.
+                        let entry = &mut self.cur_entry;
+                        for (forms, marked) in [
+                            (&entry.writings, self.kanji_priority.is_some()),
+                            (&entry.readings, self.kana_priority.is_some()),
+                        ] {
+                            if !marked {
+                                continue;
+                            }
+                            for form in forms.iter() {
+                                let priority =
+                                    self.form_priorities.get(form).copied().unwrap_or(unmarked);
+                                entry.form_priorities.insert(form.clone(), priority);
+                            }
                         }
 
                         // Reset for next entry, and return the `WordEntry`.
-                        self.kanji_priorities.clear();
-                        self.kana_priorities.clear();
+                        self.kanji_priority = None;
+                        self.kana_priority = None;
+                        self.form_priorities.clear();
+                        self.sense_pos.clear();
                         return Some(std::mem::replace(&mut self.cur_entry, WordEntry::new()));
                     }
                 }
                 Ok(Event::Text(e)) => {
-                    let text = std::str::from_utf8(&*e).unwrap().into();
+                    let text = std::str::from_utf8(&e).unwrap();
                     match self.cur_xml_elem {
                         Elem::Gloss => {
                             self.cur_entry
                                 .definitions
                                 .last_mut()
                                 .unwrap()
-                                .push_str(&format!("{}; ", text));
+                                .glosses
+                                .push(text.into());
+                        }
+                        Elem::Sequence => {
+                            self.cur_entry.sequence = text.trim().parse().ok();
                         }
                         Elem::Keb => {
-                            self.cur_entry.writings.push(text);
+                            self.cur_entry.writings.push(text.into());
+                        }
+                        Elem::Xref => {
+                            if let Some(sense) = self.cur_entry.definitions.last_mut() {
+                                sense.xrefs.push(text.into());
+                            }
+                        }
+                        Elem::Antonym => {
+                            if let Some(sense) = self.cur_entry.definitions.last_mut() {
+                                sense.antonyms.push(text.into());
+                            }
+                        }
+                        Elem::SenseNote => {
+                            if let Some(sense) = self.cur_entry.definitions.last_mut() {
+                                sense.notes.push(text.into());
+                            }
+                        }
+                        Elem::LoanwordSource => {
+                            if let Some(source) = self
+                                .cur_entry
+                                .definitions
+                                .last_mut()
+                                .and_then(|sense| sense.sources.last_mut())
+                            {
+                                source.word = text.into();
+                            }
                         }
                         Elem::Reb => {
-                            self.cur_entry.readings.push(text);
+                            self.cur_entry.readings.push(text.into());
+                        }
+                        Elem::ReadingRestriction => {
+                            if let Some(reading) = self.cur_entry.readings.last() {
+                                self.cur_entry
+                                    .reading_restrictions
+                                    .entry(reading.clone())
+                                    .or_default()
+                                    .push(text.into());
+                            }
+                        }
+                        Elem::WritingInfo => {
+                            let code = entity_name(&self.entities, text);
+                            check_tag(&mut self.unknown_tags, "ke_inf", code, KNOWN_FORM_INFO);
+                            if let Some(writing) = self.cur_entry.writings.last() {
+                                self.cur_entry
+                                    .form_info
+                                    .entry(writing.clone())
+                                    .or_default()
+                                    .push(code.into());
+                            }
+                        }
+                        Elem::ReadingInfo => {
+                            let code = entity_name(&self.entities, text);
+                            check_tag(&mut self.unknown_tags, "re_inf", code, KNOWN_FORM_INFO);
+                            if let Some(reading) = self.cur_entry.readings.last() {
+                                self.cur_entry
+                                    .form_info
+                                    .entry(reading.clone())
+                                    .or_default()
+                                    .push(code.into());
+                            }
                         }
                         Elem::Misc => {
-                            add_tag(&mut self.cur_entry, "misc", &text);
+                            let code = entity_name(&self.entities, text);
+                            check_tag(&mut self.unknown_tags, "misc", code, KNOWN_MISC);
+                            add_tag(&mut self.cur_entry, "misc", code);
 
                             // Usually written in kana alone.
-                            if text == "&uk;" {
+                            if code == "uk" {
                                 self.cur_entry.usually_kana = true;
                             }
                         }
                         Elem::Dialect => {
-                            add_tag(&mut self.cur_entry, "dial", &text);
+                            let code = entity_name(&self.entities, text);
+                            check_tag(&mut self.unknown_tags, "dial", code, KNOWN_DIALECTS);
+                            add_tag(&mut self.cur_entry, "dial", code);
                         }
                         Elem::Field => {
-                            add_tag(&mut self.cur_entry, "field", &text);
+                            let code = entity_name(&self.entities, text);
+                            check_tag(&mut self.unknown_tags, "field", code, KNOWN_FIELDS);
+                            add_tag(&mut self.cur_entry, "field", code);
                         }
                         Elem::WritingPriority => {
-                            self.kanji_priorities.push(text.trim().into());
+                            let priority = self.priority_model.marker_priority(text.trim());
+                            best_priority(&mut self.kanji_priority, priority);
+                            if let Some(writing) = self.cur_entry.writings.last() {
+                                best_form_priority(&mut self.form_priorities, writing, priority);
+                            }
                         }
                         Elem::ReadingPriority => {
-                            self.kana_priorities.push(text.trim().into());
+                            let priority = self.priority_model.marker_priority(text.trim());
+                            best_priority(&mut self.kana_priority, priority);
+                            if let Some(reading) = self.cur_entry.readings.last() {
+                                best_form_priority(&mut self.form_priorities, reading, priority);
+                            }
                         }
                         Elem::Pos => {
-                            add_tag(&mut self.cur_entry, "pos", &text);
+                            let code = entity_name(&self.entities, text);
+                            add_tag(&mut self.cur_entry, "pos", code);
 
                             use PartOfSpeech::*;
-                            match text.as_str() {
+                            match code {
                                 // Expression marker.
-                                "&exp;" => {
+                                "exp" => {
                                     self.cur_entry.pos |= Expression;
                                 },
 
                                 // The copula, だ, and words that use it as an ending.
-                                "&cop-da;" => {
+                                // Newer versions of JMDict call it "cop".
+                                "cop" | "cop-da" => {
                                     self.cur_entry.pos |= Copula;
                                     self.cur_entry.conj |= ConjugationClass::Copula;
                                 },
 
                                 // i-adjectives.
-                                "&adj-i;" => {
+                                "adj-i" => {
                                     self.cur_entry.pos |= Adjective;
                                     self.cur_entry.conj |= ConjugationClass::IAdjective;
                                 },
 
                                 // The adjective いい and compounds that end with it.
-                                "&adj-ix;" => {
+                                "adj-ix" => {
                                     self.cur_entry.pos |= Adjective;
                                     self.cur_entry.conj |= ConjugationClass::IrregularIAdjective;
                                 },
@@ -317,27 +823,27 @@ impl<R: BufRead> Iterator for Parser<R> {
                                 // *not* include things like na-adjectives, which
                                 // require an additional particle to behave
                                 // that way.
-                                "&adj-pn;" => { // Pre-noun adjectival.
+                                "adj-pn" => { // Pre-noun adjectival.
                                     self.cur_entry.pos |= Adjective;
                                 },
 
                                 // Ichidan verbs.
-                                "&v1;" => {
+                                "v1" => {
                                     self.cur_entry.pos |= Verb;
                                     self.cur_entry.conj |= ConjugationClass::IchidanVerb;
                                 },
 
                                 // Godan verbs.
-                                "&vn;" => {
+                                "vn" => {
                                     self.cur_entry.pos |= Verb;
                                     self.cur_entry.conj |= ConjugationClass::GodanVerbNu;
                                 }
-                                "&v5u;" | "&v5n;" | "&v4b;" | "&v5b;" | "&v4g;"
-                                | "&v5g;" | "&v4h;" | "&v4k;" | "&v5k;" | "&v4m;"
-                                | "&v5m;" | "&v4r;" | "&v5r;" | "&v4s;" | "&v5s;"
-                                | "&v4t;" | "&v5t;" => {
+                                "v5u" | "v5n" | "v4b" | "v5b" | "v4g"
+                                | "v5g" | "v4h" | "v4k" | "v5k" | "v4m"
+                                | "v5m" | "v4r" | "v5r" | "v4s" | "v5s"
+                                | "v4t" | "v5t" => {
                                     self.cur_entry.pos |= Verb;
-                                    self.cur_entry.conj |= match &text[3..4] {
+                                    self.cur_entry.conj |= match &code[2..3] {
                                         "u" => ConjugationClass::GodanVerbU,
                                         "t" => ConjugationClass::GodanVerbTsu,
                                         "r" => ConjugationClass::GodanVerbRu,
@@ -354,136 +860,137 @@ impl<R: BufRead> Iterator for Parser<R> {
 
                                 // する and verbs that end with it and conjugate
                                 // like it.
-                                "&vs-i;" => {
+                                "vs-i" => {
                                     self.cur_entry.pos |= Verb;
                                     self.cur_entry.conj |= ConjugationClass::SuruVerb;
                                 },
 
                                 // Verbs ending in する but that don't quite
                                 // conjugate like it.
-                                "&vs-s;" => {
+                                "vs-s" => {
                                     self.cur_entry.pos |= Verb;
                                     self.cur_entry.conj |= ConjugationClass::SuruVerbSC;
                                 },
 
                                 // 来る and verbs that end with it and conjugate
                                 // like it.
-                                "&vk;" => {
+                                "vk" => {
                                     self.cur_entry.pos |= Verb;
                                     self.cur_entry.conj |= ConjugationClass::KuruVerb;
                                 },
 
                                 // 行く and verbs that end with it or its variants
                                 // (いく and ゆく) and conjugate like it.
-                                "&v5k-s;" => {
+                                "v5k-s" => {
                                     self.cur_entry.pos |= Verb;
                                     self.cur_entry.conj |= ConjugationClass::IkuVerb;
                                 }
 
                                 // Special class of verbs that end with either
                                 // さる or しゃる.
-                                "&v5aru;" => {
+                                "v5aru" => {
                                     self.cur_entry.pos |= Verb;
                                     self.cur_entry.conj |= ConjugationClass::SharuVerb;
                                 },
 
                                 // ある ("to be") and verbs that end with and
                                 // conjugate like it.
-                                "&v5r-i;" => {
+                                "v5r-i" => {
                                     self.cur_entry.pos |= Verb;
                                     self.cur_entry.conj |= ConjugationClass::AruVerb;
                                 },
 
                                 // 呉れる / くれる and words the end with it.
-                                "&v1-s;" => {
+                                "v1-s" => {
                                     self.cur_entry.pos |= Verb;
                                     self.cur_entry.conj |= ConjugationClass::KureruVerb;
                                 }
 
                                 // Other irregular verbs.
-                                "&vz;" | // ずる verb.
-                                "&v5u-s;" // Special class of う verbs.
+                                "vz" | // ずる verb.
+                                "v5u-s" // Special class of う verbs.
                                 => {
                                     self.cur_entry.pos |= Verb;
                                     self.cur_entry.conj |= ConjugationClass::IrregularVerb;
                                 },
 
                                 // Words that essentially classify as nouns.
-                                "&vs;" | // So-called する verb, grammatically a noun.
-                                "&adj-na;" | // な adjective, grammatically a noun.
-                                "&adj-no;" | // の adjective, grammatically a noun.
-                                "&adj-t;" | // たる adjective, grammatically a noun.
-                                "&n-adv;" | // Adverbial noun.
-                                "&n-pref;" | // Noun used as prefix.
-                                "&n-suf;" | // Noun used as suffix.
-                                "&n-t;" | // Noun, temporal.
-                                "&n;" | // Noun
-                                "&pn;" | // Pronoun.
-                                "&num;" => {
+                                "vs" | // So-called する verb, grammatically a noun.
+                                "adj-na" | // な adjective, grammatically a noun.
+                                "adj-no" | // の adjective, grammatically a noun.
+                                "adj-t" | // たる adjective, grammatically a noun.
+                                "n-adv" | // Adverbial noun.
+                                "n-pref" | // Noun used as prefix.
+                                "n-suf" | // Noun used as suffix.
+                                "n-t" | // Noun, temporal.
+                                "n" | // Noun
+                                "pn" | // Pronoun.
+                                "num" => {
                                     self.cur_entry.pos |= Noun;
                                 }
 
                                 // Adverbs
-                                "&adv-to;" |
-                                "&adv;" => {
+                                "adv-to" |
+                                "adv" => {
                                     self.cur_entry.pos |= Adverb;
                                 }
 
                                 // Particle
-                                "&prt;" => {
+                                "prt" => {
                                     self.cur_entry.pos |= Particle;
                                 }
 
                                 // Conjunction.
-                                "&conj;" => {
+                                "conj" => {
                                     self.cur_entry.pos |= Conjunction;
                                 }
 
                                 // Categories that we don't care about or don't know
                                 // what to do with right now.
-                                "&vt;" | // Transitive verb.
-                                "&vi;" | // Intransitive verb.
-                                "&adj-f;" | // Noun or verb acting prenominally.
-                                "&ctr;" | // Counter.
-                                "&int;" | // Interjection.
-                                "&aux;" | // Auxiliary.
-                                "&aux-v;" | // Auxiliary verb.
-                                "&aux-adj;" | // Auxiliary adjective.
-                                "&pref;" | // Prefix.
-                                "&suf;" | // Suffix.
-                                "&unc;" | // Unclassified.
+                                "vt" | // Transitive verb.
+                                "vi" | // Intransitive verb.
+                                "adj-f" | // Noun or verb acting prenominally.
+                                "ctr" | // Counter.
+                                "int" | // Interjection.
+                                "aux" | // Auxiliary.
+                                "aux-v" | // Auxiliary verb.
+                                "aux-adj" | // Auxiliary adjective.
+                                "pref" | // Prefix.
+                                "suf" | // Suffix.
+                                "unc" | // Unclassified.
                                 // Archaic verbs.
-                                "&adj-kari;" | // Archaic.
-                                "&adj-ku;" | // Archaic.
-                                "&adj-nari;" | // Archaic.
-                                "&adj-shiku;" | // Archaic.
-                                "&vr;" | // Irregular る verb whose plain ending is り. Pretty much all archaic.
-                                "&vs-c;" | // Precursors to する, archaic.
-                                "&v2a-s;" | // Nidan verb, archaic.
-                                "&v2b-k;" | // Nidan verb, archaic.
-                                "&v2d-s;" | // Nidan verb, archaic.
-                                "&v2g-k;" | // Nidan verb, archaic.
-                                "&v2g-s;" | // Nidan verb, archaic.
-                                "&v2h-k;" | // Nidan verb, archaic.
-                                "&v2h-s;" | // Nidan verb, archaic.
-                                "&v2k-k;" | // Nidan verb, archaic.
-                                "&v2k-s;" | // Nidan verb, archaic.
-                                "&v2m-s;" | // Nidan verb, archaic.
-                                "&v2n-s;" | // Nidan verb, archaic.
-                                "&v2r-k;" | // Nidan verb, archaic.
-                                "&v2r-s;" | // Nidan verb, archaic.
-                                "&v2s-s;" | // Nidan verb, archaic.
-                                "&v2t-k;" | // Nidan verb, archaic.
-                                "&v2t-s;" | // Nidan verb, archaic.
-                                "&v2w-s;" | // Nidan verb, archaic.
-                                "&v2y-k;" | // Nidan verb, archaic.
-                                "&v2y-s;" | // Nidan verb, archaic.
-                                "&v2z-s;" // Nidan verb, archaic.
+                                "adj-kari" | // Archaic.
+                                "adj-ku" | // Archaic.
+                                "adj-nari" | // Archaic.
+                                "adj-shiku" | // Archaic.
+                                "vr" | // Irregular る verb whose plain ending is り. Pretty much all archaic.
+                                "vs-c" | // Precursors to する, archaic.
+                                "v2a-s" | // Nidan verb, archaic.
+                                "v2b-k" | // Nidan verb, archaic.
+                                "v2d-s" | // Nidan verb, archaic.
+                                "v2g-k" | // Nidan verb, archaic.
+                                "v2g-s" | // Nidan verb, archaic.
+                                "v2h-k" | // Nidan verb, archaic.
+                                "v2h-s" | // Nidan verb, archaic.
+                                "v2k-k" | // Nidan verb, archaic.
+                                "v2k-s" | // Nidan verb, archaic.
+                                "v2m-s" | // Nidan verb, archaic.
+                                "v2n-s" | // Nidan verb, archaic.
+                                "v2r-k" | // Nidan verb, archaic.
+                                "v2r-s" | // Nidan verb, archaic.
+                                "v2s-s" | // Nidan verb, archaic.
+                                "v2t-k" | // Nidan verb, archaic.
+                                "v2t-s" | // Nidan verb, archaic.
+                                "v2w-s" | // Nidan verb, archaic.
+                                "v2y-k" | // Nidan verb, archaic.
+                                "v2y-s" | // Nidan verb, archaic.
+                                "v2z-s" // Nidan verb, archaic.
                                 => {
                                 },
 
                                 // Unknown classification string.
                                 _ => {
+                                    add_unknown_tag(&mut self.unknown_tags, "pos", code);
                                 }
                             }
                         }
@@ -498,20 +1005,42 @@ impl<R: BufRead> Iterator for Parser<R> {
                         e
                     )
                 }
+                Ok(Event::DocType(ref e)) => {
+                    let dtd = String::from_utf8_lossy(e);
+                    for caps in ENTITY_DECLARATION.captures_iter(&dtd) {
+                        self.entities.insert(caps[2].into(), caps[1].into());
+                    }
+                }
                 Ok(Event::Eof) => {
                     return None;
                 }
                 _ => (),
             }
-            self.buf.clear();
         }
     }
 }
 
+impl<R: BufRead> Iterator for Parser<R> {
+    type Item = WordEntry;
+
+    fn next(&mut self) -> Option<WordEntry> {
+        // The buffer is taken out of the parser while reading, so that
+        // text can be borrowed from it rather than copied for every event.
+        let mut buf = std::mem::take(&mut self.buf);
+        let entry = self.read_entry(&mut buf);
+        self.buf = buf;
+        entry
+    }
+}
+
 enum Elem {
     None,
+    Sequence,
     Keb,
     Reb,
+    WritingInfo,
+    ReadingInfo,
+    ReadingRestriction,
     Pos,
     WritingPriority,
     ReadingPriority,
@@ -520,6 +1049,10 @@ enum Elem {
     Field,
     Sense,
     Gloss,
+    Xref,
+    Antonym,
+    SenseNote,
+    LoanwordSource,
 }
 
 //================================================================
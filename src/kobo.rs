@@ -1,25 +1,140 @@
 //! Types and functions for building and outputting a Kobo dictionary.
 
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::io::prelude::*;
-use std::io::BufWriter;
-use std::path::Path;
+use std::io::{BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
 
 use flate2::read::GzEncoder;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use unicode_categories::UnicodeCategories;
+use unicode_normalization::UnicodeNormalization;
 
-#[derive(Clone, Debug)]
+use crate::error::Error;
+use crate::output::OutputWriter;
+
+const HTML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?><html>";
+const HTML_FOOTER: &str = "</html>";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Entry {
     // The integer here is a very rough priority ranking indicating
     // the commonness of the word, specifically in that form.  A
     // lower numerical value indicates a more common word.
     pub keys: Vec<(String, u32)>,
     pub definition: String,
+
+    // The JMDict sequence number of the word the entry was generated
+    // from, if any.
+    pub jmdict_seq: Option<u32>,
+}
+
+/// Settings for writing a dictionary.  Besides `lang`, they're only used
+/// by the Kobo format.
+#[derive(Clone, Debug)]
+pub struct WriteSettings {
+    /// The language code of the dictionary's headwords, e.g. "ja" or "de".
+    pub lang: String,
+
+    /// Use the external `marisa-build` executable to build the words index,
+    /// rather than the built-in trie builder (`--builtin_trie`).
+    pub use_marisa_build: bool,
+
+    /// The maximum (uncompressed) size in bytes of each prefix html file.
+    /// Prefixes that exceed it have their lowest-priority entries removed.
+    pub max_prefix_size: Option<usize>,
+
+    /// The gzip compression level (0-9) of the prefix html files.
+    pub compression: u32,
+
+    /// A previously built version of the dictionary.  Prefix files whose
+    /// contents haven't changed are copied from it as-is rather than being
+    /// recompressed.  Everything else is still generated from scratch.
+    pub reuse_from: Option<PathBuf>,
+
+    /// The directory for the intermediate files of `marisa-build`.
+    /// Defaults to the system's temporary directory.
+    pub temp_dir: Option<PathBuf>,
+
+    /// Leave the intermediate files in place instead of deleting them, so
+    /// that they can be inspected.
+    pub keep_temp: bool,
+}
+
+/// The writer of `--format kobo`.
+pub struct KoboWriter(pub WriteSettings);
+
+impl OutputWriter for KoboWriter {
+    fn write(&self, entries: &[Entry], path: &Path) -> Result<(), Error> {
+        write_dictionary(entries, path, &self.0)
+    }
+
+    fn size(&self, entries: &[Entry]) -> Option<Result<u64, Error>> {
+        // The dictionary is built in full (minus the writing) to get its
+        // exact size, since the compression of the prefix files depends
+        // on how the entries end up being split between them.
+        Some(dictionary_size(entries, &self.0))
+    }
+}
+
+/// Writes the entries as a Kobo dicthtml zip file.
+pub fn write_dictionary(
+    entries: &[Entry],
+    output_path: &Path,
+    settings: &WriteSettings,
+) -> Result<(), Error> {
+    // The reused dictionary is read before the output file is created,
+    // since they can be the same file.
+    let base_prefix_files = read_reused_prefix_files(settings)?;
+    write_dictionary_to(
+        entries,
+        BufWriter::new(std::fs::File::create(output_path)?),
+        settings,
+        base_prefix_files,
+    )?;
+    Ok(())
 }
 
-pub fn write_dictionary(entries: &[Entry], output_path: &Path) -> std::io::Result<()> {
+/// Computes the size in bytes of the dictionary file that
+/// `write_dictionary()` would write, without writing anything.
+pub fn dictionary_size(entries: &[Entry], settings: &WriteSettings) -> Result<u64, Error> {
+    let base_prefix_files = read_reused_prefix_files(settings)?;
+    Ok(write_dictionary_to(entries, SizeCounter::default(), settings, base_prefix_files)?.len)
+}
+
+/// Reads the gzipped prefix files of the dictionary given by
+/// `--reuse_from`, if any, keyed by prefix.
+fn read_reused_prefix_files(settings: &WriteSettings) -> std::io::Result<HashMap<String, Vec<u8>>> {
+    let mut base_prefix_files = HashMap::new();
+    if let Some(ref base_path) = settings.reuse_from {
+        let mut zip_in = crate::archive::open(base_path)?;
+        for i in 0..zip_in.len() {
+            let mut f = zip_in.by_index(i)?;
+            let filename = crate::archive::member_name(f.name_raw());
+            if let Some(prefix) = filename.strip_suffix(".html") {
+                let mut data = Vec::new();
+                f.read_to_end(&mut data)?;
+                base_prefix_files.insert(prefix.into(), data);
+            }
+        }
+    }
+    Ok(base_prefix_files)
+}
+
+/// An entry of a prefix file: (key, definition texts, priority).
+type PrefixEntry<'a> = (String, Vec<&'a str>, u32);
+
+fn write_dictionary_to<W: Write + Seek>(
+    entries: &[Entry],
+    out: W,
+    settings: &WriteSettings,
+    mut base_prefix_files: HashMap<String, Vec<u8>>,
+) -> Result<W, Error> {
     // Sorted, de-duplicated list of keys.
-    let all_keys = {
+    let mut all_keys = {
         let max_priority = entries
             .iter()
             .map(|e| &e.keys[..])
@@ -28,7 +143,9 @@ pub fn write_dictionary(entries: &[Entry], output_path: &Path) -> std::io::Resul
         let mut keys = HashMap::new();
         for entry in entries.iter() {
             for entry_key in entry.keys.iter() {
-                let key = keys.entry(entry_key.0.clone()).or_insert(0);
+                let key = keys
+                    .entry(normalize_key(&entry_key.0, &settings.lang))
+                    .or_insert(0);
                 *key = (*key).max(max_priority - entry_key.1);
             }
         }
@@ -38,96 +155,54 @@ pub fn write_dictionary(entries: &[Entry], output_path: &Path) -> std::io::Resul
         all_keys
     };
 
-    //----------------------------------------------------------------
-    // Create the `words` and `words.original` data.
-
-    // Words as a new-line-separated text list.
-    let words_original = {
-        let mut words_original = String::new();
-        for key in all_keys.iter() {
-            words_original.push_str(&format!("{}\t{}\n", key.0, key.1));
-        }
-        words_original
-    };
-
-    // Create the marisa tree words data.
-    let words = {
-        // Write words to a temporary file.
-        let mut words_file = tempfile::NamedTempFile::new().unwrap();
-        words_file
-            .as_file_mut()
-            .write_all(words_original.as_bytes())
-            .unwrap();
-        words_file.as_file_mut().sync_all().unwrap();
-        let words_path = words_file.into_temp_path();
-
-        // Create a path for the marisa file.
-        let mut marisa_path = words_path.to_path_buf();
-        marisa_path.set_extension(".marisa.tmp");
-
-        // Run marisa-build to create the marisa trie data.
-        match std::process::Command::new("marisa-build")
-            .arg("-o")
-            .arg(marisa_path.as_os_str())
-            .arg(words_path.as_os_str())
-            .output()
-        {
-            Ok(output) => {
-                if !output.status.success() {
-                    eprintln!(
-                        "Error: \"marisa-build\" exited with a failure:\n{}",
-                        String::from_utf8_lossy(&output.stderr)
-                    );
-                    std::process::exit(1);
-                }
-            }
-            Err(e) => {
-                eprintln!("Error: attempt to run \"marisa-build\" failed: {}", e);
-                if e.kind() == std::io::ErrorKind::NotFound {
-                    eprintln!("Make sure you have marisa-build installed and in your path, and that you have the permissions needed to run it.");
-                }
-                std::process::exit(1);
-            }
-        };
-
-        // Read in the marisa file data.
-        let mut data = Vec::new();
-        let mut marisa_file = std::fs::File::open(&marisa_path).unwrap();
-        marisa_file.read_to_end(&mut data).unwrap();
-
-        data
-    };
-
     //----------------------------------------------------------------
     // Duplicate the entries into a prefix list.
 
-    // prefix -> Vec<(key, definition text, priority)>
-    let mut prefix_entries: HashMap<String, Vec<(String, String, u32)>> = HashMap::new();
+    // prefix -> Vec<(key, definition texts, priority)>
+    //
+    // The definitions are borrowed from the entries rather than copied,
+    // since an entry is duplicated for every one of its keys, and most
+    // entries have many keys.
+    //
+    // A BTreeMap, so that the prefix files are always written in the same
+    // order, and identical builds produce identical files.
+    let mut prefix_entries: BTreeMap<String, Vec<PrefixEntry>> = BTreeMap::new();
 
     for entry in entries.iter() {
         for key in entry.keys.iter() {
-            let prefix = dictionary_prefix(&key.0);
+            let key_text = normalize_key(&key.0, &settings.lang);
+            let prefix = dictionary_prefix(&key_text);
 
-            let a = prefix_entries.entry(prefix).or_insert(Vec::new());
-            a.push((key.0.clone(), entry.definition.clone(), key.1));
+            let a = prefix_entries.entry(prefix).or_default();
+            a.push((key_text, vec![entry.definition.as_str()], key.1));
         }
     }
 
     for entries in prefix_entries.values_mut() {
         // Sort by key, and then within key by priority, to prep for the
-        // merging below.
-        entries.sort_by_key(|a| (a.0.clone(), a.2));
+        // merging below.  Ties are broken by definition text, so that the
+        // output doesn't depend on the input order (which --reuse_from
+        // relies on).
+        entries.sort_by(|a, b| (&a.0, a.2, &a.1).cmp(&(&b.0, b.2, &b.1)));
 
         // Merge entries with the same key, so that Kobo e-readers show all
         // matches (their software is weird, and often omits duplicate exact
-        // matches for some reason).
+        // matches for some reason).  Definitions identical to one already
+        // merged into the same key are dropped, since the same entry is
+        // often reachable through several of its keys.
+        let mut seen_definitions = HashSet::new(); // Hashes of the current key's definitions.
         let mut i = 0;
         while i < entries.len() {
+            let hash = definition_hash(entries[i].1[0]);
             if i > 0 && entries[i].0 == entries[i - 1].0 {
                 let entry = entries.remove(i);
-                entries[i - 1].1.push_str(&entry.1);
+                if seen_definitions.insert(hash) {
+                    entries[i - 1].1.extend(entry.1);
+                }
                 entries[i - 1].2 = entries[i - 1].2.min(entry.2);
             } else {
+                seen_definitions.clear();
+                seen_definitions.insert(hash);
                 i += 1;
             }
         }
@@ -135,67 +210,463 @@ pub fn write_dictionary(entries: &[Entry], output_path: &Path) -> std::io::Resul
         // Sort by priority, and then by inverse entry length, so
         // higher-priority and more detailed entries hopefully show
         // up first.
-        entries.sort_by_key(|a| (a.2, -(a.1.len() as isize)));
+        let len =
+            |definitions: &[&str]| definitions.iter().map(|d| d.len()).sum::<usize>() as isize;
+        entries.sort_by(|a, b| (a.2, -len(&a.1), &a.0).cmp(&(b.2, -len(&b.1), &b.0)));
+    }
+
+    // Trim the lowest-priority entries from prefixes that exceed the size
+    // limit.  The trimmed keys are also removed from the words index, so
+    // that the Kobo doesn't find keys without entries.
+    let mut trimmed_prefixes = Vec::new(); // (prefix, trimmed count, total count)
+    if let Some(max_size) = settings.max_prefix_size {
+        let mut trimmed_keys = HashSet::new();
+        for (prefix, entries) in prefix_entries.iter_mut() {
+            let mut size = HTML_HEADER.len() + HTML_FOOTER.len();
+            let mut keep = entries.len();
+            for (i, (key, definition, _)) in entries.iter().enumerate() {
+                size += entry_html_size(key, definition);
+                if size > max_size && i > 0 {
+                    keep = i;
+                    break;
+                }
+            }
+            if keep < entries.len() {
+                trimmed_prefixes.push((prefix.clone(), entries.len() - keep, entries.len()));
+                trimmed_keys.extend(entries.drain(keep..).map(|e| e.0));
+            }
+        }
+        all_keys.retain(|key| !trimmed_keys.contains(&key.0));
+        trimmed_prefixes.sort();
     }
 
+    //----------------------------------------------------------------
+    // Create the `words` and `words.original` data.
+
+    // Words as a new-line-separated text list.
+    let words_original = {
+        let mut words_original = String::new();
+        for key in all_keys.iter() {
+            words_original.push_str(&format!("{}\t{}\n", key.0, key.1));
+        }
+        words_original
+    };
+
+    // Create the marisa tree words data.
+    let words = if settings.use_marisa_build {
+        build_words_with_marisa_build(&words_original, settings)?
+    } else {
+        crate::marisa::build(
+            all_keys
+                .iter()
+                .map(|(key, weight)| (key.as_bytes(), *weight as f32)),
+        )
+    };
+
+    let mut recompressed_count = 0;
+
     //----------------------------------------------------------------
     // Write the Kobo dictionary file.
 
     // Open the output zip archive.
-    let mut zip_out = zip::ZipWriter::new(BufWriter::new(std::fs::File::create(output_path)?));
+    let mut zip_out = zip::ZipWriter::new(out);
+
+    // All files get the same fixed timestamp, so that builds are
+    // reproducible.  (The gzip headers of the prefix files don't have one.)
+    let file_options =
+        zip::write::FileOptions::default().last_modified_time(zip::DateTime::default());
 
     // Write the words and words.original files.
-    zip_out
-        .start_file("words", zip::write::FileOptions::default())
-        .unwrap();
+    zip_out.start_file("words", file_options).unwrap();
     zip_out.write_all(&words).unwrap();
-    zip_out
-        .start_file("words.original", zip::write::FileOptions::default())
-        .unwrap();
+    zip_out.start_file("words.original", file_options).unwrap();
     zip_out.write_all(words_original.as_bytes()).unwrap();
 
-    // Write all of the prefix entry files.
-    for (prefix, prefix_entry_list) in prefix_entries.iter() {
-        // Generate the html.
-        let mut html = String::new();
-        html.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?><html>");
-        for (key, definition, _) in prefix_entry_list.iter() {
-            html.push_str(&format!(
-                "<w><p><a name=\"{}\" />{}</p></w>",
-                key, definition
-            ));
-        }
-        html.push_str("</html>");
-
-        // Compress with gzip.
-        let mut gzhtml = Vec::new();
-        let mut gz = GzEncoder::new(html.as_bytes(), flate2::Compression::fast());
-        gz.read_to_end(&mut gzhtml).unwrap();
+    // Generate and compress the prefix files in parallel.  Files that are
+    // unchanged from the reused dictionary are `None`, and reuse its
+    // compressed file.
+    let gzhtml_files: Vec<Option<Vec<u8>>> = prefix_entries
+        .par_iter()
+        .map(|(prefix, prefix_entry_list)| {
+            // Generate the html.
+            let mut html = String::new();
+            html.push_str(HTML_HEADER);
+            for (key, definitions, _) in prefix_entry_list.iter() {
+                html.push_str(&format!(
+                    "<w><p><a name=\"{}\" />",
+                    crate::html::escape_attr(key)
+                ));
+                for definition in definitions.iter() {
+                    html.push_str(definition);
+                }
+                html.push_str("</p></w>");
+            }
+            html.push_str(HTML_FOOTER);
+
+            // Reuse the compressed prefix file from the reused dictionary
+            // if it's unchanged, and otherwise compress with gzip.
+            match base_prefix_files.get(prefix) {
+                Some(base_gzhtml) if gunzip_equals(base_gzhtml, html.as_bytes()) => None,
+                _ => {
+                    let mut gzhtml = Vec::new();
+                    let mut gz = GzEncoder::new(
+                        html.as_bytes(),
+                        flate2::Compression::new(settings.compression),
+                    );
+                    gz.read_to_end(&mut gzhtml).unwrap();
+                    Some(gzhtml)
+                }
+            }
+        })
+        .collect();
+
+    // Write all of the prefix entry files, in order.  They're already
+    // gzipped, so compressing them again in the zip would just waste time.
+    let prefix_file_options = file_options.compression_method(zip::CompressionMethod::Stored);
+    for (prefix, gzhtml) in prefix_entries.keys().zip(gzhtml_files) {
+        let base_gzhtml = base_prefix_files.remove(prefix);
+        let gzhtml = match gzhtml {
+            Some(gzhtml) => {
+                recompressed_count += 1;
+                gzhtml
+            }
+            None => base_gzhtml.unwrap(),
+        };
 
         // Write the file to the zip file.
         zip_out
-            .start_file(
-                &format!("{}.html", prefix),
-                zip::write::FileOptions::default(),
-            )
+            .start_file(format!("{}.html", prefix), prefix_file_options)
             .unwrap();
         zip_out.write_all(&gzhtml).unwrap();
     }
 
-    zip_out.finish().unwrap();
+    let out = zip_out.finish().unwrap();
 
-    Ok(())
+    if settings.reuse_from.is_some() {
+        info!(
+            "Recompressed {} of {} prefix files, and reused the rest ({} removed).",
+            recompressed_count,
+            prefix_entries.len(),
+            base_prefix_files.len()
+        );
+    }
+
+    if !trimmed_prefixes.is_empty() {
+        let list: String = trimmed_prefixes
+            .iter()
+            .map(|(prefix, trimmed, total)| {
+                format!("\n    {}: {} of {} entries removed", prefix, trimmed, total)
+            })
+            .collect();
+        warning!(
+            "{} prefix files exceeded the maximum size, and had their lowest-priority entries removed:{}",
+            trimmed_prefixes.len(),
+            list
+        );
+    }
+
+    Ok(out)
+}
+
+/// A writer that only keeps track of how much would have been written.
+#[derive(Default)]
+struct SizeCounter {
+    pos: u64,
+    len: u64,
+}
+
+impl Write for SizeCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pos += buf.len() as u64;
+        self.len = self.len.max(self.pos);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SizeCounter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        match new_pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            )),
+        }
+    }
 }
 
-fn dictionary_prefix(key: &str) -> String {
-    // See: https://pgaskin.net/dictutil/dicthtml/prefixes.html, which covers
-    // the non-Japanese parts of this.
+/// Checks that a written Kobo dictionary file is readable and consistent,
+/// returning a list of the problems found.
+///
+/// This checks that all zip members can be read, that all html files
+/// un-gzip cleanly, that the `words` trie decodes and matches
+/// `words.original`, and that every key in the trie has an entry in the
+/// prefix file it belongs to.
+pub fn verify_dictionary(path: &Path) -> std::io::Result<Vec<String>> {
+    let mut problems = Vec::new();
+    let mut zip_in = crate::archive::open(path)?;
+
+    let mut words = None;
+    let mut words_original = None;
+    let mut prefix_keys: HashMap<String, HashSet<String>> = HashMap::new();
+    for i in 0..zip_in.len() {
+        let mut f = zip_in.by_index(i)?;
+        let filename = crate::archive::member_name(f.name_raw());
+        let mut data = Vec::new();
+        if let Err(e) = f.read_to_end(&mut data) {
+            problems.push(format!("\"{}\" can't be read: {}", filename, e));
+            continue;
+        }
+
+        if filename == "words" {
+            words = Some(data);
+        } else if filename == "words.original" {
+            words_original = Some(String::from_utf8_lossy(&data).into_owned());
+        } else if let Some(prefix) = filename.strip_suffix(".html") {
+            let mut html = String::new();
+            if flate2::read::GzDecoder::new(&data[..])
+                .read_to_string(&mut html)
+                .is_err()
+            {
+                problems.push(format!("\"{}\" isn't valid gzipped html", filename));
+                continue;
+            }
+            let keys = prefix_keys.entry(prefix.into()).or_default();
+            for entry in crate::kobo_ja::parse_html(&html) {
+                keys.insert(entry.key);
+                keys.extend(entry.variants);
+            }
+        }
+    }
+
+    // The words trie.
+    let keys = match words {
+        None => {
+            problems.push("missing \"words\" file".into());
+            Vec::new()
+        }
+        Some(data) => match crate::marisa::read_keys(&data) {
+            Ok(keys) => keys,
+            Err(e) => {
+                problems.push(format!("\"words\" trie doesn't decode: {}", e));
+                Vec::new()
+            }
+        },
+    };
+    let keys: Vec<String> = keys
+        .iter()
+        .map(|k| String::from_utf8_lossy(k).into_owned())
+        .collect();
+
+    if let Some(text) = words_original {
+        let mut original: Vec<&str> = text
+            .lines()
+            .filter_map(|line| line.split('\t').next())
+            .collect();
+        let mut sorted_keys: Vec<&str> = keys.iter().map(|k| k.as_str()).collect();
+        original.sort_unstable();
+        sorted_keys.sort_unstable();
+        if !keys.is_empty() && original != sorted_keys {
+            problems.push(format!(
+                "\"words\" trie has {} keys, which don't match the {} keys of \"words.original\"",
+                sorted_keys.len(),
+                original.len()
+            ));
+        }
+    }
 
-    // TODO: this totally punts on combining characters at the moment, and
-    // therefore won't necessarily be correct for e.g. accented characters,
-    // hangul, etc.  For Japanese, however, this should be fine.
+    // Every key should have an entry in its prefix file.
+    for key in keys.iter() {
+        let prefix = dictionary_prefix(key);
+        match prefix_keys.get(&prefix) {
+            Some(prefix_keys) if prefix_keys.contains(key) => {}
+            Some(_) => problems.push(format!(
+                "key \"{}\" has no entry in \"{}.html\"",
+                key, prefix
+            )),
+            None => problems.push(format!(
+                "key \"{}\" belongs in \"{}.html\", which doesn't exist",
+                key, prefix
+            )),
+        }
+    }
 
-    let prefix: Vec<_> = key.to_lowercase().trim().chars().take(2).collect();
+    Ok(problems)
+}
+
+/// Returns whether the gzipped data decompresses to exactly `data`.
+fn gunzip_equals(gzdata: &[u8], data: &[u8]) -> bool {
+    let mut decompressed = Vec::with_capacity(data.len());
+    match flate2::read::GzDecoder::new(gzdata).read_to_end(&mut decompressed) {
+        Ok(_) => decompressed == data,
+        Err(_) => false,
+    }
+}
+
+fn definition_hash(definition: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    definition.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The size in bytes of an entry in the prefix html.
+fn entry_html_size(key: &str, definitions: &[&str]) -> usize {
+    "<w><p><a name=\"\" /></p></w>".len()
+        + crate::html::escape_attr(key).len()
+        + definitions.iter().map(|d| d.len()).sum::<usize>()
+}
+
+/// Checks that the `marisa-build` executable can be run, so that a
+/// missing one is reported before any of the slow work of building a
+/// dictionary rather than after.  On failure, the error explains how to
+/// install it.
+pub fn check_marisa_build() -> Result<(), Error> {
+    // marisa-build has no option to print its version, but `--help` runs
+    // it without doing anything.
+    let reason = match std::process::Command::new("marisa-build")
+        .arg("--help")
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+    {
+        Ok(status) if status.success() => return Ok(()),
+        Ok(status) => format!("\"marisa-build --help\" failed with {}", status),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            "it wasn't found in your PATH".to_string()
+        }
+        Err(e) => e.to_string(),
+    };
+    let hint = if cfg!(windows) {
+        "Build it from https://github.com/s-yata/marisa-trie (e.g. under Cygwin or MSYS2), and make sure the directory containing marisa-build.exe is in your PATH."
+    } else if cfg!(target_os = "macos") {
+        "Build it from https://github.com/s-yata/marisa-trie, and make sure it's installed somewhere in your PATH."
+    } else {
+        "Install your distribution's marisa package (e.g. \"apt install marisa\" on Debian and Ubuntu), or build it from https://github.com/s-yata/marisa-trie."
+    };
+    Err(Error::usage(format!(
+        "\"marisa-build\" couldn't be run ({}), but is needed to build the Kobo word index.  {}  Or pass --builtin_trie to use the built-in trie builder instead.",
+        reason, hint
+    )))
+}
+
+/// Builds the marisa trie words data by running the external
+/// `marisa-build` executable on the words list.
+fn build_words_with_marisa_build(
+    words_original: &str,
+    settings: &WriteSettings,
+) -> Result<Vec<u8>, Error> {
+    // Write words to a temporary file.
+    let temp_dir = settings.temp_dir.clone().unwrap_or_else(std::env::temp_dir);
+    let mut words_file = tempfile::Builder::new()
+        .prefix("kobo_jp_dict-words-")
+        .suffix(".txt")
+        .tempfile_in(&temp_dir)?;
+    words_file
+        .as_file_mut()
+        .write_all(words_original.as_bytes())?;
+    words_file.as_file_mut().sync_all()?;
+
+    // With `keep_temp` the files are left in place, even if marisa-build
+    // fails.  Otherwise the words file is deleted when `_words_guard` is
+    // dropped.
+    let (words_path, _words_guard) = if settings.keep_temp {
+        let path = words_file.into_temp_path().keep().map_err(|e| e.error)?;
+        (path, None)
+    } else {
+        let temp_path = words_file.into_temp_path();
+        (temp_path.to_path_buf(), Some(temp_path))
+    };
+    let marisa_path = words_path.with_extension("marisa");
+    if settings.keep_temp {
+        info!(
+            "Keeping the temporary files {} and {}.",
+            words_path.display(),
+            marisa_path.display()
+        );
+    }
+
+    // Run marisa-build to create the marisa trie data.
+    match std::process::Command::new("marisa-build")
+        .arg("-o")
+        .arg(marisa_path.as_os_str())
+        .arg(words_path.as_os_str())
+        .output()
+    {
+        Ok(output) => {
+            if !output.status.success() {
+                return Err(Error::failed(format!(
+                    "\"marisa-build\" exited with a failure:\n{}",
+                    String::from_utf8_lossy(&output.stderr)
+                )));
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(Error::failed(format!("attempt to run \"marisa-build\" failed: {}\nMake sure you have marisa-build installed and in your path, and that you have the permissions needed to run it.", e)));
+        }
+        Err(e) => {
+            return Err(Error::failed(format!(
+                "attempt to run \"marisa-build\" failed: {}",
+                e
+            )));
+        }
+    };
+
+    // Read in the marisa file data.
+    let data = std::fs::read(&marisa_path)?;
+    if !settings.keep_temp {
+        let _ = std::fs::remove_file(&marisa_path);
+    }
+
+    Ok(data)
+}
+
+/// Normalizes a key for use in the words index and entry anchors.
+///
+/// Keys are NFKC normalized, so that e.g. full-width latin letters and
+/// half-width katakana match their ordinary forms.  Kobo e-readers also
+/// lowercase words before looking them up, so for most languages the keys
+/// need to be lowercase as well to be findable.  Japanese keys aren't
+/// lowercased, since they're already generated in the forms the Kobo looks
+/// up.
+pub fn normalize_key(key: &str, lang: &str) -> String {
+    let key: String = key.trim().nfkc().collect();
+    if lang == "ja" {
+        key
+    } else {
+        key.to_lowercase()
+    }
+}
+
+/// Computes the name of the prefix file that an entry with the given key
+/// belongs in.  The key should already be normalized with
+/// `normalize_key()`.
+///
+/// See: https://pgaskin.net/dictutil/dicthtml/prefixes.html, which covers
+/// the non-Japanese parts of this.
+pub fn dictionary_prefix(key: &str) -> String {
+    // Any combining marks that are left over after normalization are
+    // stripped, since they don't count towards the prefix length and aren't
+    // letters themselves.
+    let prefix: Vec<char> = key
+        .trim()
+        .to_lowercase()
+        .chars()
+        .filter(|c| !c.is_mark())
+        .take(2)
+        .collect();
 
     if prefix.is_empty() {
         return "11".into();
@@ -204,15 +675,15 @@ fn dictionary_prefix(key: &str) -> String {
     let ch = prefix[0] as u32;
 
     // Cyrillic and Japanese kana.
-    if (ch >= 0x0400 && ch <= 0x052f)
-        || (ch >= 0x2de0 && ch <= 0x2dff)
-        || (ch >= 0xa640 && ch <= 0xa69f)
-        || (ch >= 0x3040 && ch <= 0x30ff)
+    if (0x0400..=0x052f).contains(&ch)
+        || (0x2de0..=0x2dff).contains(&ch)
+        || (0xa640..=0xa69f).contains(&ch)
+        || (0x3040..=0x30ff).contains(&ch)
     {
         prefix.iter().collect()
     }
     // Basic Unicode plane Japanese Kanji / Chinese characters.
-    else if (ch >= 0x3400 && ch <= 0x4dbf) || (ch >= 0x4e00 && ch <= 0x9fff) {
+    else if (0x3400..=0x4dbf).contains(&ch) || (0x4e00..=0x9fff).contains(&ch) {
         prefix.iter().take(1).collect()
     }
     // Unicode letter class.
@@ -225,8 +696,58 @@ fn dictionary_prefix(key: &str) -> String {
             "11".into()
         }
     }
-    // For now, punt on everything else.
+    // Everything else (digits, punctuation, symbols, etc.).
     else {
         "11".into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The prefix of a key after normalizing it, as when building.
+    fn prefix(key: &str) -> String {
+        dictionary_prefix(&normalize_key(key, "xx"))
+    }
+
+    #[test]
+    fn kana_pair() {
+        assert_eq!(prefix("たべる"), "たべ");
+        assert_eq!(prefix("カメラ"), "カメ");
+        assert_eq!(prefix("あ"), "あ");
+    }
+
+    #[test]
+    fn single_kanji() {
+        assert_eq!(prefix("食べる"), "食");
+        assert_eq!(prefix("日本"), "日");
+    }
+
+    #[test]
+    fn latin_letters() {
+        assert_eq!(prefix("Apple"), "ap");
+        assert_eq!(prefix("x"), "xa");
+    }
+
+    #[test]
+    fn letter_then_non_letter() {
+        assert_eq!(prefix("a1"), "11");
+        assert_eq!(prefix("a-b"), "11");
+        assert_eq!(prefix("1a"), "11");
+    }
+
+    #[test]
+    fn accented_latin_letters() {
+        assert_eq!(prefix("été"), "ét");
+        assert_eq!(prefix("Ü"), "üa");
+        // Decomposed forms go in the same file as precomposed ones.
+        assert_eq!(prefix("e\u{301}te\u{301}"), "ét");
+    }
+
+    #[test]
+    fn empty_key() {
+        assert_eq!(prefix(""), "11");
+        assert_eq!(prefix("  "), "11");
+    }
+}
@@ -0,0 +1,284 @@
+//! Parses existing Kobo dicthtml .zip dictionaries, such as the official
+//! Japanese dictionary that ships with Kobo e-readers.
+//!
+//! See https://pgaskin.net/dictutil/dicthtml/ for details of the format.
+//! Each html file in the archive is a gzipped list of `<w>` elements, each
+//! containing an `<a name="...">` anchor with the entry's key, optional
+//! `<var><variant name="..."/></var>` alternate keys, and the entry's
+//! html contents.
+
+use std::io::prelude::*;
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+
+use crate::archive;
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub key: String,
+    pub variants: Vec<String>,
+    pub definition: String,
+}
+
+/// Parses all entries from the dicthtml file at `path`.
+pub fn parse(path: &Path) -> std::io::Result<Vec<Entry>> {
+    Ok(parse_prefixes(path)?
+        .drain(..)
+        .flat_map(|(_, entries)| entries)
+        .collect())
+}
+
+/// Parses all entries from the dicthtml file at `path`, grouped by the
+/// prefix file they're in.  The prefixes are in archive order.
+pub fn parse_prefixes(path: &Path) -> std::io::Result<Vec<(String, Vec<Entry>)>> {
+    let mut zip_in = archive::open(path)?;
+
+    let mut prefixes = Vec::new();
+    for i in 0..zip_in.len() {
+        let mut f = zip_in.by_index(i)?;
+        let filename = archive::member_name(f.name_raw());
+        if !filename.ends_with(".html") {
+            continue;
+        }
+
+        let mut data = Vec::new();
+        f.read_to_end(&mut data)?;
+        let html = decode_html(&data).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "\"{}\" in {} is neither plain nor gzipped html (the dictionary may be encrypted)",
+                    filename,
+                    path.display()
+                ),
+            )
+        })?;
+
+        let prefix = filename[..(filename.len() - ".html".len())].to_string();
+        prefixes.push((prefix, parse_html(&html)));
+    }
+
+    Ok(prefixes)
+}
+
+/// Reads the keys of the dicthtml file's `words.original` list, if it has
+/// one.  Note that official Kobo dictionaries usually don't.
+pub fn parse_words_original(path: &Path) -> std::io::Result<Option<Vec<String>>> {
+    let mut zip_in = archive::open(path)?;
+    let mut f = match archive::find_member(&mut zip_in, "words.original")? {
+        Some(i) => zip_in.by_index(i)?,
+        None => return Ok(None),
+    };
+
+    let mut text = String::new();
+    f.read_to_string(&mut text)?;
+    Ok(Some(
+        text.lines()
+            .filter_map(|line| line.split('\t').next())
+            .filter(|key| !key.is_empty())
+            .map(|key| key.into())
+            .collect(),
+    ))
+}
+
+/// The result of looking up a word the way a Kobo e-reader does.
+#[derive(Clone, Debug)]
+pub struct Lookup {
+    /// The word as it's looked up, after normalization.
+    pub key: String,
+
+    /// Whether the key is in the dictionary's `words` index.  The Kobo
+    /// only shows entries for keys that are.
+    pub in_index: bool,
+
+    /// The prefix file that the key's entries are in.
+    pub prefix: String,
+
+    /// The entries of the prefix file with the key, either as their key or
+    /// as a variant, in file order.
+    pub entries: Vec<Entry>,
+
+    /// If the key isn't in the index, the keys that start the same way it
+    /// does for the most characters (at most 10, shortest first).
+    pub similar_keys: Vec<String>,
+}
+
+/// Looks up a word in the dicthtml file at `path` like a Kobo e-reader
+/// does: the normalized word is looked up in the `words` trie, and if it's
+/// there, its entries are read from the prefix file for it.
+pub fn lookup(path: &Path, word: &str, lang: &str) -> std::io::Result<Lookup> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+
+    let mut zip_in = archive::open(path)?;
+    let names = archive::member_names(&mut zip_in)?;
+
+    // Look up the key in the words index.
+    let key = crate::kobo::normalize_key(word, lang);
+    let keys: Vec<String> = {
+        let index = names
+            .iter()
+            .position(|name| name == "words")
+            .ok_or_else(|| invalid("the dictionary has no \"words\" index".into()))?;
+        let mut data = Vec::new();
+        zip_in.by_index(index)?.read_to_end(&mut data)?;
+        crate::marisa::read_keys(&data)
+            .map_err(|e| invalid(format!("the \"words\" index doesn't decode: {}", e)))?
+            .iter()
+            .map(|k| String::from_utf8_lossy(k).into_owned())
+            .collect()
+    };
+    let in_index = keys.contains(&key);
+
+    // Find the closest keys if it's not there.
+    let mut similar_keys = Vec::new();
+    if !in_index {
+        let common_len = |k: &String| {
+            k.chars()
+                .zip(key.chars())
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        let best = keys.iter().map(common_len).max().unwrap_or(0);
+        if best > 0 {
+            similar_keys = keys
+                .iter()
+                .filter(|k| common_len(k) == best)
+                .cloned()
+                .collect();
+            similar_keys.sort_by(|a, b| a.chars().count().cmp(&b.chars().count()).then(a.cmp(b)));
+            similar_keys.truncate(10);
+        }
+    }
+
+    // Read its entries from the prefix file.
+    let prefix = crate::kobo::dictionary_prefix(&key);
+    let mut entries = Vec::new();
+    if in_index {
+        let filename = format!("{}.html", prefix);
+        if let Some(index) = names.iter().position(|name| *name == filename) {
+            let mut data = Vec::new();
+            zip_in.by_index(index)?.read_to_end(&mut data)?;
+            let html = decode_html(&data)
+                .ok_or_else(|| invalid(format!("\"{}\" isn't valid html", filename)))?;
+            entries = parse_html(&html)
+                .drain(..)
+                .filter(|entry| entry.key == key || entry.variants.contains(&key))
+                .collect();
+        }
+    }
+
+    Ok(Lookup {
+        key,
+        in_index,
+        prefix,
+        entries,
+        similar_keys,
+    })
+}
+
+/// Decodes the (usually gzipped) contents of a dicthtml prefix file.
+pub fn decode_html(data: &[u8]) -> Option<String> {
+    let mut text = Vec::new();
+    if GzDecoder::new(data).read_to_end(&mut text).is_err() {
+        text.clear();
+        text.extend_from_slice(data);
+    }
+    let text = String::from_utf8(text).ok()?;
+    if text.trim_start().starts_with('<') {
+        Some(text)
+    } else {
+        None
+    }
+}
+
+/// Parses the entries out of the html of a single prefix file.
+pub fn parse_html(html: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+
+    let mut rest = html;
+    while let Some(start) = rest.find("<w>") {
+        let body_start = start + "<w>".len();
+        let body_end = match rest[body_start..].find("</w>") {
+            Some(i) => body_start + i,
+            None => break,
+        };
+        let body = &rest[body_start..body_end];
+        rest = &rest[(body_end + "</w>".len())..];
+
+        // Key.
+        let (key, anchor_range) = match find_tag_attr(body, "<a ", "name") {
+            Some(a) => a,
+            None => continue,
+        };
+
+        // Variants.
+        let mut variants = Vec::new();
+        let mut definition = String::new();
+        definition.push_str(&body[..anchor_range.0]);
+        let mut after_anchor = &body[anchor_range.1..];
+        if let Some(var_start) = after_anchor.find("<var>") {
+            if let Some(var_len) = after_anchor[var_start..].find("</var>") {
+                let var_end = var_start + var_len + "</var>".len();
+                let mut var_text = &after_anchor[var_start..var_end];
+                while let Some((variant, range)) = find_tag_attr(var_text, "<variant ", "name") {
+                    variants.push(variant);
+                    var_text = &var_text[range.1..];
+                }
+                definition.push_str(&after_anchor[..var_start]);
+                after_anchor = &after_anchor[var_end..];
+            }
+        }
+        definition.push_str(after_anchor);
+
+        // Our own generated dictionaries wrap each entry in a `<p>`, which
+        // is left empty-headed once the anchor is removed.
+        let definition = definition.trim();
+        let definition = match definition.strip_prefix("<p>") {
+            Some(d) if d.ends_with("</p>") => &d[..(d.len() - "</p>".len())],
+            _ => definition,
+        };
+
+        entries.push(Entry {
+            key,
+            variants,
+            definition: definition.trim().into(),
+        });
+    }
+
+    entries
+}
+
+/// Finds the first tag starting with `tag_start`, and returns the
+/// (unescaped) value of its `attr` attribute along with the byte range of
+/// the whole tag.
+fn find_tag_attr(text: &str, tag_start: &str, attr: &str) -> Option<(String, (usize, usize))> {
+    let start = text.find(tag_start)?;
+    let end = start + text[start..].find('>')? + 1;
+    let tag = &text[start..end];
+
+    let attr_start = tag.find(&format!("{}=", attr))? + attr.len() + 1;
+    let quote = tag[attr_start..].chars().next()?;
+    let value = if quote == '"' || quote == '\'' {
+        let value_start = attr_start + 1;
+        let value_end = value_start + tag[value_start..].find(quote)?;
+        &tag[value_start..value_end]
+    } else {
+        let value_end = attr_start
+            + tag[attr_start..]
+                .find(|c: char| c.is_whitespace() || c == '/' || c == '>')
+                .unwrap_or(tag.len() - attr_start);
+        &tag[attr_start..value_end]
+    };
+
+    Some((unescape(value), (start, end)))
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
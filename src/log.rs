@@ -0,0 +1,100 @@
+//! Logging of the progress and problems of a build, controlled by the
+//! --quiet, --verbose, and --log_json flags.
+//!
+//! Messages are logged with the macros `error!`, `warning!`, `info!`,
+//! `verbose!`, and `debug!`, in decreasing order of importance.  By
+//! default everything up to `info!` is printed, as plain text: errors to
+//! stderr with an "Error: " prefix, warnings to stdout with a "Warning: "
+//! prefix, and the rest to stdout as-is.  With --log_json, each message is
+//! instead printed to stderr as a line of json, e.g.
+//! `{"level":"warning","message":"..."}`.
+
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Level {
+    Error = 0,
+    Warning = 1,
+    Info = 2,
+    Verbose = 3, // -v: details of what's being done, e.g. skipped rows.
+    Debug = 4,   // -vv: decisions made for individual entries.
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Info => "info",
+            Level::Verbose => "verbose",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Sets the most detailed level of messages that are printed, and whether
+/// they're printed as json.
+pub fn init(max_level: Level, json: bool) {
+    MAX_LEVEL.store(max_level as u8, Ordering::Relaxed);
+    JSON.store(json, Ordering::Relaxed);
+}
+
+/// Whether messages of the given level are printed.  This is for avoiding
+/// work that's only needed for detailed messages.
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn log(level: Level, message: std::fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    if JSON.load(Ordering::Relaxed) {
+        eprintln!(
+            "{}",
+            serde_json::json!({
+                "level": level.name(),
+                "message": message.to_string(),
+            })
+        );
+        return;
+    }
+    match level {
+        Level::Error => eprintln!("Error: {}", message),
+        Level::Warning => println!("Warning: {}", message),
+        _ => println!("{}", message),
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Error, format_args!($($arg)*))
+    };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Warning, format_args!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Info, format_args!($($arg)*))
+    };
+}
+
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Verbose, format_args!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Debug, format_args!($($arg)*))
+    };
+}
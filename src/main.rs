@@ -3,66 +3,799 @@
 #[macro_use]
 extern crate lazy_static;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
-use std::io::BufReader;
 
 use flate2::read::GzDecoder;
+use rayon::prelude::*;
 
+#[macro_use]
+mod log;
+
+mod archive;
+mod cache;
+mod config;
+mod conjugation;
+mod counters;
+mod csv;
+mod error;
+mod fetch;
+mod filter;
+mod html;
+mod inflection;
+mod input;
+mod install;
 mod jmdict;
 mod kobo;
+mod kobo_ja;
+mod marisa;
+mod output;
+mod project;
+mod style;
 mod yomichan;
+mod zim;
 
-use jmdict::{ConjugationClass, PartOfSpeech, WordEntry};
+use config::Config;
+use error::{Context, Error};
+use inflection::InflectionRules;
+use input::InputSource;
+use jmdict::{ConjugationClass, PartOfSpeech, Sense, WordEntry};
+use style::Styles;
 
-fn main() -> io::Result<()> {
-    let matches = clap::Command::new("Kobo Japanese Dictionary Builder")
+fn main() {
+    let command = clap::Command::new("Kobo Japanese Dictionary Builder")
         .version(clap::crate_version!())
+        .subcommand_required(true)
+        .arg_required_else_help(true)
         .arg(
-            clap::Arg::new("OUTPUT")
-                .help("The output filepath to write the new dictionary to.")
-                .required(true)
-                .index(1),
+            clap::Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .help("Print more details of what's being done, such as skipped rows and how entries are matched.  Give twice (-vv) for a message about every unmatched entry.")
+                .multiple_occurrences(true)
+                .global(true),
         )
         .arg(
-            clap::Arg::new("pitch_accent")
-                .short('p')
-                .long("pitch_accent")
-                .help("Path to a custom pitch accent file in .tsv format.  Will be used instead of the bundled pitch accent data.")
-                .value_name("PATH")
-                .takes_value(true),
+            clap::Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .help("Only print warnings and errors.")
+                .conflicts_with("verbose")
+                .global(true),
         )
         .arg(
-            clap::Arg::new("yomichan_dict")
-                .short('y')
-                .long("yomichan")
-                .help("Path to a zipped Yomichan dictionary.  Will add either additional definitions to existing entries or completely new entries, depending the dictionary.")
-                .value_name("PATH")
-                .takes_value(true)
-                .multiple_occurrences(true),
+            clap::Arg::new("log_json")
+                .long("log_json")
+                .help("Print messages to stderr as json, one object per line with \"level\" and \"message\" fields, for use by other programs.")
+                .global(true),
         )
-        .arg(
-            clap::Arg::new("katakana_pronunciation")
-                .short('k')
-                .long("katakana")
-                .help("Use katakana instead of hiragana for word pronunciation."),
+        .subcommand(
+            clap::Command::new("build")
+                .about("Builds a new dictionary.  This is the default when no subcommand is given.")
+                .arg(
+                    clap::Arg::new("OUTPUT")
+                        .help("The output filepath to write the new dictionary to.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::new("preset")
+                        .long("preset")
+                        .help(PRESET_HELP)
+                        .value_name("PRESET")
+                        .possible_values(PRESETS.iter().map(|(name, _)| *name))
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("format")
+                        .long("format")
+                        .help("The output dictionary format.  \"kobo\" produces a Kobo dicthtml zip, and \"zim\" produces a ZIM archive for use with Kiwix.")
+                        .value_name("FORMAT")
+                        .possible_values(output::FORMATS.iter().map(|(name, _)| *name))
+                        .default_value("kobo")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("lang")
+                        .long("lang")
+                        .help("The language code of the dictionary's headwords, e.g. \"ja\" or \"de\".  For languages other than Japanese the bundled Japanese data isn't used, and the entries instead come from --csv (and --kobo_merge) files.")
+                        .value_name("CODE")
+                        .default_value("ja")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("csv")
+                        .long("csv")
+                        .help("Path to a CSV file of additional entries, with one \"word,definition\" row per entry.  Multiple lookup words for an entry can be separated with \"|\", and definitions are html.")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    clap::Arg::new("jmdict")
+                        .long("jmdict")
+                        .help("Path to a gzipped JMDict xml file, such as JMdict_e.gz from the JMDict project.  Will be used instead of the bundled (or fetched) JMDict data.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("pitch_accent")
+                        .short('p')
+                        .long("pitch_accent")
+                        .help("Path to a custom pitch accent file in .tsv format.  Will be used instead of the bundled pitch accent data.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("yomichan_dict")
+                        .short('y')
+                        .long("yomichan")
+                        .help("Path to a zipped Yomichan dictionary.  Will add either additional definitions to existing entries or completely new entries, depending the dictionary.  When multiple dictionaries have definitions for the same word, they're listed in the order given (see also --dict_order).  To only use some of a dictionary's content, append a colon and a comma-separated list of \"terms\", \"names\", and \"kanji\" to the path, e.g. \"kanjidic.zip:kanji\".")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    clap::Arg::new("names_dict")
+                        .long("names_dict")
+                        .help("Path to a zipped Yomichan dictionary of proper nouns, such as a dictionary of people's or place names.  Like with --yomichan, but its terms become separate name entries, listed after the other entries for the same word, as with JMnedict.")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    clap::Arg::new("no_names")
+                        .long("no_names")
+                        .help("Leave out name entries, such as the ones from JMnedict or --names_dict."),
+                )
+                .arg(
+                    clap::Arg::new("no_kanji")
+                        .long("no_kanji")
+                        .help("Leave out kanji entries, so that the dictionary only has word entries."),
+                )
+                .arg(
+                    clap::Arg::new("frequency")
+                        .long("frequency")
+                        .help("Path to a zipped Yomichan frequency dictionary.  Its frequency ranks are used to order entries with the same look-up key, in place of JMDict's (rather dated) priority markers.  The dictionary should list ranks (1 being the most frequent) rather than occurrence counts.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("dict_order")
+                        .long("dict_order")
+                        .help("Comma-separated list of Yomichan dictionary titles, in the order their definitions should be listed within entries, e.g. \"明鏡国語辞典,大辞林,JMdict\".  Dictionaries that aren't listed come after the listed ones, in --yomichan order.  Titles are matched case-insensitively, ignoring any parenthesized part.")
+                        .value_name("TITLES")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("dict_title")
+                        .long("dict_title")
+                        .help("Show a dictionary under a different name above its definitions, e.g. \"明鏡国語辞典=明鏡\".  The title is matched like in --dict_order (the bundled JMDict definitions are titled \"JMDict\"), and an empty name hides the dictionary's name entirely.  Can be given several times.")
+                        .value_name("TITLE=NAME")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    clap::Arg::new("merge")
+                        .long("merge")
+                        .help("How Yomichan entries are matched to JMDict entries, and thereby combined with other dictionaries' entries for the same word.  \"exact\" requires the same writing and reading.  \"okurigana\" also matches writings that only differ in their kana, e.g. 取扱い and 取り扱い.  \"reading\" additionally falls back to matching by reading alone, when only one JMDict word has that reading.")
+                        .value_name("POLICY")
+                        .possible_values(["exact", "okurigana", "reading"])
+                        .default_value("exact")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("config")
+                        .long("config")
+                        .help("Path to a json config file with settings for individual Yomichan dictionaries, such as how entry headers are stripped from their definitions.  See the readme for the format.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("embed_images")
+                        .long("embed_images")
+                        .help("Embed the images in Yomichan dictionaries' definitions into the entries.  By default they're replaced with an \"[image]\" placeholder, since they can make the dictionary much larger.")
+                )
+                .arg(
+                    clap::Arg::new("max_defs")
+                        .long("max_defs")
+                        .help("Limit each dictionary's definitions in an entry to the first N, with an ellipsis marking the rest.  To limit a single dictionary, use TITLE=N, where the title is matched like in --dict_order (the bundled JMDict definitions are titled \"JMDict\").  Can be given several times.")
+                        .value_name("[TITLE=]N")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    clap::Arg::new("max_entry_size")
+                        .long("max_entry_size")
+                        .help("Maximum size in bytes of the definitions of each word entry.  Entries that exceed this list fewer definitions from each dictionary until they fit, but always keep their header and at least the first definition from each dictionary.  Useful for encyclopedic entries that are slow to display on-device.")
+                        .value_name("BYTES")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("kobo_merge")
+                        .long("kobo_merge")
+                        .help("Path to an existing Kobo dictionary, such as the official Japanese dictionary (dicthtml-ja.zip) from a Kobo e-reader.  Its entries will be merged into the generated dictionary, so that its content isn't lost when replacing it.  By default they are listed after the generated entries.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("kobo_merge_first")
+                        .long("kobo_merge_first")
+                        .requires("kobo_merge")
+                        .help("List the entries from --kobo_merge before the generated entries instead of after."),
+                )
+                .arg(
+                    clap::Arg::new("jmdict_definitions")
+                        .long("jmdict_definitions")
+                        .help("Include the English definitions from the bundled JMDict data in word entries.  \"append\" adds them after any Yomichan definitions, and \"fallback\" only uses them for words without any Yomichan definitions.  Either way, this makes it possible to build a useful dictionary without an English Yomichan dictionary.")
+                        .value_name("MODE")
+                        .possible_values(["append", "fallback"])
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("unmatched_entries")
+                        .long("unmatched_entries")
+                        .help("Include Yomichan entries that don't match any JMDict entry as standalone entries.  By default they're left out, since without JMDict's metadata they have less accurate look-up keys and headers."),
+                )
+                .arg(
+                    clap::Arg::new("english_section")
+                        .long("english_section")
+                        .help("Move English definitions into a separate, smaller section at the end of word entries, marked 【英】.  Useful for Japanese-Japanese dictionaries with English as a fallback.  Whether a definition is English is guessed from its text, sentence by sentence, and only the English parts of mixed definitions are moved.  JMDict definitions are always English."),
+                )
+                .arg(
+                    clap::Arg::new("numbers")
+                        .long("numbers")
+                        .help("Include entries for the numbers from 1 to 9999 written in kanji, e.g. 三百."),
+                )
+                .arg(
+                    clap::Arg::new("usage_labels")
+                        .long("usage_labels")
+                        .help("Show JMDict's usage notes in entry headers, such as \"colloquial\", \"honorific\", \"usually kana\", or the dialect a word is from."),
+                )
+                .arg(
+                    clap::Arg::new("hide_rare_forms")
+                        .long("hide_rare_forms")
+                        .help("Leave rarely-used, irregular, and outdated writings and readings (as marked in JMDict) out of entry headers and look-up keys."),
+                )
+                .arg(
+                    clap::Arg::new("no_cache")
+                        .long("no_cache")
+                        .help("Don't use or write the cache of the parsed bundled JMDict data, which is normally kept in the temporary directory (see --tmpdir) to speed up later runs."),
+                )
+                .arg(data_dir_arg())
+                .arg(
+                    clap::Arg::new("tmpdir")
+                        .long("tmpdir")
+                        .help("The directory for temporary files: the JMDict cache, and the intermediate files of marisa-build.  Defaults to the system's temporary directory.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("keep_temp")
+                        .long("keep_temp")
+                        .help("Don't delete the intermediate files of marisa-build, and print where they are, so they can be inspected when something goes wrong."),
+                )
+                .arg(
+                    clap::Arg::new("skip_archaic")
+                        .long("skip_archaic")
+                        .help("Leave out JMDict words that are only used archaically, i.e. whose senses are all marked archaic or are nidan or yodan verbs or classical adjectives."),
+                )
+                .arg(
+                    clap::Arg::new("include_tags")
+                        .long("include_tags")
+                        .help("Only include words with at least one of the given comma-separated tags, e.g. \"vk,vs\".  JMDict tags can be given with or without their element, e.g. \"misc:vulg\" or \"vulg\", and the latter also matches Yomichan tags.")
+                        .value_name("TAGS")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("exclude_tags")
+                        .long("exclude_tags")
+                        .help("Leave out JMDict senses and Yomichan entries with any of the given comma-separated tags, e.g. \"vulg,arch\".  JMDict words without any senses left are left out entirely.  Tags are given the same way as for --include_tags.")
+                        .value_name("TAGS")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("exclude_pattern")
+                        .long("exclude_pattern")
+                        .help("Leave out words with a writing or reading that matches the given regular expression.")
+                        .value_name("REGEX")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("conjugation_table")
+                        .long("conjugation_table")
+                        .help("Append a compact table of basic conjugations (negative, past, te-form, potential, passive, causative, volitional, and polite) to verb and i-adjective entries."),
+                )
+                .arg(
+                    clap::Arg::new("katakana_pronunciation")
+                        .short('k')
+                        .long("katakana")
+                        .help("Use katakana instead of hiragana for word pronunciation."),
+                )
+                .arg(
+                    clap::Arg::new("use_move_terms")
+                        .short('m')
+                        .long("use_move_terms")
+                        .help("Use the terms \"other-move\" and \"self-move\" instead of \"transitive\" and \"intransitive\".  The former is more accurate to how Japanese works, but the latter are more commonly known and used."),
+                )
+                .arg(
+                    clap::Arg::new("use_japanese_terms")
+                        .short('j')
+                        .long("use_japanese_terms")
+                        .help("Use the Japanese terms for \"verb\", \"transitive\", etc. instead of English in entry headers."),
+                )
+                .arg(
+                    clap::Arg::new("style")
+                        .long("style")
+                        .help("A built-in style preset for the generated entries.  \"compact\" reduces spacing, \"large-print\" enlarges headwords, pitch accent, and definitions, and \"high-contrast\" uses bold text instead of italics.")
+                        .value_name("PRESET")
+                        .possible_values(style::PRESETS.iter().map(|(name, _)| *name))
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("pitch_graphs")
+                        .long("pitch_graphs")
+                        .help("Draw the pitch accents in entry headers as small graphs, rather than writing them as numbers."),
+                )
+                .arg(
+                    clap::Arg::new("css")
+                        .long("css")
+                        .help("Path to a css file with styles for the generated entries.  Each rule's selector should be the name of one of the styled entry elements (\"reading\", \"pitch-accent\", \"word-type\", \"sense-label\", \"definitions\", \"english\", \"conjugations\", \"kanji-heading\", \"kanji\", or \"kanji-reading\"), and its declarations are merged into that element's default inline style.  Applied after --style.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("header_template")
+                        .long("header_template")
+                        .help("Path to a template file for word entry headers.  The placeholders {reading}, {pitch_accent}, {writings}, and {word_type} are replaced with the respective parts of the header.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("inflection_rules")
+                        .long("inflection_rules")
+                        .help("Path to a json file of inflection rules, used to generate look-up keys for conjugated forms of words.  Each conjugation class in the file replaces the bundled rules for that class.  See dictionaries/inflection_rules.json for the format and the bundled rules.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("classical")
+                        .long("classical")
+                        .help("Also generate look-up keys for the conjugations of classical Japanese, such as 〜ぬ, 〜けり, and 〜たり, and for nidan verbs.  Useful for reading older literature, at the cost of a larger dictionary.  Applied before --inflection_rules."),
+                )
+                .arg(
+                    clap::Arg::new("jobs")
+                        .long("jobs")
+                        .help("The number of threads to use for the parts of the build that run in parallel.  Defaults to the number of logical CPU cores.  Fewer threads use less CPU and memory, but take longer.")
+                        .value_name("N")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("max_prefix_size")
+                        .long("max_prefix_size")
+                        .help("Maximum size in kilobytes of each of the Kobo dictionary's internal html files (before compression).  Very large files can be slow or unstable on older Kobo firmware.  Files that exceed this have their lowest-priority entries removed, and a report of what was removed is printed.")
+                        .value_name("KB")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("compression")
+                        .long("compression")
+                        .help("The gzip compression level of the Kobo dictionary's internal html files, from 0 (none) to 9 (smallest file, slowest build).")
+                        .value_name("LEVEL")
+                        .possible_values(["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"])
+                        .default_value("1")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("exclude_words")
+                        .long("exclude_words")
+                        .help("Path to a text file with one word per line.  These words are removed from the look-up keys of all entries, e.g. to keep short keys from shadowing more useful entries.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("only_words")
+                        .long("only_words")
+                        .help("Path to a text file with one word per line.  Only entries that can be looked up by at least one of these words are included (along with all of their look-up keys, so conjugated forms still work).  Useful for building small dictionaries of core vocabulary.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("reuse_from")
+                        .long("reuse_from")
+                        .help("Path to a previously built version of the Kobo dictionary.  Its compressed internal html files are reused where their contents haven't changed, which saves recompressing them.  The dictionary is otherwise still built from scratch, so this is mainly useful with high --compression levels.  May be the same as the output path.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("verify")
+                        .long("verify")
+                        .help("After writing a Kobo dictionary, re-open it and check that it's complete and readable."),
+                )
+                .arg(
+                    clap::Arg::new("force")
+                        .long("force")
+                        .help("Overwrite the output file if it already exists."),
+                )
+                .arg(
+                    clap::Arg::new("save_project")
+                        .long("save_project")
+                        .help("Save the merged entries to a project file (.kjd), from which the dictionary can be written again with --project, without parsing and merging the input dictionaries.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("project")
+                        .long("project")
+                        .help("Write the dictionary from the entries in a project file saved with --save_project, instead of from input dictionaries.  Options for the output, such as --format, --only_words, and --max_prefix_size, still apply.")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .conflicts_with_all(&[
+                            "yomichan_dict",
+                            "names_dict",
+                            "frequency",
+                            "jmdict",
+                            "pitch_accent",
+                            "csv",
+                            "kobo_merge",
+                        ]),
+                )
+                .arg(
+                    clap::Arg::new("watch")
+                        .long("watch")
+                        .help("After building the dictionary, keep watching the input files (dictionaries, config, CSS, templates, etc.) and rebuild it whenever one of them changes.  Rebuilds only recompress the parts of the Kobo dictionary that changed.  Stop with Ctrl+C."),
+                )
+                .arg(
+                    clap::Arg::new("dry_run")
+                        .long("dry_run")
+                        .help("Do all of the parsing and merging, but instead of writing the dictionary, print statistics about it: the number of entries and look-up keys, and the size the Kobo dictionary file would be."),
+                )
+                .arg(
+                    clap::Arg::new("report")
+                        .long("report")
+                        .help("Write the report of how the entries of each Yomichan dictionary matched JMDict entries to a json file.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("no_manifest")
+                        .long("no_manifest")
+                        .help("Don't write a manifest next to the output file.  By default, a json file recording the tool version, the arguments, the input files and their hashes, and the entry counts is written, e.g. \"dicthtml-ja.manifest.json\" for \"dicthtml-ja.zip\"."),
+                )
+                .arg(
+                    clap::Arg::new("builtin_trie")
+                        .long("builtin_trie")
+                        .help("Build the Kobo word index with the built-in trie builder, instead of the external \"marisa-build\" executable.  This doesn't need marisa-build to be installed, but isn't yet checked against its output."),
+                ),
         )
-        .arg(
-            clap::Arg::new("use_move_terms")
-                .short('m')
-                .long("use_move_terms")
-                .help("Use the terms \"other-move\" and \"self-move\" instead of \"transitive\" and \"intransitive\".  The former is more accurate to how Japanese works, but the latter are more commonly known and used."),
+        .subcommand(
+            clap::Command::new("fetch")
+                .about("Downloads the latest version of source data that's bundled with this tool, so that builds use it instead of the bundled copy.")
+                .arg(
+                    clap::Arg::new("SOURCE")
+                        .help("The data to download.")
+                        .possible_values(fetch::SOURCES.iter().map(|source| source.name))
+                        .required(true)
+                        .index(1),
+                )
+                .arg(data_dir_arg()),
         )
-        .arg(
-            clap::Arg::new("use_japanese_terms")
-                .short('j')
-                .long("use_japanese_terms")
-                .help("Use the Japanese terms for \"verb\", \"transitive\", etc. instead of English in entry headers."),
+        .subcommand(
+            clap::Command::new("install")
+                .about("Installs a built dictionary onto a connected Kobo e-reader.")
+                .arg(
+                    clap::Arg::new("DICTIONARY")
+                        .help("The built dictionary file to install.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::new("device")
+                        .short('d')
+                        .long("device")
+                        .help("Path to the mounted Kobo device.  If not given, connected devices are searched for automatically.")
+                        .value_name("PATH")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("name")
+                        .short('n')
+                        .long("name")
+                        .help("The filename to install the dictionary as, e.g. \"dicthtml-ja-en.zip\".  Kobo e-readers use the filename to determine the dictionary's language(s).  Defaults to the dictionary's current filename.")
+                        .value_name("NAME")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("update_locales")
+                        .long("update_locales")
+                        .help("If needed, add the dictionary's language to the \"ExtraLocales\" setting on the device.  Only relevant for older firmware without custom dictionary support."),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("inspect")
+                .about("Lists the contents of an existing Kobo dictionary file.")
+                .arg(
+                    clap::Arg::new("DICTIONARY")
+                        .help("The Kobo dictionary file to inspect, e.g. \"dicthtml-ja.zip\".")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::new("entry")
+                        .short('e')
+                        .long("entry")
+                        .help("Print the html of the entries with the given key, instead of listing the dictionary's contents.")
+                        .value_name("KEY")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::new("samples")
+                        .long("samples")
+                        .help("The number of sample keys to list for each prefix file.")
+                        .value_name("N")
+                        .default_value("3")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("verify")
+                .about("Checks that an existing Kobo dictionary file is complete and readable.")
+                .arg(
+                    clap::Arg::new("DICTIONARY")
+                        .help("The Kobo dictionary file to check, e.g. \"dicthtml-ja.zip\".")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("lookup")
+                .about("Looks up a word in an existing Kobo dictionary file the way a Kobo e-reader does, and prints the entries it would show.")
+                .arg(
+                    clap::Arg::new("DICTIONARY")
+                        .help("The Kobo dictionary file to look the word up in, e.g. \"dicthtml-ja.zip\".")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::new("WORD")
+                        .help("The word to look up.")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("validate")
+                .about("Checks a Yomichan dictionary, and reports anything in it that won't be converted.")
+                .arg(
+                    clap::Arg::new("DICTIONARY")
+                        .help("The Yomichan dictionary file to check.")
+                        .required(true)
+                        .index(1),
+                ),
         )
-        .get_matches();
+        .subcommand(
+            clap::Command::new("export")
+                .about("Converts an existing Kobo dictionary file into a Yomichan/Yomitan dictionary.")
+                .arg(
+                    clap::Arg::new("DICTIONARY")
+                        .help("The Kobo dictionary file to convert, e.g. \"dicthtml-ja.zip\".")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::new("OUTPUT")
+                        .help("The output filepath to write the Yomichan dictionary to.")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    clap::Arg::new("force")
+                        .long("force")
+                        .help("Overwrite the output file if it already exists."),
+                )
+                .arg(
+                    clap::Arg::new("title")
+                        .long("title")
+                        .help("The title of the exported dictionary.  Defaults to the Kobo dictionary's filename.")
+                        .value_name("TITLE")
+                        .takes_value(true),
+                ),
+        )
+        ;
+    let args = expand_presets(&command, default_to_build(std::env::args_os().collect()));
+    let matches = command.clone().get_matches_from(args.clone());
+    let log_level = if matches.is_present("quiet") {
+        log::Level::Warning
+    } else {
+        match matches.occurrences_of("verbose") {
+            0 => log::Level::Info,
+            1 => log::Level::Verbose,
+            _ => log::Level::Debug,
+        }
+    };
+    log::init(log_level, matches.is_present("log_json"));
+
+    let result = match matches.subcommand() {
+        Some(("build", sub_matches)) if sub_matches.is_present("watch") => {
+            watch_build(&command, args, sub_matches)
+        }
+        Some(("build", sub_matches)) => build_dictionary(sub_matches),
+        Some(("fetch", sub_matches)) => fetch_data(sub_matches),
+        Some(("install", sub_matches)) => install_dictionary(sub_matches),
+        Some(("inspect", sub_matches)) => inspect_dictionary(sub_matches),
+        Some(("verify", sub_matches)) => verify_dictionary(sub_matches),
+        Some(("validate", sub_matches)) => validate_dictionary(sub_matches),
+        Some(("lookup", sub_matches)) => lookup_word(sub_matches),
+        Some(("export", sub_matches)) => export_dictionary(sub_matches),
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+/// The options that each `--preset` stands for.
+const PRESETS: &[(&str, &[&str])] = &[
+    (
+        "monolingual-learner",
+        &[
+            "--jmdict_definitions",
+            "fallback",
+            "--english_section",
+            "--use_japanese_terms",
+        ],
+    ),
+    (
+        "beginner",
+        &[
+            "--jmdict_definitions",
+            "append",
+            "--conjugation_table",
+            "--usage_labels",
+            "--hide_rare_forms",
+            "--skip_archaic",
+        ],
+    ),
+    (
+        "minimal",
+        &[
+            "--jmdict_definitions",
+            "fallback",
+            "--no_names",
+            "--no_kanji",
+            "--skip_archaic",
+        ],
+    ),
+];
+
+const PRESET_HELP: &str = "A bundle of options for a common kind of dictionary.  \"monolingual-learner\" is for Japanese-Japanese dictionaries: --jmdict_definitions fallback --english_section --use_japanese_terms.  \"beginner\" puts the English definitions after any others and adds learning aids: --jmdict_definitions append --conjugation_table --usage_labels --hide_rare_forms --skip_archaic.  \"minimal\" is for a small dictionary of just words: --jmdict_definitions fallback --no_names --no_kanji --skip_archaic.  Options that are also given explicitly override the preset's.";
+
+/// Replaces `--preset` with the options it stands for in the command line
+/// arguments of the `build` subcommand.  The preset's options are skipped
+/// where the same option is also given explicitly, so that those override
+/// it.
+fn expand_presets(
+    command: &clap::Command,
+    mut args: Vec<std::ffi::OsString>,
+) -> Vec<std::ffi::OsString> {
+    if !matches!(args.get(1), Some(arg) if arg == "build") {
+        return args;
+    }
+
+    // Find the preset, if any.
+    let mut preset = None;
+    for (i, arg) in args.iter().enumerate() {
+        let arg = arg.to_string_lossy();
+        if arg == "--preset" {
+            preset = args
+                .get(i + 1)
+                .map(|name| name.to_string_lossy().into_owned());
+        } else if let Some(name) = arg.strip_prefix("--preset=") {
+            preset = Some(name.into());
+        }
+    }
+    let options = match PRESETS
+        .iter()
+        .find(|(name, _)| Some(*name) == preset.as_deref())
+    {
+        Some((_, options)) => options,
+        None => return args, // Clap reports invalid presets.
+    };
+
+    // Whether an option is given explicitly, in either its long or short
+    // form.
+    let build_command = command.find_subcommand("build").unwrap();
+    let is_given = |long: &str| {
+        let short = build_command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long))
+            .and_then(|arg| arg.get_short());
+        args.iter().any(|arg| {
+            let arg = arg.to_string_lossy();
+            match arg.strip_prefix("--") {
+                Some(name) => name == long || name.starts_with(&format!("{}=", long)),
+                None => {
+                    arg.starts_with('-') && matches!(short, Some(short) if arg[1..].contains(short))
+                }
+            }
+        })
+    };
+
+    let mut preset_args: Vec<std::ffi::OsString> = Vec::new();
+    let mut i = 0;
+    while i < options.len() {
+        // An option, along with its value if it has one.
+        let len = if matches!(options.get(i + 1), Some(value) if !value.starts_with("--")) {
+            2
+        } else {
+            1
+        };
+        if !is_given(&options[i][2..]) {
+            preset_args.extend(options[i..(i + len)].iter().map(|arg| arg.into()));
+        }
+        i += len;
+    }
+    args.splice(2..2, preset_args);
+    args
+}
+
+/// Inserts the `build` subcommand into the command line arguments if no
+/// subcommand is given, so that the tool can still be run the way it was
+/// before it had subcommands, e.g. `kobo_jp_dict -y dict.zip out.zip`.
+fn default_to_build(mut args: Vec<std::ffi::OsString>) -> Vec<std::ffi::OsString> {
+    const OTHER_ARGS: &[&str] = &[
+        "build",
+        "fetch",
+        "install",
+        "inspect",
+        "verify",
+        "validate",
+        "lookup",
+        "export",
+        "help",
+        "-h",
+        "--help",
+        "-V",
+        "--version",
+    ];
+    if let Some(first) = args.get(1) {
+        if !OTHER_ARGS.iter().any(|arg| first == arg) {
+            args.insert(1, "build".into());
+        }
+    }
+    args
+}
+
+/// Handles the `build` subcommand.
+fn build_dictionary(matches: &clap::ArgMatches) -> Result<(), Error> {
+    check_output_path(matches)?;
+    if let Some(path) = matches.value_of("tmpdir") {
+        if !std::path::Path::new(path).is_dir() {
+            return Err(Error::usage(format!(
+                "--tmpdir \"{}\" isn't a directory.",
+                path
+            )));
+        }
+    }
+    if let Some(jobs) = matches.value_of("jobs") {
+        match jobs.parse::<usize>() {
+            Ok(jobs) if jobs > 0 => {
+                // This fails if the thread pool was already set up by an
+                // earlier build with --watch, which is fine.
+                let _ = rayon::ThreadPoolBuilder::new()
+                    .num_threads(jobs)
+                    .build_global();
+            }
+            _ => return Err(Error::usage(format!("invalid --jobs \"{}\".", jobs))),
+        }
+    }
+    if !matches.is_present("builtin_trie") && matches.value_of("format").unwrap() == "kobo" {
+        kobo::check_marisa_build()?;
+    }
 
     let lang_mode = if matches.is_present("use_japanese_terms") {
         LangMode::Japanese
@@ -72,86 +805,353 @@ fn main() -> io::Result<()> {
         LangMode::English
     };
 
-    // Output zip archive path.
-    let output_filename = matches.value_of("OUTPUT").unwrap();
+    // Output from a project file skips straight to writing it.
+    if let Some(path) = matches.value_of("project") {
+        let project = project::Project::load(std::path::Path::new(path))?;
+        let lang = matches.value_of("lang").unwrap();
+        if project.lang != lang {
+            return Err(Error::invalid(
+                format!("project file '{}' can't be used", path),
+                format!(
+                    "it's for language \"{}\", not \"{}\".  Pass --lang {}.",
+                    project.lang, lang, project.lang
+                ),
+            ));
+        }
+        info!("    {} entries: {}", path, project.entries.len());
+        return write_output(matches, project.entries, project.dictionaries);
+    }
+
+    // Entry styling.
+    let mut styles = Styles::new();
+    if let Some(preset) = matches.value_of("style") {
+        styles.apply_preset(preset);
+    }
+    if let Some(path) = matches.value_of("css") {
+        let css = std::fs::read_to_string(path)
+            .context(|| format!("failed to read CSS file '{}'", path))?;
+        for selector in styles.apply_css(&css) {
+            warning!("unknown css selector \"{}\" in {}", selector, path);
+        }
+    }
+    if let Some(path) = matches.value_of("header_template") {
+        let template = std::fs::read_to_string(path)
+            .context(|| format!("failed to read header template '{}'", path))?;
+        styles.set_header_template(&template);
+    }
+    styles.set_pitch_accent_graphs(matches.is_present("pitch_graphs"));
+
+    // Inflection rules for look-up keys.
+    let mut inflection_rules = InflectionRules::bundled();
+    if matches.is_present("classical") {
+        inflection_rules.add(InflectionRules::classical());
+    }
+    if let Some(path) = matches.value_of("inflection_rules") {
+        let text = std::fs::read_to_string(path)
+            .context(|| format!("failed to read inflection rules '{}'", path))?;
+        match InflectionRules::from_json(&text) {
+            Ok(rules) => inflection_rules.override_with(rules),
+            Err(e) => {
+                return Err(Error::invalid(
+                    format!("inflection rules '{}' aren't valid", path),
+                    e,
+                ))
+            }
+        }
+    }
+
+    // Configuration file.
+    let config = match matches.value_of("config") {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .context(|| format!("failed to read config file '{}'", path))?;
+            match Config::from_json(&text) {
+                Ok(config) => config,
+                Err(e) => {
+                    return Err(Error::invalid(
+                        format!("config file '{}' isn't valid", path),
+                        e,
+                    ))
+                }
+            }
+        }
+        None => Config::default(),
+    };
+
+    // Entry filters.
+    let entry_filter = {
+        let tag_list = |name: &str| -> Vec<String> {
+            matches
+                .value_of(name)
+                .map(|tags| {
+                    tags.split(',')
+                        .map(|tag| tag.trim().to_string())
+                        .filter(|tag| !tag.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let exclude_pattern = match matches.value_of("exclude_pattern") {
+            Some(pattern) => match regex::Regex::new(pattern) {
+                Ok(regex) => Some(regex),
+                Err(e) => return Err(Error::usage(format!("invalid --exclude_pattern: {}", e))),
+            },
+            None => None,
+        };
+        filter::EntryFilter {
+            include_tags: tag_list("include_tags"),
+            exclude_tags: tag_list("exclude_tags"),
+            exclude_pattern,
+        }
+    };
+
+    // Non-Japanese dictionaries are built purely from the given entry files.
+    if matches.value_of("lang").unwrap() != "ja" {
+        let mut entries = Vec::new();
+        add_finished_entries(matches, &mut entries)?;
+        if entries.is_empty() {
+            return Err(Error::invalid(
+                "",
+                "no entries to build a dictionary from.  Non-Japanese dictionaries need at least one --csv or --kobo_merge file.",
+            ));
+        }
+        return write_output(matches, entries, Vec::new());
+    }
+
+    // The input tables are dropped when generate_entries() returns, before
+    // the output is written, since writing takes a lot of memory of its own.
+    let (entries, dict_stats) = generate_entries(
+        matches,
+        lang_mode,
+        &styles,
+        &inflection_rules,
+        &config,
+        &entry_filter,
+    )?;
+    write_output(matches, entries, dict_stats)
+}
 
+/// Reads and merges the input dictionaries, and generates the entries of
+/// a Japanese dictionary from them.  Also returns the matching statistics
+/// of the Yomichan dictionaries, from `coverage_json()`.
+fn generate_entries(
+    matches: &clap::ArgMatches,
+    lang_mode: LangMode,
+    styles: &Styles,
+    inflection_rules: &InflectionRules,
+    config: &Config,
+    entry_filter: &filter::EntryFilter,
+) -> Result<(Vec<kobo::Entry>, Vec<serde_json::Value>), Error> {
     //----------------------------------------------------------------
     // Read in all the files.
 
-    println!("Extracting bundled data...");
+    info!("Loading dictionaries...");
+
+    // Parse the JMDict XML data: the file passed with --jmdict, or else
+    // the copy from `fetch` if there is one, or else the bundled one.
+    #[cfg(feature = "bundled-jmdict")]
+    const JM_DATA: Option<&[u8]> = Some(include_bytes!("../dictionaries/JMdict_e.xml.gz"));
+    #[cfg(not(feature = "bundled-jmdict"))]
+    const JM_DATA: Option<&[u8]> = None;
+    let jm_path = matches
+        .value_of("jmdict")
+        .map(std::path::PathBuf::from)
+        .or_else(|| fetch::local_path(&data_dir(matches), "jmdict"));
+    let jm_file_data = match jm_path {
+        Some(path) => {
+            info!("    Using the JMDict data in {}", path.display());
+            Some(
+                std::fs::read(&path)
+                    .context(|| format!("failed to read JMDict data '{}'", path.display()))?,
+            )
+        }
+        None => None,
+    };
+    let jm_data = match jm_file_data.as_deref().or(JM_DATA) {
+        Some(data) => data,
+        None => {
+            return Err(Error::usage("no JMDict data.  This build doesn't include the bundled data, so pass a JMDict file with --jmdict, or download one with `kobo_jp_dict fetch jmdict`."));
+        }
+    };
+    let frequencies = match matches.value_of("frequency") {
+        Some(path) => {
+            let frequencies = yomichan::parse_frequencies(std::path::Path::new(path))?;
+            info!("    {} frequencies: {}", path, frequencies.len());
+            frequencies
+        }
+        None => HashMap::new(),
+    };
+
+    // The Yomichan dictionaries, with whether they're --names_dict ones.
+    // Name dictionaries come after the others.
+    let yomi_args: Vec<(&str, bool)> = matches
+        .values_of("yomichan_dict")
+        .into_iter()
+        .flatten()
+        .map(|arg| (arg, false))
+        .chain(
+            matches
+                .values_of("names_dict")
+                .into_iter()
+                .flatten()
+                .map(|arg| (arg, true)),
+        )
+        .collect();
+
+    // Parse the JMDict data, the pitch accent data, and each of the
+    // Yomichan dictionaries in parallel, since parsing is most of the
+    // work of a build.
+    let ((jmdict, pa_table), yomi_dicts) = rayon::join(
+        || {
+            rayon::join(
+                || {
+                    cache::load_jmdict(
+                        jm_data,
+                        &config.priority_model,
+                        !matches.is_present("no_cache"),
+                        &matches
+                            .value_of("tmpdir")
+                            .map(|p| p.into())
+                            .unwrap_or_else(std::env::temp_dir),
+                    )
+                },
+                || read_pitch_accents(matches),
+            )
+        },
+        || {
+            yomi_args
+                .par_iter()
+                .map(|(arg, is_names_dict)| {
+                    input::YomichanSource {
+                        path: parse_dict_arg(arg).0.into(),
+                        config,
+                        embed_images: matches.is_present("embed_images"),
+                        is_names_dict: *is_names_dict,
+                    }
+                    .load()
+                })
+                .collect::<Vec<_>>()
+        },
+    );
 
-    // Parse the bundled JMDict XML data.
-    const JM_DATA: &[u8] = include_bytes!("../dictionaries/JMdict_e.xml.gz");
     let jm_table = {
-        let mut jm_table: HashMap<(String, String), Vec<WordEntry>> = HashMap::new(); // (Kanji, Kana)
-        let parser = jmdict::Parser::from_reader(BufReader::new(GzDecoder::new(JM_DATA)));
-        for entry in parser {
-            let reading = strip_non_kana(&hiragana_to_katakana(&entry.readings[0].trim()));
-            let writing = if entry.writings.len() > 0 {
-                entry.writings[0].clone()
+        let mut jm_table: BTreeMap<(String, String), Vec<WordEntry>> = BTreeMap::new(); // (Kanji, Kana)
+        let skip_archaic = matches.is_present("skip_archaic");
+        let mut archaic_count = 0;
+        let mut filtered_count = 0;
+        for mut entry in jmdict.entries {
+            if skip_archaic && entry.is_archaic() {
+                archaic_count += 1;
+                continue;
+            }
+            if !entry_filter.is_empty() && !entry_filter.filter_jmdict(&mut entry) {
+                filtered_count += 1;
+                continue;
+            }
+
+            // Use the word's frequency rank as its priority if it has one,
+            // since JMDict's priority markers are quite dated.  The ranks
+            // are roughly on the same scale as JMDict's priorities.
+            if let Some(rank) = frequency_rank(&frequencies, &entry) {
+                entry.priority = rank;
+                entry.form_priorities.clear();
+            }
+
+            // Key the entry on its first writing, and the first reading
+            // that's valid for that writing.
+            let (writing, reading) = if entry.writings.len() > 0 {
+                (
+                    kobo::normalize_key(&entry.writings[0], "ja"),
+                    entry.reading_of(&entry.writings[0]),
+                )
             } else {
-                entry.readings[0].trim().into()
+                (entry.readings[0].trim().into(), entry.readings[0].as_str())
             };
+            let reading = strip_non_kana(&hiragana_to_katakana(reading.trim()));
 
             let e = jm_table.entry((writing, reading)).or_insert(Vec::new());
             e.push(entry);
         }
-        jm_table
-    };
-    println!("    Metadata entries: {}", jm_table.len());
-
-    // Open and parse the pitch accent data.
-    const PA_DATA: &[u8] = include_bytes!("../dictionaries/accents.tsv.gz");
-    let pa_table = {
-        let mut pa_table: HashMap<(String, String), Vec<u32>> = HashMap::new(); // (Kanji, Kana), Pitch Accent
-
-        // Use the passed file if specified on the command line.  Otherwise use the bundled one.
-        let mut data = Vec::new();
-        if let Some(path) = matches.value_of("pitch_accent") {
-            File::open(path)?.read_to_end(&mut data)?;
-        } else {
-            GzDecoder::new(PA_DATA).read_to_end(&mut data)?;
-        };
-        let reader = std::io::Cursor::new(data);
-
-        for line in reader.lines() {
-            let line = line.unwrap_or_else(|_| "".into());
-            let parts: Vec<_> = line.split("\t").map(|a| a.trim()).collect();
-            assert_eq!(parts.len(), 3);
-            let accents: Vec<u32> = parts[2]
-                .split(|ch: char| !ch.is_digit(10))
-                .filter(|s| !s.is_empty())
-                .map(|a| a.parse::<u32>().unwrap())
+        if !jmdict.unknown_tags.is_empty() {
+            let tags: Vec<String> = jmdict
+                .unknown_tags
+                .iter()
+                .map(|(tag, count)| format!("\n    {} ({} times)", tag, count))
                 .collect();
-
-            let (writing, reading) = if is_all_kana(parts[0]) && parts[1].is_empty() {
-                (parts[0].into(), hiragana_to_katakana(parts[0]))
-            } else {
-                (parts[0].into(), hiragana_to_katakana(parts[1]))
-            };
-
-            pa_table.insert((writing, reading), accents);
+            warning!(
+                "JMDict has tags that aren't recognized, and may be mishandled:{}",
+                tags.concat()
+            );
+        }
+        if skip_archaic {
+            info!("    Skipped archaic entries: {}", archaic_count);
+        }
+        if !entry_filter.is_empty() {
+            info!("    Filtered out entries: {}", filtered_count);
         }
-        pa_table
+        jm_table
     };
-    println!("    Pitch Accent entries: {}", pa_table.len());
+    info!("    Metadata entries: {}", jm_table.len());
+
+    let pa_table = pa_table?;
 
-    println!("Loading dictionaries...");
+    info!("    Pitch Accent entries: {}", pa_table.len());
 
     // Open and parse Yomichan dictionaries.
-    let mut yomi_term_table: HashMap<(String, String), Vec<yomichan::TermEntry>> = HashMap::new(); // (Kanji, Kana)
-    let mut yomi_name_table: HashMap<(String, String), Vec<yomichan::TermEntry>> = HashMap::new(); // (Kanji, Kana)
-    let mut yomi_kanji_table: HashMap<String, Vec<yomichan::KanjiEntry>> = HashMap::new(); // Kanji
-    if let Some(paths) = matches.values_of("yomichan_dict") {
-        for path in paths {
+    // These are BTreeMaps so that the entries are generated in the same
+    // order on every run, which makes builds reproducible.
+    let mut yomi_term_table: BTreeMap<(String, String), Vec<yomichan::TermEntry>> = BTreeMap::new(); // (Kanji, Kana)
+    let mut yomi_name_table: BTreeMap<(String, String), Vec<yomichan::TermEntry>> = BTreeMap::new(); // (Kanji, Kana)
+    let mut yomi_kanji_table: BTreeMap<String, Vec<yomichan::KanjiEntry>> = BTreeMap::new(); // Kanji
+    let mut dict_names = Vec::new(); // In --yomichan order.
+    let mut coverage: HashMap<String, Coverage> = HashMap::new(); // Dictionary name -> coverage
+    let mut loaded_dicts: Vec<LoadedDict> = Vec::new(); // In --yomichan order.
+    {
+        for ((arg, _), dict) in yomi_args.iter().zip(yomi_dicts) {
             let mut entry_count = 0usize;
 
-            let (mut word_entries, mut name_entries, mut kanji_entries) =
-                yomichan::parse(std::path::Path::new(path)).unwrap();
+            let (path, content) = parse_dict_arg(arg);
+            let input::SourceEntries {
+                terms: mut word_entries,
+                names: mut name_entries,
+                kanji: mut kanji_entries,
+                ..
+            } = dict?;
+            let mut loaded = LoadedDict {
+                path: path.into(),
+                dict_name: word_entries.first().map(|entry| entry.dict_name.clone()),
+                parsed: word_entries.len() + name_entries.len() + kanji_entries.len(),
+                ..LoadedDict::default()
+            };
+            if !content.terms {
+                word_entries.clear();
+            }
+            if !content.names || matches.is_present("no_names") {
+                name_entries.clear();
+            }
+            if !content.kanji || matches.is_present("no_kanji") {
+                kanji_entries.clear();
+            }
+            loaded.selected = word_entries.len() + name_entries.len() + kanji_entries.len();
+            let mut filtered_count = 0;
+            if !entry_filter.is_empty() {
+                let count = word_entries.len() + name_entries.len();
+                word_entries.retain(|entry| entry_filter.keep_yomichan(entry));
+                name_entries.retain(|entry| entry_filter.keep_yomichan(entry));
+                filtered_count = count - word_entries.len() - name_entries.len();
+            }
+            loaded.terms = word_entries.len();
+            loaded.others = name_entries.len() + kanji_entries.len();
+            loaded_dicts.push(loaded);
 
             // Put all of the word entries into the terms table.
             entry_count += word_entries.len();
-            for entry in word_entries.drain(..) {
+            for mut entry in word_entries.drain(..) {
+                if !dict_names.contains(&entry.dict_name) {
+                    dict_names.push(entry.dict_name.clone());
+                }
+                entry.writing = kobo::normalize_key(&entry.writing, "ja");
+                entry.reading = kobo::normalize_key(&entry.reading, "ja");
                 let reading = strip_non_kana(&hiragana_to_katakana(entry.reading.trim()));
                 let writing: String = entry.writing.trim().into();
                 if writing.is_empty() {
@@ -166,6 +1166,28 @@ fn main() -> io::Result<()> {
                         .or_insert(Vec::new());
                     entry_list.push(entry);
                 } else {
+                    // Use JMDict's form of the headword if it only differs
+                    // from this one in its use of the iteration mark (々).
+                    let writing = match iteration_mark_variant(&writing) {
+                        Some(variant)
+                            if !jm_table.contains_key(&(writing.clone(), reading.clone()))
+                                && jm_table.contains_key(&(variant.clone(), reading.clone())) =>
+                        {
+                            verbose!(
+                                "Matched {}【{}】 from {} to JMDict's {}.",
+                                writing,
+                                reading,
+                                entry.dict_name,
+                                variant
+                            );
+                            coverage
+                                .entry(entry.dict_name.clone())
+                                .or_default()
+                                .normalized += 1;
+                            variant
+                        }
+                        _ => writing,
+                    };
                     let entry_list = yomi_term_table
                         .entry((writing, reading))
                         .or_insert(Vec::new());
@@ -175,7 +1197,9 @@ fn main() -> io::Result<()> {
 
             // Put all of the name entries into the names table.
             entry_count += name_entries.len();
-            for entry in name_entries.drain(..) {
+            for mut entry in name_entries.drain(..) {
+                entry.writing = kobo::normalize_key(&entry.writing, "ja");
+                entry.reading = kobo::normalize_key(&entry.reading, "ja");
                 let reading = strip_non_kana(&hiragana_to_katakana(entry.reading.trim()));
                 let writing: String = entry.writing.trim().into();
                 if writing.is_empty() {
@@ -200,10 +1224,142 @@ fn main() -> io::Result<()> {
                 entry_list.push(entry);
             }
 
-            println!("    {} entries: {}", path, entry_count);
+            if filtered_count > 0 {
+                info!(
+                    "    {} entries: {} ({} filtered out)",
+                    path, entry_count, filtered_count
+                );
+            } else {
+                info!("    {} entries: {}", path, entry_count);
+            }
         }
     }
 
+    // Combine Yomichan entries that don't exactly match a JMDict entry with
+    // the ones that loosely match, depending on --merge.
+    let merge_policy = matches.value_of("merge").unwrap();
+    if merge_policy != "exact" {
+        let without_kana =
+            |writing: &str| -> String { writing.chars().filter(|ch| !is_kana(*ch)).collect() };
+
+        // Index the JMDict entries by the kanji of each of their writings,
+        // and by reading.
+        let mut okurigana_index: HashMap<(String, &str), Vec<&(String, String)>> = HashMap::new();
+        let mut reading_index: HashMap<&str, Vec<&(String, String)>> = HashMap::new();
+        for (key, items) in jm_table.iter() {
+            for writing in items.iter().flat_map(|entry| entry.writings.iter()) {
+                let keys = okurigana_index
+                    .entry((without_kana(writing), &key.1))
+                    .or_default();
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+            reading_index.entry(&key.1).or_default().push(key);
+        }
+
+        // Only merge into a single, unambiguous match.
+        let mut merged_keys = Vec::new();
+        let unmatched: Vec<(String, String)> = yomi_term_table
+            .keys()
+            .filter(|key| !jm_table.contains_key(*key))
+            .cloned()
+            .collect();
+        for key in unmatched {
+            let kanji = without_kana(&key.0);
+            let target = match okurigana_index.get(&(kanji.clone(), key.1.as_str())) {
+                Some(keys) if keys.len() == 1 && !kanji.is_empty() => Some(keys[0]),
+                _ if merge_policy == "reading" => match reading_index.get(key.1.as_str()) {
+                    Some(keys) if keys.len() == 1 => Some(keys[0]),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some(target) = target {
+                let items = yomi_term_table.remove(&key).unwrap();
+                for item in items.iter() {
+                    verbose!(
+                        "Merged {}【{}】 from {} into JMDict's {}【{}】.",
+                        key.0,
+                        key.1,
+                        item.dict_name,
+                        target.0,
+                        target.1
+                    );
+                    coverage
+                        .entry(item.dict_name.clone())
+                        .or_default()
+                        .normalized += 1;
+                }
+                yomi_term_table
+                    .entry(target.clone())
+                    .or_default()
+                    .extend(items);
+                merged_keys.push(target.clone());
+            }
+        }
+
+        // Put the merged definitions back in --yomichan order.
+        let rank = |name: &str| dict_names.iter().position(|n| n == name);
+        for key in merged_keys.iter() {
+            if let Some(items) = yomi_term_table.get_mut(key) {
+                items.sort_by_key(|item| rank(&item.dict_name));
+            }
+        }
+        info!("    Loosely matched entries: {}", merged_keys.len());
+    }
+
+    // Order the definitions of each word by dictionary.  They're already
+    // in --yomichan order, so a stable sort keeps that for unlisted ones.
+    if let Some(order) = matches.value_of("dict_order") {
+        let order: Vec<String> = order.split(',').map(normalize_dict_title).collect();
+        let rank = |name: &str| order.iter().position(|n| n == name).unwrap_or(order.len());
+        for items in yomi_term_table.values_mut() {
+            items.sort_by_key(|item| rank(&item.dict_name));
+        }
+
+        for name in order.iter() {
+            if !yomi_term_table
+                .values()
+                .any(|items| items.iter().any(|item| &item.dict_name == name))
+            {
+                warning!(
+                    "no loaded dictionary is titled \"{}\" (from --dict_order).",
+                    name
+                );
+            }
+        }
+    }
+
+    // The names shown above each dictionary's definitions.
+    let dict_labels = DictLabels::new(
+        config,
+        matches.values_of("dict_title").into_iter().flatten(),
+    )?;
+
+    // Limit the number of definitions from each dictionary.
+    let max_defs = parse_max_defs(matches.values_of("max_defs").into_iter().flatten())?;
+    for items in yomi_term_table.values_mut() {
+        for item in items.iter_mut() {
+            if let Some(n) = max_defs.get(&item.dict_name) {
+                item.definitions.truncate(n);
+            }
+        }
+    }
+    let jm_max_defs = max_defs.get(&normalize_dict_title("JMDict"));
+    let max_entry_size = match matches.value_of("max_entry_size") {
+        Some(bytes) => match bytes.parse::<usize>() {
+            Ok(bytes) => Some(bytes),
+            Err(_) => {
+                return Err(Error::usage(format!(
+                    "invalid --max_entry_size \"{}\".",
+                    bytes
+                )))
+            }
+        },
+        None => None,
+    };
+
     //----------------------------------------------------------------
     // Generate the new dictionary entries.
     let mut entries = Vec::new();
@@ -211,70 +1367,1453 @@ fn main() -> io::Result<()> {
     // Kanji entries.
     for (kanji, items) in yomi_kanji_table.iter() {
         let mut entry_text: String = "<hr/>".into();
-        entry_text.push_str(&generate_kanji_entry_text(&items[0]));
+        entry_text.push_str(&generate_kanji_entry_text(&items[0], styles));
 
         entries.push(kobo::Entry {
             keys: vec![(kanji.clone(), 0)],
             definition: entry_text,
+            jmdict_seq: None,
         });
     }
 
-    // Term entries.
-    for ((kanji, kana), item) in jm_table.iter() {
-        for jm_entry in item.iter() {
-            // Find matching entries in the source dictionaries.
-            let pitch_accent = pa_table.get(&(kanji.clone(), kana.clone()));
-            let yomi_term_entries = yomi_term_table
-                .get(&(kanji.clone(), kana.clone()))
-                .map(|a| a.as_slice())
-                .unwrap_or(&[]);
+    // Words that take the honorific prefix お or ご, found from JMDict's
+    // polite and honorific entries that have it, e.g. 土産 (みやげ) from
+    // お土産.  These then get look-up keys with the prefix.
+    let mut honorific_prefixes: HashMap<String, Vec<(String, String)>> = HashMap::new(); // Word -> (prefix, reading)
+    for jm_entry in jm_table.values().flatten() {
+        if !jm_entry.tags.contains("misc:pol") && !jm_entry.tags.contains("misc:hon") {
+            continue;
+        }
+        for reading in jm_entry.readings.iter() {
+            let reading = match reading
+                .strip_prefix("お")
+                .or_else(|| reading.strip_prefix("ご"))
+            {
+                Some(r) if !r.is_empty() => r,
+                _ => continue,
+            };
+            for form in jm_entry.writings.iter().chain(jm_entry.readings.iter()) {
+                for prefix in ["お", "ご", "御"].iter() {
+                    if let Some(word) = form.strip_prefix(prefix) {
+                        let prefixes = honorific_prefixes.entry(word.into()).or_default();
+                        let item = (prefix.to_string(), reading.to_string());
+                        if !word.is_empty() && !prefixes.contains(&item) {
+                            prefixes.push(item);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // The terms used in an entry's header and conjugation table follow the
+    // `terms` setting of the dictionary its definitions start with, if it
+    // has one.
+    let dict_terms = config.terms();
+    let jmdict_title = normalize_dict_title("JMDict");
+    let entry_lang_mode = |first_dict: Option<&str>| -> LangMode {
+        first_dict
+            .and_then(|name| dict_terms.get(name))
+            .copied()
+            .unwrap_or(lang_mode)
+    };
+
+    // Term entries.
+    let jmdict_definitions = matches.value_of("jmdict_definitions");
+    let english_section = matches.is_present("english_section");
+    let conjugation_table = matches.is_present("conjugation_table");
+    let hide_rare_forms = matches.is_present("hide_rare_forms");
+    for ((kanji, kana), item) in jm_table.iter() {
+        for jm_entry in item.iter() {
+            let shown_entry;
+            let jm_entry = if hide_rare_forms {
+                shown_entry = jm_entry.without_rare_forms();
+                &shown_entry
+            } else {
+                jm_entry
+            };
+
+            // Find matching entries in the source dictionaries.
+            let pitch_accent = pa_table.get(&(kanji.clone(), kana.clone()));
+            let yomi_term_entries = yomi_term_table
+                .get(&(kanji.clone(), kana.clone()))
+                .map(|a| a.as_slice())
+                .unwrap_or(&[]);
+
+            let jm_definitions = match jmdict_definitions {
+                Some("append") => &jm_entry.definitions[..],
+                Some("fallback") if yomi_term_entries.is_empty() => &jm_entry.definitions[..],
+                _ => &[],
+            };
+
+            if pitch_accent.is_some() || !yomi_term_entries.is_empty() || !jm_definitions.is_empty()
+            {
+                let lang_mode = entry_lang_mode(match yomi_term_entries.first() {
+                    Some(item) => Some(&item.dict_name),
+                    None if !jm_definitions.is_empty() => Some(&jmdict_title),
+                    None => None,
+                });
+                let mut entry_text: String = "<hr/>".into();
+
+                // Add header and definition to the entry text.
+                entry_text.push_str(&generate_header_text(
+                    matches.is_present("katakana_pronunciation"),
+                    lang_mode,
+                    matches.is_present("usage_labels"),
+                    &kana,
+                    pitch_accent,
+                    &jm_entry,
+                    styles,
+                ));
+                entry_text.push_str(&generate_limited_definition_text(
+                    yomi_term_entries,
+                    jm_definitions,
+                    jm_max_defs,
+                    max_entry_size,
+                    english_section,
+                    &dict_labels,
+                    styles,
+                ));
+                if conjugation_table {
+                    entry_text.push_str(&generate_conjugation_text(lang_mode, jm_entry, styles));
+                }
+
+                // Add to the entry list.
+                entries.push(kobo::Entry {
+                    keys: generate_lookup_keys(jm_entry, inflection_rules, &honorific_prefixes),
+                    definition: entry_text,
+                    jmdict_seq: jm_entry.sequence,
+                });
+            }
+        }
+    }
+
+    // Yomichan term entries without a matching JMDict entry.
+    let dict_stats = {
+        let include_unmatched = matches.is_present("unmatched_entries");
+        for ((writing, reading), items) in yomi_term_table.iter() {
+            if jm_table.contains_key(&(writing.clone(), reading.clone())) {
+                for item in items.iter() {
+                    coverage.entry(item.dict_name.clone()).or_default().matched += 1;
+                }
+                continue;
+            }
+            for item in items.iter() {
+                coverage
+                    .entry(item.dict_name.clone())
+                    .or_default()
+                    .unmatched += 1;
+                debug!(
+                    "No JMDict entry for {}【{}】 from {}{}.",
+                    writing,
+                    reading,
+                    item.dict_name,
+                    if include_unmatched {
+                        ""
+                    } else {
+                        ", so it's left out"
+                    }
+                );
+            }
+            if !include_unmatched {
+                continue;
+            }
+
+            let word_entry = unmatched_word_entry(writing, &items[0]);
+            let lang_mode = entry_lang_mode(Some(&items[0].dict_name));
+            let mut entry_text: String = "<hr/>".into();
+            entry_text.push_str(&generate_header_text(
+                matches.is_present("katakana_pronunciation"),
+                lang_mode,
+                matches.is_present("usage_labels"),
+                &word_entry.readings[0],
+                pa_table.get(&(writing.clone(), reading.clone())),
+                &word_entry,
+                styles,
+            ));
+            entry_text.push_str(&generate_limited_definition_text(
+                items,
+                &[],
+                None,
+                max_entry_size,
+                english_section,
+                &dict_labels,
+                styles,
+            ));
+            if conjugation_table {
+                entry_text.push_str(&generate_conjugation_text(lang_mode, &word_entry, styles));
+            }
+            entries.push(kobo::Entry {
+                keys: generate_lookup_keys(&word_entry, inflection_rules, &honorific_prefixes),
+                definition: entry_text,
+                jmdict_seq: word_entry.sequence,
+            });
+        }
+
+        print_coverage(&dict_names, &coverage, include_unmatched);
+        warn_empty_dicts(&loaded_dicts, &coverage, include_unmatched);
+        let dict_stats = coverage_json(&dict_names, &coverage, include_unmatched);
+        if let Some(path) = matches.value_of("report") {
+            write_coverage_report(path, &dict_stats)?;
+        }
+        dict_stats
+    };
+
+    // Number entries.
+    if matches.is_present("numbers") {
+        for n in 1..10000 {
+            let (kanji, reading) = counters::kanji_number(n);
+            let mut word_entry = WordEntry::new();
+            word_entry.writings.push(kanji.clone());
+            word_entry.readings.push(reading.clone());
+
+            let mut entry_text: String = "<hr/>".into();
+            entry_text.push_str(&generate_header_text(
+                matches.is_present("katakana_pronunciation"),
+                lang_mode,
+                matches.is_present("usage_labels"),
+                &reading,
+                None,
+                &word_entry,
+                styles,
+            ));
+            entry_text.push_str(&generate_definition_text(
+                &[],
+                &[Sense {
+                    glosses: vec![n.to_string()],
+                    ..Sense::new()
+                }],
+                None,
+                english_section,
+                &dict_labels,
+                styles,
+            ));
+            entries.push(kobo::Entry {
+                keys: vec![(kanji, 1000000)], // After any JMDict entries for the same number.
+                definition: entry_text,
+                jmdict_seq: None,
+            });
+        }
+    }
+
+    // Name entries.
+    for ((writing, _reading), items) in yomi_name_table.iter() {
+        for item in items.iter() {
+            let mut entry_text: String = "<hr/>".into();
+            entry_text.push_str(&generate_name_entry_text(
+                matches.is_present("katakana_pronunciation"),
+                entry_lang_mode(Some(&item.dict_name)),
+                item,
+                styles,
+            ));
+            entries.push(kobo::Entry {
+                keys: vec![(writing.clone(), std::u32::MAX)], // Always sort names last.
+                definition: entry_text,
+                jmdict_seq: None,
+            });
+        }
+    }
+
+    add_finished_entries(matches, &mut entries)?;
+
+    Ok((entries, dict_stats))
+}
+
+/// Reads the pitch accent data: the file passed with --pitch_accent, or
+/// else the bundled data, if this build has it.
+fn read_pitch_accents(
+    matches: &clap::ArgMatches,
+) -> Result<HashMap<(String, String), Vec<u32>>, Error> {
+    #[cfg(feature = "bundled-accents")]
+    const PA_DATA: Option<&[u8]> = Some(include_bytes!("../dictionaries/accents.tsv.gz"));
+    #[cfg(not(feature = "bundled-accents"))]
+    const PA_DATA: Option<&[u8]> = None;
+    let mut pa_table: HashMap<(String, String), Vec<u32>> = HashMap::new(); // (Kanji, Kana), Pitch Accent
+
+    let mut data = Vec::new();
+    if let Some(path) = matches.value_of("pitch_accent") {
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .context(|| format!("failed to read pitch accent file '{}'", path))?;
+    } else if let Some(pa_data) = PA_DATA {
+        GzDecoder::new(pa_data).read_to_end(&mut data)?;
+    } else {
+        warning!("this build doesn't include the bundled pitch accent data, so entries won't have pitch accents.  Pass a pitch accent file with --pitch_accent.");
+    };
+    let reader = std::io::Cursor::new(data);
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.unwrap_or_else(|_| "".into());
+        let parts: Vec<_> = line.split("\t").map(|a| a.trim()).collect();
+        if parts.len() != 3 {
+            return Err(Error::invalid(
+                format!(
+                    "pitch accent file '{}' isn't valid",
+                    matches.value_of("pitch_accent").unwrap_or("(bundled)")
+                ),
+                format!("line {} has {} columns instead of 3", i + 1, parts.len()),
+            ));
+        }
+        let accents: Vec<u32> = parts[2]
+            .split(|ch: char| !ch.is_digit(10))
+            .filter_map(|a| a.parse::<u32>().ok())
+            .collect();
+
+        let (writing, reading) = if is_all_kana(parts[0]) && parts[1].is_empty() {
+            (parts[0].into(), hiragana_to_katakana(parts[0]))
+        } else {
+            (
+                kobo::normalize_key(parts[0], "ja"),
+                hiragana_to_katakana(parts[1]),
+            )
+        };
+
+        pa_table.insert((writing, reading), accents);
+    }
+    Ok(pa_table)
+}
+
+/// Handles `build --watch`: builds the dictionary, and then rebuilds it
+/// whenever one of the input files changes, until interrupted.  Errors of
+/// a build are reported without stopping, except for command line errors
+/// in the first build, which changing the input files can't fix.
+///
+/// Rebuilds reuse the compressed files of the previous build (see
+/// `--reuse_from`), so that only the prefix files that changed are
+/// recompressed.
+fn watch_build(
+    command: &clap::Command,
+    mut args: Vec<std::ffi::OsString>,
+    matches: &clap::ArgMatches,
+) -> Result<(), Error> {
+    let output = matches.value_of("OUTPUT").unwrap();
+    let build_matches = |args: &[std::ffi::OsString]| -> clap::ArgMatches {
+        command
+            .clone()
+            .get_matches_from(args)
+            .subcommand_matches("build")
+            .unwrap()
+            .clone()
+    };
+
+    // Rebuilds overwrite the output of the previous build, and so are
+    // always forced.
+    if !matches.is_present("force") {
+        args.push("--force".into());
+    }
+    let rebuild_matches = build_matches(&args);
+    let reuse_matches = if matches.value_of("format").unwrap() == "kobo"
+        && !matches.is_present("reuse_from")
+        && !matches.is_present("dry_run")
+    {
+        args.push("--reuse_from".into());
+        args.push(output.into());
+        Some(build_matches(&args))
+    } else {
+        None
+    };
+
+    // Each file's modification time and size, or `None` if it can't be
+    // read.
+    let mut paths: Vec<&str> = Vec::new();
+    for (arg_name, _) in INPUT_ARGS.iter() {
+        for arg in matches.values_of(arg_name).into_iter().flatten() {
+            paths.push(match *arg_name {
+                "yomichan_dict" | "names_dict" => parse_dict_arg(arg).0,
+                _ => arg,
+            });
+        }
+    }
+    let file_states = || -> Vec<Option<(std::time::SystemTime, u64)>> {
+        paths
+            .iter()
+            .map(|path| {
+                let metadata = std::fs::metadata(path).ok()?;
+                Some((metadata.modified().ok()?, metadata.len()))
+            })
+            .collect()
+    };
+
+    let mut states = file_states();
+    let mut first_build = true;
+    loop {
+        // Reuse the previous build, if there is one.
+        let build_matches = match reuse_matches {
+            _ if first_build => matches,
+            Some(ref reuse_matches) if std::path::Path::new(output).is_file() => reuse_matches,
+            _ => &rebuild_matches,
+        };
+        match build_dictionary(build_matches) {
+            Err(e @ Error::Usage(_)) if first_build => return Err(e),
+            Err(e) => error!("{}", e),
+            Ok(()) => {}
+        }
+        first_build = false;
+
+        info!(
+            "Watching {} input files for changes.  Press Ctrl+C to stop.",
+            paths.len()
+        );
+        loop {
+            std::thread::sleep(std::time::Duration::from_millis(500));
+            let new_states = file_states();
+            if new_states != states {
+                // Give the program that changed the file time to finish.
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                states = file_states();
+                break;
+            }
+        }
+        info!("Input files changed, rebuilding...");
+    }
+}
+
+/// Adds the finished entries of the `--csv` files and the `--kobo_merge`
+/// dictionary.
+fn add_finished_entries(
+    matches: &clap::ArgMatches,
+    entries: &mut Vec<kobo::Entry>,
+) -> Result<(), Error> {
+    let mut sources: Vec<Box<dyn InputSource>> = Vec::new();
+    for path in matches.values_of("csv").into_iter().flatten() {
+        sources.push(Box::new(input::CsvSource { path: path.into() }));
+    }
+    if let Some(path) = matches.value_of("kobo_merge") {
+        let priority = if matches.is_present("kobo_merge_first") {
+            1 // Just after kanji entries.
+        } else {
+            u32::MAX - 1 // Just before name entries.
+        };
+        sources.push(Box::new(input::KoboSource {
+            path: path.into(),
+            priority,
+        }));
+    }
+
+    for source in sources {
+        let loaded = source.load()?;
+        info!(
+            "    {} entries: {}",
+            source.path().display(),
+            loaded.finished.len()
+        );
+        entries.extend(loaded.finished);
+    }
+    Ok(())
+}
+
+/// Writes the entries to the output file in the selected format, along
+/// with its manifest.  `dict_stats` are the matching statistics of the
+/// Yomichan dictionaries, from `coverage_json()`.
+fn write_output(
+    matches: &clap::ArgMatches,
+    mut entries: Vec<kobo::Entry>,
+    dict_stats: Vec<serde_json::Value>,
+) -> Result<(), Error> {
+    let output_path = std::path::Path::new(matches.value_of("OUTPUT").unwrap());
+    let lang = matches.value_of("lang").unwrap();
+
+    // The input files are hashed before the output is written, since the
+    // output can replace one of them (--reuse_from).
+    let inputs = if matches.is_present("save_project")
+        || !(matches.is_present("no_manifest") || matches.is_present("dry_run"))
+    {
+        input_files_json(matches)?
+    } else {
+        Vec::new()
+    };
+
+    if let Some(path) = matches.value_of("save_project") {
+        let path = std::path::Path::new(path);
+        let project = project::Project::new(lang, inputs.clone(), dict_stats.clone(), entries);
+        write_atomically(path, |temp_path| project.save(temp_path))
+            .context(|| format!("failed to write project file '{}'", path.display()))?;
+        info!("Saved the entries to {}.", path.display());
+        entries = project.entries;
+    }
+
+    // Word lists.
+    if let Some(path) = matches.value_of("exclude_words") {
+        let words = read_word_list(path, lang)?;
+        let mut count = 0;
+        for entry in entries.iter_mut() {
+            let key_count = entry.keys.len();
+            entry
+                .keys
+                .retain(|(key, _)| !words.contains(&kobo::normalize_key(key, lang)));
+            count += key_count - entry.keys.len();
+        }
+        entries.retain(|entry| !entry.keys.is_empty());
+        info!("Excluded {} keys listed in {}.", count, path);
+    }
+    if let Some(path) = matches.value_of("only_words") {
+        let words = read_word_list(path, lang)?;
+        let entry_count = entries.len();
+        entries.retain(|entry| {
+            entry
+                .keys
+                .iter()
+                .any(|(key, _)| words.contains(&kobo::normalize_key(key, lang)))
+        });
+        info!(
+            "Kept {} of {} entries, for the words listed in {}.",
+            entries.len(),
+            entry_count,
+            path
+        );
+    }
+
+    // Malformed html in one entry can break the rendering of every entry
+    // after it in the same prefix file, so all entries are sanitized.
+    let sanitized_count: usize = entries
+        .par_iter_mut()
+        .map(|entry| {
+            let sanitized = html::sanitize(&entry.definition);
+            if sanitized != entry.definition {
+                entry.definition = sanitized;
+                1
+            } else {
+                0
+            }
+        })
+        .sum();
+    if sanitized_count > 0 {
+        info!(
+            "Removed unsupported or malformed html from {} entries.",
+            sanitized_count
+        );
+    }
+
+    entries.sort_by_key(|a| a.keys[0].0.len());
+
+    let max_prefix_size = match matches.value_of("max_prefix_size") {
+        Some(kb) => match kb.parse::<usize>() {
+            Ok(kb) => Some(kb * 1024),
+            Err(_) => {
+                return Err(Error::usage(format!(
+                    "invalid --max_prefix_size \"{}\".",
+                    kb
+                )))
+            }
+        },
+        None => None,
+    };
+
+    let settings = kobo::WriteSettings {
+        lang: lang.into(),
+        use_marisa_build: !matches.is_present("builtin_trie"),
+        max_prefix_size,
+        compression: matches.value_of("compression").unwrap().parse().unwrap(),
+        reuse_from: matches.value_of("reuse_from").map(|p| p.into()),
+        temp_dir: matches.value_of("tmpdir").map(|p| p.into()),
+        keep_temp: matches.is_present("keep_temp"),
+    };
+
+    let writer = output::writer(matches.value_of("format").unwrap(), &settings).unwrap();
+
+    if matches.is_present("dry_run") {
+        return print_dry_run_summary(&entries, lang, writer.as_ref());
+    }
+
+    info!("Writing dictionary to disk...");
+    write_atomically(output_path, |path| writer.write(&entries, path))
+        .context(|| format!("failed to write dictionary '{}'", output_path.display()))?;
+
+    if !matches.is_present("no_manifest") {
+        write_manifest(matches, output_path, &entries, inputs, dict_stats)?;
+    }
+
+    if matches.is_present("verify") && matches.value_of("format").unwrap() == "kobo" {
+        check_dictionary(output_path)?;
+    }
+
+    Ok(())
+}
+
+/// Writes a record of what went into a dictionary next to it, e.g.
+/// "dicthtml-ja.manifest.json" for "dicthtml-ja.zip": the version of this
+/// tool, the command line arguments, the input files with their md5
+/// hashes (`inputs`, from `input_files_json()`), and the number of
+/// entries.
+fn write_manifest(
+    matches: &clap::ArgMatches,
+    output_path: &std::path::Path,
+    entries: &[kobo::Entry],
+    inputs: Vec<serde_json::Value>,
+    dict_stats: Vec<serde_json::Value>,
+) -> Result<(), Error> {
+    let mut keys = std::collections::HashSet::new();
+    for entry in entries.iter() {
+        for (key, _) in entry.keys.iter() {
+            keys.insert(kobo::normalize_key(key, matches.value_of("lang").unwrap()));
+        }
+    }
+
+    let manifest = serde_json::json!({
+        "tool": "kobo_jp_dict",
+        "version": clap::crate_version!(),
+        "arguments": std::env::args_os()
+            .skip(1)
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect::<Vec<_>>(),
+        "inputs": inputs,
+        "entries": entries.len(),
+        "lookup_keys": keys.len(),
+        "dictionaries": dict_stats,
+    });
+    let path = output_path.with_extension("manifest.json");
+    std::fs::write(&path, format!("{:#}\n", manifest))
+        .context(|| format!("failed to write manifest '{}'", path.display()))
+}
+
+/// The input files of a build as json, with their md5 hashes, for the
+/// manifest and project files.
+fn input_files_json(matches: &clap::ArgMatches) -> Result<Vec<serde_json::Value>, Error> {
+    use md5::{Digest, Md5};
+
+    let md5_hash = |path: &str| -> Result<String, Error> {
+        let data = std::fs::read(path).context(|| format!("failed to read '{}'", path))?;
+        Ok(Md5::digest(&data)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect())
+    };
+
+    let mut inputs = Vec::new();
+    let reuse_arg = [("reuse_from", "--reuse_from")];
+    for (arg_name, flag) in INPUT_ARGS.iter().chain(reuse_arg.iter()) {
+        for arg in matches.values_of(arg_name).into_iter().flatten() {
+            let path = match *arg_name {
+                "yomichan_dict" | "names_dict" => parse_dict_arg(arg).0,
+                _ => arg,
+            };
+            let mut input = serde_json::json!({
+                "option": flag,
+                "path": path,
+                "md5": md5_hash(path)?,
+            });
+            if let "yomichan_dict" | "names_dict" | "frequency" = *arg_name {
+                if let Some((title, revision)) = yomichan::read_title(std::path::Path::new(path)) {
+                    input["title"] = title.into();
+                    input["revision"] = revision.into();
+                }
+            }
+            inputs.push(input);
+        }
+    }
+
+    // JMDict data fetched with `fetch`, which is used instead of the
+    // bundled data.
+    if matches.value_of("lang").unwrap() == "ja"
+        && !matches.is_present("project")
+        && !matches.is_present("jmdict")
+    {
+        if let Some(path) = fetch::local_path(&data_dir(matches), "jmdict") {
+            let path = path.to_string_lossy();
+            inputs.push(serde_json::json!({
+                "option": "fetch",
+                "path": path,
+                "md5": md5_hash(&path)?,
+            }));
+        }
+    }
+
+    Ok(inputs)
+}
+
+/// How the term entries of a Yomichan dictionary matched JMDict entries.
+#[derive(Debug, Default, Copy, Clone)]
+struct Coverage {
+    /// Entries that have a JMDict entry, including the normalized ones.
+    matched: usize,
+
+    /// Entries that only matched a JMDict entry after normalizing their
+    /// writing: using JMDict's form of iteration marks, or with --merge.
+    normalized: usize,
+
+    /// Entries without a JMDict entry.  They're dropped unless
+    /// --unmatched_entries is given.
+    unmatched: usize,
+}
+
+/// How many entries of a Yomichan dictionary made it through each step of
+/// loading, for `warn_empty_dicts()`.
+#[derive(Debug, Default, Clone)]
+struct LoadedDict {
+    path: String,
+    dict_name: Option<String>, // None if it has no term entries.
+
+    /// Term, name, and kanji entries that were parsed.
+    parsed: usize,
+
+    /// Entries left after taking only the content given with its path and
+    /// applying --no_names and --no_kanji.
+    selected: usize,
+
+    /// Term entries, and name and kanji entries, left after filtering.
+    terms: usize,
+    others: usize,
+}
+
+/// Warns about Yomichan dictionaries that don't contribute any entries
+/// to the output, with the likely reason why.
+fn warn_empty_dicts(
+    loaded_dicts: &[LoadedDict],
+    coverage: &HashMap<String, Coverage>,
+    include_unmatched: bool,
+) {
+    for dict in loaded_dicts.iter() {
+        let matched = dict
+            .dict_name
+            .as_ref()
+            .and_then(|name| coverage.get(name))
+            .map(|c| c.matched)
+            .unwrap_or(0);
+        if dict.others > 0 || (dict.terms > 0 && (matched > 0 || include_unmatched)) {
+            continue;
+        }
+        let reason = if dict.parsed == 0 {
+            "no entries could be read from it.  It may be a frequency or pitch accent dictionary rather than a term dictionary (frequency dictionaries are passed with --frequency), or its entries may all be malformed.  \"kobo_jp_dict validate\" can tell you more.".to_string()
+        } else if dict.selected == 0 {
+            "none of its entries are of the kinds of content given after its path (e.g. \":kanji\"), or they were left out by --no_names or --no_kanji.".to_string()
+        } else if dict.terms == 0 {
+            "all of its entries were removed by --include_tags, --exclude_tags, or --exclude_pattern.".to_string()
+        } else {
+            format!("none of its {} entries matched a JMDict entry, and entries without a match are left out.  Use --unmatched_entries to include them, or --merge to match them more loosely.", dict.terms)
+        };
+        warning!(
+            "{} doesn't contribute any entries to the dictionary: {}",
+            dict.path,
+            reason
+        );
+    }
+}
+
+/// Prints how the entries of each Yomichan dictionary matched JMDict
+/// entries, in --yomichan order.
+fn print_coverage(
+    dict_names: &[String],
+    coverage: &HashMap<String, Coverage>,
+    include_unmatched: bool,
+) {
+    if dict_names.is_empty() {
+        return;
+    }
+    info!(
+        "Yomichan entries matched to JMDict entries (exactly / after normalizing / not matched):"
+    );
+    for dict_name in dict_names.iter() {
+        let c = coverage.get(dict_name).copied().unwrap_or_default();
+        info!(
+            "    {}: {} / {} / {}",
+            dict_name,
+            c.matched - c.normalized,
+            c.normalized,
+            c.unmatched
+        );
+    }
+    if !include_unmatched && coverage.values().any(|c| c.unmatched > 0) {
+        info!("    Entries that aren't matched are left out.  Use --unmatched_entries to include them.");
+    }
+}
+
+/// The matching statistics of each Yomichan dictionary as json, for
+/// --report and the manifest.
+fn coverage_json(
+    dict_names: &[String],
+    coverage: &HashMap<String, Coverage>,
+    include_unmatched: bool,
+) -> Vec<serde_json::Value> {
+    dict_names
+        .iter()
+        .map(|dict_name| {
+            let c = coverage.get(dict_name).copied().unwrap_or_default();
+            serde_json::json!({
+                "title": dict_name,
+                "entries": c.matched + c.unmatched,
+                "matched_exactly": c.matched - c.normalized,
+                "matched_after_normalizing": c.normalized,
+                "unmatched": c.unmatched,
+                "dropped": if include_unmatched { 0 } else { c.unmatched },
+            })
+        })
+        .collect()
+}
+
+/// Writes the matching statistics of --report as json.
+fn write_coverage_report(path: &str, dict_stats: &[serde_json::Value]) -> Result<(), Error> {
+    let report = serde_json::json!({ "dictionaries": dict_stats });
+    std::fs::write(path, format!("{:#}\n", report))
+        .context(|| format!("failed to write report '{}'", path))
+}
+
+/// Prints statistics about the entries that would have been written, for
+/// `--dry_run`.
+fn print_dry_run_summary(
+    entries: &[kobo::Entry],
+    lang: &str,
+    writer: &dyn output::OutputWriter,
+) -> Result<(), Error> {
+    let mut keys = std::collections::HashSet::new();
+    for entry in entries.iter() {
+        for (key, _) in entry.keys.iter() {
+            keys.insert(kobo::normalize_key(key, lang));
+        }
+    }
+
+    println!("Dry run, so no dictionary was written.");
+    println!("    Entries: {}", entries.len());
+    println!("    Look-up keys: {}", keys.len());
+
+    if let Some(size) = writer.size(entries) {
+        println!("    Size: {:.1} MB", size? as f64 / 1_000_000.0);
+    }
+
+    Ok(())
+}
+
+/// The arguments of the build subcommand that are input files (other than
+/// --reuse_from), with their flags.
+const INPUT_ARGS: &[(&str, &str)] = &[
+    ("yomichan_dict", "-y"),
+    ("names_dict", "--names_dict"),
+    ("frequency", "--frequency"),
+    ("jmdict", "--jmdict"),
+    ("pitch_accent", "--pitch_accent"),
+    ("csv", "--csv"),
+    ("kobo_merge", "--kobo_merge"),
+    ("config", "--config"),
+    ("css", "--css"),
+    ("header_template", "--header_template"),
+    ("inflection_rules", "--inflection_rules"),
+    ("exclude_words", "--exclude_words"),
+    ("only_words", "--only_words"),
+    ("project", "--project"),
+];
+
+/// Returns an error if the output path looks like it was meant to be
+/// an input, e.g. `kobo_jp_dict 明鏡国語辞典.zip` without the `-y`, or if
+/// it's an existing file and --force isn't given, since the file would be
+/// overwritten.
+fn check_output_path(matches: &clap::ArgMatches) -> Result<(), Error> {
+    let output = matches.value_of("OUTPUT").unwrap();
+    let output_path = std::path::Path::new(output);
+    if !output_path.is_file() {
+        return Ok(());
+    }
+
+    // Any of the input files, except for --reuse_from, which is meant to
+    // be the output path.
+    let output_canonical = std::fs::canonicalize(output_path).ok();
+    for (arg_name, flag) in INPUT_ARGS {
+        for arg in matches.values_of(arg_name).into_iter().flatten() {
+            let path = match *arg_name {
+                "yomichan_dict" | "names_dict" => parse_dict_arg(arg).0,
+                _ => arg,
+            };
+            if output_canonical.is_some() && std::fs::canonicalize(path).ok() == output_canonical {
+                return Err(Error::usage(format!(
+                    "the output file \"{}\" is also given as an input with {}, and would be overwritten.  Please give a different output path.",
+                    output, flag
+                )));
+            }
+        }
+    }
+
+    // A Yomichan dictionary.
+    let is_yomichan = archive::open(output_path)
+        .and_then(|mut zip| archive::find_member(&mut zip, "index.json"))
+        .map(|index| index.is_some())
+        .unwrap_or(false);
+    if is_yomichan {
+        // If a Yomichan dictionary was given that doesn't exist, it's
+        // probably the intended output path.
+        let missing: Vec<_> = matches
+            .values_of("yomichan_dict")
+            .into_iter()
+            .flatten()
+            .filter(|arg| !std::path::Path::new(parse_dict_arg(arg).0).exists())
+            .collect();
+        let suggestion = match missing[..] {
+            [missing] => format!("-y \"{}\" \"{}\"", output, missing),
+            _ => format!("-y \"{}\" dicthtml-ja.zip", output),
+        };
+        return Err(Error::usage(format!(
+            "the output file \"{}\" is a Yomichan dictionary, and would be overwritten.  Yomichan dictionaries are passed with -y, and the output path goes last, e.g.:\n    kobo_jp_dict build {}",
+            output, suggestion
+        )));
+    }
+
+    // Any other file.  Rebuilding a dictionary in place with
+    // --reuse_from is fine though.
+    let is_reused = output_canonical.is_some()
+        && matches
+            .value_of("reuse_from")
+            .and_then(|path| std::fs::canonicalize(path).ok())
+            == output_canonical;
+    if !matches.is_present("dry_run") && !is_reused {
+        check_overwrite(matches, output_path)?;
+    }
+    Ok(())
+}
+
+/// Returns an error if the output file already exists and --force isn't
+/// given.
+fn check_overwrite(matches: &clap::ArgMatches, output_path: &std::path::Path) -> Result<(), Error> {
+    if output_path.exists() && !matches.is_present("force") {
+        return Err(Error::usage(format!(
+            "the output file \"{}\" already exists.  Use --force to overwrite it.",
+            output_path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Writes a file by writing it to a temporary file next to it, and then
+/// renaming that over it.  That way a build that fails or is interrupted
+/// never leaves a partial file in place of a working one.
+fn write_atomically<F, E>(path: &std::path::Path, write: F) -> Result<(), E>
+where
+    F: FnOnce(&std::path::Path) -> Result<(), E>,
+    E: From<io::Error>,
+{
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let temp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+    let result = write(&temp_path).and_then(|_| Ok(std::fs::rename(&temp_path, path)?));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Handles the `verify` subcommand.
+fn verify_dictionary(matches: &clap::ArgMatches) -> Result<(), Error> {
+    check_dictionary(std::path::Path::new(
+        matches.value_of("DICTIONARY").unwrap(),
+    ))
+}
+
+/// Checks that a Kobo dictionary file is complete and readable, and
+/// returns an error listing the problems if it isn't.
+fn check_dictionary(path: &std::path::Path) -> Result<(), Error> {
+    info!("Verifying dictionary...");
+    let problems = kobo::verify_dictionary(path)
+        .context(|| format!("failed to read dictionary '{}'", path.display()))?;
+    if !problems.is_empty() {
+        let mut list: String = problems
+            .iter()
+            .take(20)
+            .map(|problem| format!("\n    {}", problem))
+            .collect();
+        if problems.len() > 20 {
+            list += &format!("\n    ...and {} more.", problems.len() - 20);
+        }
+        return Err(Error::verification(format!(
+            "verification found {} problems:{}",
+            problems.len(),
+            list
+        )));
+    }
+    info!("Dictionary verified successfully.");
+    Ok(())
+}
+
+/// Handles the `lookup` subcommand.
+fn lookup_word(matches: &clap::ArgMatches) -> Result<(), Error> {
+    let dict_path = std::path::Path::new(matches.value_of("DICTIONARY").unwrap());
+    let word = matches.value_of("WORD").unwrap();
+
+    // The Kobo knows the dictionary's language from its filename, and
+    // dictionaries without one are English.
+    let file_name = dict_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let lang = install::dict_language(&file_name).unwrap_or("en");
+
+    let lookup = kobo_ja::lookup(dict_path, word, lang)
+        .context(|| format!("failed to read Kobo dictionary '{}'", dict_path.display()))?;
+    if lookup.key != word {
+        println!("Looking up \"{}\" as \"{}\".", word, lookup.key);
+    }
+    if !lookup.in_index {
+        let mut message = format!(
+            "\"{}\" isn't in the dictionary's words index, so it can't be found.",
+            lookup.key
+        );
+        if !lookup.similar_keys.is_empty() {
+            message += &format!("\nSimilar keys: {}", lookup.similar_keys.join(", "));
+        }
+        return Err(Error::failed(message));
+    }
+    if lookup.entries.is_empty() {
+        return Err(Error::failed(format!(
+            "\"{}\" is in the words index, but has no entries in \"{}.html\", so nothing is shown.",
+            lookup.key, lookup.prefix
+        )));
+    }
+
+    for entry in lookup.entries.iter() {
+        println!("[{}.html] {}", lookup.prefix, entry.key);
+        if !entry.variants.is_empty() {
+            println!("Variants: {}", entry.variants.join(", "));
+        }
+        println!("{}\n", html::to_text(&entry.definition));
+    }
+    Ok(())
+}
+
+/// Handles the `validate` subcommand.
+fn validate_dictionary(matches: &clap::ArgMatches) -> Result<(), Error> {
+    let path = std::path::Path::new(matches.value_of("DICTIONARY").unwrap());
+    let report = yomichan::validate(path)?;
+
+    println!("Title: {}", report.title);
+    if !report.revision.is_empty() {
+        println!("Revision: {}", report.revision);
+    }
+    println!("Banks:");
+    for (name, rows, malformed) in report.banks.iter() {
+        if *malformed > 0 {
+            println!("    {}: {} rows ({} malformed)", name, rows, malformed);
+        } else {
+            println!("    {}: {} rows", name, rows);
+        }
+    }
+
+    let malformed: usize = report.banks.iter().map(|(_, _, m)| m).sum();
+    if malformed > 0 {
+        warning!("{} malformed rows, which will be skipped.", malformed);
+    }
+    if report.empty_definitions > 0 {
+        let examples: Vec<_> = report
+            .empty_definition_examples
+            .iter()
+            .map(|(writing, reading)| format!("{} ({})", writing, reading))
+            .collect();
+        warning!(
+            "{} terms have no definition text, e.g. {}.",
+            report.empty_definitions,
+            examples.join(", ")
+        );
+    }
+    if report.structured_content > 0 {
+        println!(
+            "Note: {} definitions use structured content, which is converted to plain text.",
+            report.structured_content
+        );
+    }
+    if report.images > 0 {
+        println!(
+            "Note: {} definitions are images, which are only included with --embed_images.",
+            report.images
+        );
+    }
+    if !report.unknown_rules.is_empty() {
+        println!("Note: terms with these inflection rules won't be conjugated:");
+        for (rule, count) in report.unknown_rules.iter() {
+            println!("    {} ({} times)", rule, count);
+        }
+    }
+    if !report.undefined_tags.is_empty() {
+        println!("Note: these tags are used, but not defined in a tag bank:");
+        for (tag, count) in report.undefined_tags.iter() {
+            println!("    {} ({} times)", tag, count);
+        }
+    }
+
+    if !report.problems.is_empty() {
+        let list: String = report
+            .problems
+            .iter()
+            .map(|problem| format!("\n    {}", problem))
+            .collect();
+        return Err(Error::verification(format!(
+            "the dictionary can't be converted:{}",
+            list
+        )));
+    }
+    println!("The dictionary can be converted.");
+    Ok(())
+}
+
+/// Reads a file with one word per line, for --exclude_words and
+/// --only_words.
+fn read_word_list(path: &str, lang: &str) -> Result<std::collections::HashSet<String>, Error> {
+    let text =
+        std::fs::read_to_string(path).context(|| format!("failed to read word list '{}'", path))?;
+    Ok(text
+        .trim_start_matches('\u{feff}')
+        .lines()
+        .map(|line| kobo::normalize_key(line, lang))
+        .filter(|word| !word.is_empty())
+        .collect())
+}
+
+/// Handles the `inspect` subcommand.
+fn inspect_dictionary(matches: &clap::ArgMatches) -> Result<(), Error> {
+    let dict_path = std::path::Path::new(matches.value_of("DICTIONARY").unwrap());
+    let mut prefixes = kobo_ja::parse_prefixes(dict_path)
+        .context(|| format!("failed to read Kobo dictionary '{}'", dict_path.display()))?;
+    prefixes.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // Dump a single entry.
+    if let Some(key) = matches.value_of("entry") {
+        let mut found = false;
+        for (prefix, entries) in prefixes.iter() {
+            for entry in entries.iter() {
+                if entry.key == key || entry.variants.iter().any(|v| v == key) {
+                    found = true;
+                    println!("[{}.html] {}", prefix, entry.key);
+                    if !entry.variants.is_empty() {
+                        println!("Variants: {}", entry.variants.join(", "));
+                    }
+                    println!("{}\n", entry.definition);
+                }
+            }
+        }
+        if !found {
+            return Err(Error::failed(format!("no entries found for \"{}\".", key)));
+        }
+        return Ok(());
+    }
+
+    // List the dictionary's contents.
+    let samples = match matches.value_of("samples").unwrap().parse::<usize>() {
+        Ok(n) => n,
+        Err(_) => return Err(Error::usage("--samples must be a number.")),
+    };
+    for (prefix, entries) in prefixes.iter() {
+        let sample_keys: Vec<&str> = entries
+            .iter()
+            .take(samples)
+            .map(|e| e.key.as_str())
+            .collect();
+        println!(
+            "{}.html\t{} entries\t{}",
+            prefix,
+            entries.len(),
+            sample_keys.join(", ")
+        );
+    }
+
+    println!();
+    println!("File: {}", dict_path.display());
+    println!("Prefix files: {}", prefixes.len());
+    println!(
+        "Entries: {}",
+        prefixes.iter().map(|(_, e)| e.len()).sum::<usize>()
+    );
+    match kobo_ja::parse_words_original(dict_path)
+        .context(|| format!("failed to read Kobo dictionary '{}'", dict_path.display()))?
+    {
+        Some(words) => println!("Keys in words.original: {}", words.len()),
+        None => println!("Keys in words.original: (no words.original)"),
+    }
+
+    Ok(())
+}
+
+/// Finds the best (lowest) frequency rank of any of the word's forms.
+fn frequency_rank(frequencies: &HashMap<(String, String), u32>, entry: &WordEntry) -> Option<u32> {
+    let mut forms: Vec<&String> = entry.writings.iter().collect();
+    if entry.writings.is_empty() || entry.usually_kana {
+        forms.extend(entry.readings.iter());
+    }
+
+    let no_reading = String::new();
+    let mut best: Option<u32> = None;
+    for form in forms {
+        for reading in entry.readings.iter().chain(std::iter::once(&no_reading)) {
+            if let Some(rank) = frequencies.get(&(form.clone(), reading.clone())) {
+                best = Some(best.map_or(*rank, |b| b.min(*rank)));
+            }
+        }
+    }
+    best
+}
+
+/// The maximum number of definitions to list from each dictionary, from
+/// `--max_defs`.
+struct DefinitionLimits {
+    default: Option<usize>,
+    per_dict: HashMap<String, usize>, // Normalized dictionary title -> limit
+}
+
+impl DefinitionLimits {
+    fn get(&self, dict_name: &str) -> Option<usize> {
+        self.per_dict.get(dict_name).copied().or(self.default)
+    }
+}
+
+/// Parses the `--max_defs` arguments, which are either "N" or "TITLE=N".
+fn parse_max_defs<'a>(args: impl Iterator<Item = &'a str>) -> Result<DefinitionLimits, Error> {
+    let mut limits = DefinitionLimits {
+        default: None,
+        per_dict: HashMap::new(),
+    };
+    for arg in args {
+        let (title, n) = match arg.rfind('=') {
+            Some(i) => (Some(&arg[..i]), &arg[(i + 1)..]),
+            None => (None, arg),
+        };
+        let n = match n.trim().parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => return Err(Error::usage(format!("invalid --max_defs \"{}\".", arg))),
+        };
+        match title {
+            Some(title) => {
+                limits.per_dict.insert(normalize_dict_title(title), n);
+            }
+            None => limits.default = Some(n),
+        }
+    }
+    Ok(limits)
+}
+
+/// The names shown above each dictionary's definitions, from `--dict_title`
+/// and the config file.  Dictionaries are shown by their title by default.
+struct DictLabels {
+    per_dict: HashMap<String, Option<String>>, // Normalized dictionary title -> label
+}
+
+impl DictLabels {
+    /// Builds the labels from the config file and the `--dict_title`
+    /// arguments, which are "TITLE=NAME".  The latter take precedence.
+    fn new<'a>(config: &Config, args: impl Iterator<Item = &'a str>) -> Result<DictLabels, Error> {
+        let mut labels = DictLabels {
+            per_dict: config.labels(),
+        };
+        for arg in args {
+            let (title, name) = match arg.split_once('=') {
+                Some((title, name)) if !title.trim().is_empty() => (title, name.trim()),
+                _ => return Err(Error::usage(format!("invalid --dict_title \"{}\".", arg))),
+            };
+            labels.per_dict.insert(
+                normalize_dict_title(title),
+                if name.is_empty() {
+                    None
+                } else {
+                    Some(name.into())
+                },
+            );
+        }
+        Ok(labels)
+    }
+
+    /// The label of the dictionary with the given (normalized) title, or
+    /// `None` if it's hidden.  `default` is used if no label is set.
+    fn get<'a>(&'a self, dict_name: &str, default: &'a str) -> Option<&'a str> {
+        match self.per_dict.get(dict_name) {
+            Some(label) => label.as_deref(),
+            None => Some(default),
+        }
+    }
+}
+
+/// Which kinds of content to take from a Yomichan dictionary.
+struct DictContent {
+    terms: bool,
+    names: bool,
+    kanji: bool,
+}
+
+/// Splits a `--yomichan` argument into the dictionary path and the content
+/// to take from it, e.g. "kanjidic.zip:kanji".  A suffix that isn't a list
+/// of content types is treated as part of the path.
+fn parse_dict_arg(arg: &str) -> (&str, DictContent) {
+    if let Some(i) = arg.rfind(':') {
+        let (path, list) = (&arg[..i], &arg[(i + 1)..]);
+        let mut content = DictContent {
+            terms: false,
+            names: false,
+            kanji: false,
+        };
+        let mut valid = !path.is_empty();
+        for item in list.split(',') {
+            match item.trim() {
+                "terms" => content.terms = true,
+                "names" => content.names = true,
+                "kanji" => content.kanji = true,
+                _ => valid = false,
+            }
+        }
+        if valid {
+            return (path, content);
+        }
+    }
+
+    (
+        arg,
+        DictContent {
+            terms: true,
+            names: true,
+            kanji: true,
+        },
+    )
+}
+
+/// Normalizes a dictionary title the same way as the titles of parsed
+/// Yomichan dictionaries.
+fn normalize_dict_title(title: &str) -> String {
+    title
+        .to_lowercase()
+        .split('(')
+        .next()
+        .unwrap()
+        .trim()
+        .into()
+}
+
+/// Handles the `export` subcommand.
+fn export_dictionary(matches: &clap::ArgMatches) -> Result<(), Error> {
+    let dict_path = std::path::Path::new(matches.value_of("DICTIONARY").unwrap());
+    let output_path = std::path::Path::new(matches.value_of("OUTPUT").unwrap());
+    let title = match matches.value_of("title") {
+        Some(title) => title.to_string(),
+        None => dict_path
+            .file_stem()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+
+    check_overwrite(matches, output_path)?;
+
+    info!("Reading {}...", dict_path.display());
+    let entries: Vec<(Vec<String>, String)> = kobo_ja::parse(dict_path)
+        .context(|| format!("failed to read Kobo dictionary '{}'", dict_path.display()))?
+        .drain(..)
+        .filter_map(|entry| {
+            let text = html::to_text(&entry.definition);
+            if text.is_empty() {
+                return None;
+            }
+            let mut words = vec![entry.key];
+            words.extend(entry.variants);
+            Some((words, text))
+        })
+        .collect();
+
+    info!(
+        "Writing {} entries to {}...",
+        entries.len(),
+        output_path.display()
+    );
+    write_atomically(output_path, |path| {
+        yomichan::write_dictionary(&entries, &title, path)
+    })
+    .context(|| {
+        format!(
+            "failed to write Yomichan dictionary '{}'",
+            output_path.display()
+        )
+    })
+}
+
+/// The `--data_dir` argument, shared by the `build` and `fetch`
+/// subcommands.
+fn data_dir_arg() -> clap::Arg<'static> {
+    clap::Arg::new("data_dir")
+        .long("data_dir")
+        .help("The directory that `fetch` downloads data to, and that builds use it from.  Defaults to a \"kobo_jp_dict\" directory in the platform's usual place for application data.")
+        .value_name("PATH")
+        .takes_value(true)
+}
 
-            if pitch_accent.is_some() || !yomi_term_entries.is_empty() {
-                let mut entry_text: String = "<hr/>".into();
+/// The data directory given with `--data_dir`, or the default one.
+fn data_dir(matches: &clap::ArgMatches) -> std::path::PathBuf {
+    matches
+        .value_of("data_dir")
+        .map(|path| path.into())
+        .unwrap_or_else(fetch::default_data_dir)
+}
 
-                // Add header and definition to the entry text.
-                entry_text.push_str(&generate_header_text(
-                    matches.is_present("katakana_pronunciation"),
-                    lang_mode,
-                    &kana,
-                    pitch_accent,
-                    &jm_entry,
-                ));
-                entry_text.push_str(&generate_definition_text(yomi_term_entries));
+/// Handles the `fetch` subcommand.
+fn fetch_data(matches: &clap::ArgMatches) -> Result<(), Error> {
+    let name = matches.value_of("SOURCE").unwrap();
+    let source = fetch::SOURCES
+        .iter()
+        .find(|source| source.name == name)
+        .unwrap();
+    info!("Downloading {}...", source.url);
+    let (path, hash) = fetch::fetch(source, &data_dir(matches))?;
+    info!("Saved to {} (md5 {}).", path.display(), hash);
+    info!("Builds will use it in place of the bundled data.  Delete it to go back to the bundled data.");
+    Ok(())
+}
 
-                // Add to the entry list.
-                entries.push(kobo::Entry {
-                    keys: generate_lookup_keys(jm_entry),
-                    definition: entry_text,
-                });
+/// Handles the `install` subcommand.
+fn install_dictionary(matches: &clap::ArgMatches) -> Result<(), Error> {
+    let dict_path = std::path::Path::new(matches.value_of("DICTIONARY").unwrap());
+    let name = match matches.value_of("name") {
+        Some(name) => name.to_string(),
+        None => dict_path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+    let lang = match install::dict_language(&name) {
+        Some(lang) => lang,
+        None => {
+            return Err(Error::usage(format!(
+                "\"{}\" isn't a valid Kobo dictionary filename.  Use --name to specify one, such as \"dicthtml-ja-en.zip\" or \"dicthtml-ja.zip\".",
+                name
+            )))
+        }
+    };
+
+    let device = match matches.value_of("device") {
+        Some(path) => path.into(),
+        None => {
+            let mut devices = install::find_devices();
+            match devices.len() {
+                0 => {
+                    return Err(Error::failed("no connected Kobo device found.  Make sure it's connected and mounted, or use --device to specify its location."))
+                }
+                1 => devices.remove(0),
+                _ => {
+                    let list: String = devices
+                        .iter()
+                        .map(|device| format!("\n    {}", device.display()))
+                        .collect();
+                    return Err(Error::usage(format!(
+                        "multiple Kobo devices found.  Use --device to specify one of:{}",
+                        list
+                    )));
+                }
             }
         }
-    }
+    };
 
-    // Name entries.
-    for ((writing, _reading), items) in yomi_name_table.iter() {
-        for item in items.iter() {
-            let mut entry_text: String = "<hr/>".into();
-            entry_text.push_str(&generate_name_entry_text(
-                matches.is_present("katakana_pronunciation"),
-                lang_mode,
-                item,
-            ));
-            entries.push(kobo::Entry {
-                keys: vec![(writing.clone(), std::u32::MAX)], // Always sort names last.
-                definition: entry_text,
-            });
+    let dest = install::install(dict_path, &device, &name)
+        .context(|| format!("failed to install dictionary '{}'", dict_path.display()))?;
+    info!("Installed dictionary to {}", dest.display());
+
+    // Older firmware only loads dictionaries in `.kobo/dict` for languages
+    // listed in ExtraLocales.
+    if !dest.parent().unwrap().ends_with("custom-dict") {
+        let update = matches.is_present("update_locales");
+        if install::check_extra_locales(&device, lang, update)
+            .context(|| "failed to check the device's ExtraLocales setting")?
+        {
+            if update {
+                info!(
+                    "\"{}\" is included in the device's ExtraLocales setting.",
+                    lang
+                );
+            }
+        } else {
+            info!(
+                "Note: your device's firmware may require \"{0}\" to be listed in the ExtraLocales setting for the dictionary to show up.  Re-run with --update_locales to add it, or add \"ExtraLocales={0}\" under [ApplicationPreferences] in \".kobo/Kobo/Kobo eReader.conf\" yourself.",
+                lang
+            );
         }
     }
 
-    entries.sort_by_key(|a| a.keys[0].0.len());
-
-    //----------------------------------------------------------------
-    // Write the new dictionary file.
-    println!("Writing dictionary to disk...");
-    kobo::write_dictionary(&entries, std::path::Path::new(output_filename))?;
-
-    return Ok(());
+    Ok(())
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -320,6 +2859,37 @@ lazy_static! {
         m.insert(", irregular", &[", irregular", ", irregular", ""][..]);
         m.insert(", ichidan", &[", ichidan", ", ichidan", "、一段"][..]);
         m.insert(", godan", &[", godan", ", godan", "、五段"][..]);
+        m.insert("wasei", &["wasei", "wasei", "和製"][..]);
+
+        // Conjugation table forms.
+        m.insert("negative", &["negative", "negative", "否定"][..]);
+        m.insert("past", &["past", "past", "過去"][..]);
+        m.insert("te-form", &["te-form", "te-form", "て形"][..]);
+        m.insert("potential", &["potential", "potential", "可能"][..]);
+        m.insert("passive", &["passive", "passive", "受身"][..]);
+        m.insert("causative", &["causative", "causative", "使役"][..]);
+        m.insert("volitional", &["volitional", "volitional", "意志"][..]);
+        m.insert("polite", &["polite", "polite", "丁寧"][..]);
+
+        // Usage labels.
+        m.insert("misc:uk", &["usually kana", "usually kana", "かな書き"][..]);
+        m.insert("misc:col", &["colloquial", "colloquial", "口語"][..]);
+        m.insert("misc:sl", &["slang", "slang", "俗語"][..]);
+        m.insert("misc:vulg", &["vulgar", "vulgar", "卑語"][..]);
+        m.insert("misc:hon", &["honorific", "honorific", "尊敬語"][..]);
+        m.insert("misc:hum", &["humble", "humble", "謙譲語"][..]);
+        m.insert("dial:bra", &["Brazilian", "Brazilian", "ブラジル"][..]);
+        m.insert("dial:hob", &["Hokkaido-ben", "Hokkaido-ben", "北海道弁"][..]);
+        m.insert("dial:ksb", &["Kansai-ben", "Kansai-ben", "関西弁"][..]);
+        m.insert("dial:ktb", &["Kantou-ben", "Kantou-ben", "関東弁"][..]);
+        m.insert("dial:kyb", &["Kyoto-ben", "Kyoto-ben", "京都弁"][..]);
+        m.insert("dial:kyu", &["Kyuushuu-ben", "Kyuushuu-ben", "九州弁"][..]);
+        m.insert("dial:nab", &["Nagano-ben", "Nagano-ben", "長野弁"][..]);
+        m.insert("dial:osb", &["Osaka-ben", "Osaka-ben", "大阪弁"][..]);
+        m.insert("dial:rkb", &["Ryuukyuu-ben", "Ryuukyuu-ben", "琉球方言"][..]);
+        m.insert("dial:thb", &["Touhoku-ben", "Touhoku-ben", "東北弁"][..]);
+        m.insert("dial:tsb", &["Tosa-ben", "Tosa-ben", "土佐弁"][..]);
+        m.insert("dial:tsug", &["Tsugaru-ben", "Tsugaru-ben", "津軽弁"][..]);
 
         m
     };
@@ -329,45 +2899,39 @@ lazy_static! {
 fn generate_header_text(
     use_katakana: bool,
     lang_mode: LangMode,
+    usage_labels: bool,
     kana: &str,
     pitch_accent: Option<&Vec<u32>>,
     jm_entry: &WordEntry,
+    styles: &Styles,
 ) -> String {
-    let mut text = format!(
-        "{}",
-        if use_katakana {
-            hiragana_to_katakana(&kana)
-        } else {
-            katakana_to_hiragana(&kana)
-        }
-    );
+    let reading = if use_katakana {
+        hiragana_to_katakana(&kana)
+    } else {
+        katakana_to_hiragana(&kana)
+    };
 
-    if let Some(accent_list) = pitch_accent {
-        if !accent_list.is_empty() {
-            text.push_str(" ");
-            for a in accent_list.iter() {
-                text.push_str(&format!("[{}]", a));
-            }
-        }
-    }
+    let accent_text = match pitch_accent {
+        Some(accent_list) => styles.render_pitch_accent(&reading, accent_list),
+        None => String::new(),
+    };
 
-    text.push_str(" &nbsp;&nbsp;&mdash; 【");
+    let mut writings_text = String::new();
     let mut first = true;
     if jm_entry.usually_kana || jm_entry.writings.is_empty() {
-        text.push_str(&jm_entry.readings[0]);
+        writings_text.push_str(&html::escape_text(&jm_entry.readings[0]));
         first = false;
     }
     for w in jm_entry.writings.iter() {
         if !first {
-            text.push_str("／");
+            writings_text.push_str("／");
         }
-        text.push_str(&w);
+        writings_text.push_str(&html::escape_text(w));
         first = false;
     }
-    text.push_str("】");
 
-    const WORD_TYPE_START: &'static str =
-        " <span style=\"font-size: 0.8em; font-style: italic; margin-left: 0; white-space: nowrap;\">";
+    let mut text = String::new();
+    let word_type_start = format!(" <span style=\"{}\">", styles.get("word-type"));
     const WORD_TYPE_END: &'static str = "</span>";
     match jm_entry.pos {
         PartOfSpeech::Verb => {
@@ -408,7 +2972,7 @@ fn generate_header_text(
 
             text.push_str(&format!(
                 "{}{}{}{}{}",
-                WORD_TYPE_START,
+                word_type_start,
                 HEADER_TERMS["verb"][lang_mode.idx()],
                 transitive_text,
                 conj_type_text,
@@ -430,41 +2994,468 @@ fn generate_header_text(
 
             text.push_str(&format!(
                 "{}{}{}{}",
-                WORD_TYPE_START, adjective_type_text, irregular_text, WORD_TYPE_END
+                word_type_start, adjective_type_text, irregular_text, WORD_TYPE_END
             ));
         }
 
         _ => {}
     }
 
-    text
+    // The origins of loanwords, e.g. "← Ger: Arbeit".
+    let mut sources: Vec<&jmdict::LoanwordSource> = Vec::new();
+    for source in jm_entry
+        .definitions
+        .iter()
+        .flat_map(|sense| sense.sources.iter())
+    {
+        if !sources.contains(&source) {
+            sources.push(source);
+        }
+    }
+    if !sources.is_empty() {
+        let source_texts: Vec<String> = sources
+            .iter()
+            .map(|source| {
+                let mut lang = source.lang.clone();
+                if let Some(first) = lang.get_mut(0..1) {
+                    first.make_ascii_uppercase();
+                }
+                if source.wasei {
+                    lang = HEADER_TERMS["wasei"][lang_mode.idx()].into();
+                }
+                if source.word.is_empty() {
+                    lang
+                } else {
+                    format!("{}: {}", lang, source.word)
+                }
+            })
+            .collect();
+        let separator = if sources.iter().all(|source| source.partial) {
+            " + "
+        } else {
+            ", "
+        };
+        text.push_str(&format!(
+            "{}← {}{}",
+            word_type_start,
+            html::escape_text(&source_texts.join(separator)),
+            WORD_TYPE_END
+        ));
+    }
+
+    // Usage labels, such as "colloquial" or "Kansai-ben".
+    if usage_labels {
+        let mut labels: Vec<&str> = Vec::new();
+        if jm_entry.usually_kana && !jm_entry.writings.is_empty() {
+            labels.push(HEADER_TERMS["misc:uk"][lang_mode.idx()]);
+        }
+        let mut tags: Vec<&String> = jm_entry.tags.iter().collect();
+        tags.sort();
+        for tag in tags {
+            if tag == "misc:uk" {
+                continue;
+            }
+            if let Some(terms) = HEADER_TERMS.get(tag.as_str()) {
+                labels.push(terms[lang_mode.idx()]);
+            }
+        }
+        if !labels.is_empty() {
+            let separator = if lang_mode == LangMode::Japanese {
+                "、"
+            } else {
+                ", "
+            };
+            text.push_str(&format!(
+                "{}({}){}",
+                word_type_start,
+                labels.join(separator),
+                WORD_TYPE_END
+            ));
+        }
+    }
+
+    styles.render_header(
+        &styles.wrap("reading", &html::escape_text(&reading)),
+        &styles.wrap("pitch-accent", &accent_text),
+        &writings_text,
+        &text,
+    )
 }
 
-/// Generate English definition text from the given JMDict entry.
-fn generate_definition_text(yomi_entries: &[yomichan::TermEntry]) -> String {
-    let mut text = String::new();
+/// Generate definition text from the given Yomichan entries and JMDict
+/// definitions, listing at most `jm_max_defs` of the latter.
+fn generate_definition_text(
+    yomi_entries: &[yomichan::TermEntry],
+    jm_definitions: &[Sense],
+    jm_max_defs: Option<usize>,
+    english_section: bool,
+    dict_labels: &DictLabels,
+    styles: &Styles,
+) -> String {
+    let source_count = yomi_entries.len() + if jm_definitions.is_empty() { 0 } else { 1 };
 
-    text.push_str("<div style=\"margin-top: 0.7em\">");
+    // Render each source's definitions, noting which are in English.
+    let mut sources = Vec::new(); // (html, is_english)
     for entry in yomi_entries.iter() {
-        text.push_str("<p>");
-        if yomi_entries.len() > 1 {
-            text.push_str(&format!("{}:<br/>", entry.dict_name));
+        let mut text: String = "<p>".into();
+        match dict_labels.get(&entry.dict_name, &entry.dict_name) {
+            Some(label) if source_count > 1 => {
+                text.push_str(&format!("{}:<br/>", html::escape_text(label)))
+            }
+            _ => {}
         }
-        text.push_str(&yomichan::definition_to_html(
-            &entry.definitions,
-            entry.definitions.depth(),
-            true,
-        ));
-        text.push_str("</p>");
+        // Mixed-language definitions are split, so that only their
+        // English parts go in the English section.
+        let parts = if english_section {
+            split_by_language(&entry.definitions)
+        } else {
+            vec![(entry.definitions.clone(), false)]
+        };
+        for (definitions, english) in parts {
+            let mut text = text.clone();
+            text.push_str(&yomichan::definition_to_html(
+                &definitions,
+                definitions.depth(),
+                true,
+            ));
+            text.push_str("</p>");
+            sources.push((text, english));
+        }
+    }
+    if !jm_definitions.is_empty() {
+        let mut text: String = "<p>".into();
+        match dict_labels.get(&normalize_dict_title("JMDict"), "JMDict") {
+            Some(label) if source_count > 1 => {
+                text.push_str(&format!("{}:<br/>", html::escape_text(label)))
+            }
+            _ => {}
+        }
+        // The part of speech is only worth labeling when it differs
+        // between senses, since otherwise the header already covers it.
+        // Like in JMDict itself, it's then labeled where it changes.
+        let label_pos = jm_definitions
+            .iter()
+            .any(|sense| !sense.pos_codes().eq(jm_definitions[0].pos_codes()));
+
+        text.push_str("<ol>");
+        let jm_max_defs = jm_max_defs.unwrap_or(jm_definitions.len());
+        for (i, sense) in jm_definitions.iter().enumerate().take(jm_max_defs) {
+            text.push_str("<li>");
+            let pos_changed = i == 0 || !sense.pos_codes().eq(jm_definitions[i - 1].pos_codes());
+            let labels: Vec<&str> = sense
+                .tags
+                .iter()
+                .filter_map(|tag| match tag.split_once(':') {
+                    Some(("pos", code)) if label_pos && pos_changed => Some(code),
+                    Some(("field", code)) | Some(("dial", code)) => Some(code),
+                    // "Usually kana" is already clear from the header.
+                    Some(("misc", code)) if code != "uk" => Some(code),
+                    _ => None,
+                })
+                .collect();
+            if !labels.is_empty() {
+                text.push_str(&format!(
+                    "<span style=\"{}\">({})</span> ",
+                    styles.get("sense-label"),
+                    html::escape_text(&labels.join(", "))
+                ));
+            }
+            text.push_str(&html::escape_text(&sense.text()));
+            if !sense.notes.is_empty() {
+                text.push_str(&format!(
+                    " <i>({})</i>",
+                    html::escape_text(&sense.notes.join("; "))
+                ));
+            }
+            let references: Vec<String> = sense
+                .xrefs
+                .iter()
+                .map(|xref| format!("see {}", format_xref(xref)))
+                .chain(
+                    sense
+                        .antonyms
+                        .iter()
+                        .map(|ant| format!("ant. {}", format_xref(ant))),
+                )
+                .collect();
+            if !references.is_empty() {
+                text.push_str(&format!(
+                    " <span style=\"{}\">({})</span>",
+                    styles.get("sense-label"),
+                    html::escape_text(&references.join("; "))
+                ));
+            }
+            text.push_str("</li>");
+        }
+        if jm_definitions.len() > jm_max_defs {
+            text.push_str("<li>…</li>");
+        }
+        text.push_str("</ol></p>");
+        sources.push((text, english_section));
+    }
+
+    // With `english_section`, English definitions go in a separate section
+    // after the others, unless there aren't any others.
+    let has_english_section = english_section
+        && sources.iter().any(|(_, english)| *english)
+        && sources.iter().any(|(_, english)| !*english);
+
+    let mut text = String::new();
+    text.push_str(&format!("<div style=\"{}\">", styles.get("definitions")));
+    for (source_text, english) in sources.iter() {
+        if !(has_english_section && *english) {
+            text.push_str(source_text);
+        }
+    }
+    if has_english_section {
+        text.push_str(&format!("<div style=\"{}\">【英】", styles.get("english")));
+        for (source_text, english) in sources.iter() {
+            if *english {
+                text.push_str(source_text);
+            }
+        }
+        text.push_str("</div>");
     }
     text.push_str("</div>");
 
     text
 }
 
+/// Formats a JMDict cross-reference, such as "丸・まる・1", for display as
+/// e.g. "丸 (まる)".  The sense number is dropped to keep it short.
+fn format_xref(xref: &str) -> String {
+    let mut parts = xref
+        .split('・')
+        .filter(|part| !part.chars().all(|c| c.is_ascii_digit()));
+    match (parts.next(), parts.next()) {
+        (Some(word), Some(reading)) => format!("{} ({})", word, reading),
+        (Some(word), None) => word.into(),
+        _ => xref.into(),
+    }
+}
+
+/// Generate a table of the basic conjugations of a verb or i-adjective, to
+/// go at the end of its entry.  Returns an empty string for other words.
+fn generate_conjugation_text(lang_mode: LangMode, jm_entry: &WordEntry, styles: &Styles) -> String {
+    let word = if jm_entry.usually_kana || jm_entry.writings.is_empty() {
+        &jm_entry.readings[0]
+    } else {
+        &jm_entry.writings[0]
+    };
+    let table = conjugation::conjugation_table(word, jm_entry.conj);
+    if table.is_empty() {
+        return String::new();
+    }
+
+    // Two forms per row, to keep it short.
+    let mut text = format!("<table style=\"{}\">", styles.get("conjugations"));
+    for row in table.chunks(2) {
+        text.push_str("<tr>");
+        for (form, conjugation) in row.iter() {
+            text.push_str(&format!(
+                "<td><i>{}</i></td><td>{}</td>",
+                HEADER_TERMS[form][lang_mode.idx()],
+                html::escape_text(conjugation)
+            ));
+        }
+        text.push_str("</tr>");
+    }
+    text.push_str("</table>");
+
+    text
+}
+
+/// Like `generate_definition_text()`, but if the text is longer than
+/// `max_size` bytes, fewer definitions are listed from each source until
+/// it fits.  At least the first definition of each source is always kept.
+fn generate_limited_definition_text(
+    yomi_entries: &[yomichan::TermEntry],
+    jm_definitions: &[Sense],
+    jm_max_defs: Option<usize>,
+    max_size: Option<usize>,
+    english_section: bool,
+    dict_labels: &DictLabels,
+    styles: &Styles,
+) -> String {
+    let generate = |yomi_entries: &[yomichan::TermEntry], jm_max_defs| {
+        generate_definition_text(
+            yomi_entries,
+            jm_definitions,
+            jm_max_defs,
+            english_section,
+            dict_labels,
+            styles,
+        )
+    };
+
+    let mut text = generate(yomi_entries, jm_max_defs);
+    let max_size = match max_size {
+        Some(max_size) if text.len() > max_size => max_size,
+        _ => return text,
+    };
+
+    let jm_len = jm_max_defs.map_or(jm_definitions.len(), |n| n.min(jm_definitions.len()));
+    let most_defs = yomi_entries
+        .iter()
+        .map(|entry| entry.definitions.list_len())
+        .fold(jm_len, |a, b| a.max(b));
+    for n in (1..most_defs).rev() {
+        let mut truncated = yomi_entries.to_vec();
+        for entry in truncated.iter_mut() {
+            entry.definitions.truncate(n);
+        }
+        text = generate(&truncated, Some(jm_len.min(n)));
+        if text.len() <= max_size {
+            break;
+        }
+    }
+
+    text
+}
+
+/// Splits Yomichan definitions into their Japanese and English parts, as
+/// (definitions, is_english) pairs.  The items of a definition list are
+/// grouped by language, and anything else is taken as a whole.
+fn split_by_language(definitions: &yomichan::Definition) -> Vec<(yomichan::Definition, bool)> {
+    use yomichan::Definition;
+
+    let is_english_def = |def: &Definition| {
+        is_english(&html::to_text(&yomichan::definition_to_html(
+            def,
+            def.depth(),
+            true,
+        )))
+    };
+
+    // Look within lists that only wrap a single other list, like when
+    // they're displayed.
+    let mut list = definitions;
+    while let Definition::List((header, items)) = list {
+        if header.trim().is_empty() && items.len() == 1 {
+            list = &items[0];
+        } else {
+            break;
+        }
+    }
+    if let Definition::List((header, items)) = list {
+        let (english, japanese): (Vec<Definition>, Vec<Definition>) =
+            items.iter().cloned().partition(|item| is_english_def(item));
+        if !english.is_empty() && !japanese.is_empty() {
+            return vec![
+                (Definition::List((header.clone(), japanese)), false),
+                (Definition::List((String::new(), english)), true),
+            ];
+        }
+    }
+    vec![(definitions.clone(), is_english_def(definitions))]
+}
+
+/// Guesses whether a definition is written in English rather than
+/// Japanese.
+///
+/// The text is split into sentences and lines, and each is judged on its
+/// own, since English definitions often have bits of Japanese and vice
+/// versa.  A segment with any kana is Japanese, and otherwise it's English
+/// if it has more latin words than kanji, so that romaji, abbreviations
+/// like "DNA", and formulas don't make a Japanese gloss look English.
+/// The definition is then English if most of its text is in English
+/// segments.
+fn is_english(text: &str) -> bool {
+    let mut english_len = 0usize;
+    let mut japanese_len = 0usize;
+    for segment in text.split(|c| "\n。．！？!?;；".contains(c)) {
+        let mut words = 0usize;
+        let mut in_word = false;
+        let mut kana = 0usize;
+        let mut kanji = 0usize;
+        for ch in segment.chars() {
+            if ch.is_ascii_alphabetic() {
+                if !in_word {
+                    words += 1;
+                }
+                in_word = true;
+                continue;
+            }
+            in_word = false;
+            if is_kana(ch) {
+                kana += 1;
+            } else if is_kanji(ch) {
+                kanji += 1;
+            }
+        }
+
+        let len = segment.chars().count();
+        if kana > 0 || (kanji > 0 && kanji >= words) {
+            japanese_len += len;
+        } else if words > 0 {
+            english_len += len;
+        }
+    }
+    english_len > japanese_len
+}
+
+/// Creates a stand-in JMDict entry for a Yomichan term entry that doesn't
+/// have a matching JMDict entry, so that it can be rendered and keyed like
+/// the others.
+fn unmatched_word_entry(writing: &str, entry: &yomichan::TermEntry) -> WordEntry {
+    use yomichan::InflectionType;
+    use ConjugationClass::*;
+
+    let reading = if entry.reading.trim().is_empty() {
+        writing.to_string()
+    } else {
+        entry.reading.trim().to_string()
+    };
+
+    let (conj, pos) = match entry.infl {
+        InflectionType::VerbIchidan => (IchidanVerb, PartOfSpeech::Verb),
+        InflectionType::VerbGodan => {
+            let conj = if writing.ends_with("行く") || writing.ends_with("いく") {
+                IkuVerb
+            } else {
+                match writing.chars().last() {
+                    Some('う') => GodanVerbU,
+                    Some('つ') => GodanVerbTsu,
+                    Some('る') => GodanVerbRu,
+                    Some('く') => GodanVerbKu,
+                    Some('ぐ') => GodanVerbGu,
+                    Some('ぬ') => GodanVerbNu,
+                    Some('ぶ') => GodanVerbBu,
+                    Some('む') => GodanVerbMu,
+                    Some('す') => GodanVerbSu,
+                    _ => Other,
+                }
+            };
+            (conj, PartOfSpeech::Verb)
+        }
+        // Yomichan also uses "vs" for nouns that take する, which don't
+        // conjugate themselves.
+        InflectionType::VerbSuru if writing.ends_with("する") => (SuruVerb, PartOfSpeech::Verb),
+        InflectionType::VerbKuru if writing.ends_with("来る") || writing.ends_with("くる") => {
+            (KuruVerb, PartOfSpeech::Verb)
+        }
+        InflectionType::IAdjective => (IAdjective, PartOfSpeech::Adjective),
+        _ => (Other, PartOfSpeech::Unknown),
+    };
+
+    let mut word_entry = WordEntry::new();
+    if !is_all_kana(writing) || writing != reading {
+        word_entry.writings.push(writing.into());
+    }
+    word_entry.readings.push(reading);
+    word_entry.usually_kana = word_entry.writings.is_empty();
+    word_entry.conj = conj;
+    word_entry.pos = pos;
+    word_entry
+}
+
 /// Generates the look-up keys for a JMDict word entry, including
 /// basic conjugations.
-fn generate_lookup_keys(jm_entry: &WordEntry) -> Vec<(String, u32)> {
+fn generate_lookup_keys(
+    jm_entry: &WordEntry,
+    inflection_rules: &InflectionRules,
+    honorific_prefixes: &HashMap<String, Vec<(String, String)>>,
+) -> Vec<(String, u32)> {
     let jm_priority = jm_entry.priority + 256; // Ensure we never reach zero, since that's reserved for Kanji entries.
 
     // Give verbs and i-adjectives a priority boost, so they show up
@@ -483,10 +3474,11 @@ fn generate_lookup_keys(jm_entry: &WordEntry) -> Vec<(String, u32)> {
     let mut end_replace_push = |word: &str, trail: &str, endings: &[&str]| {
         // If a word is usually written in kana, give the kana form a major
         // priority boost.
+        let form_priority = jm_entry.form_priority(word) + 256;
         let priority = if is_all_kana(word) && jm_entry.usually_kana {
-            jm_priority / 8
+            form_priority / 8
         } else {
-            jm_priority
+            form_priority
         } / priority_boost;
 
         // We include the katakana version for all-hiragana
@@ -498,7 +3490,7 @@ fn generate_lookup_keys(jm_entry: &WordEntry) -> Vec<(String, u32)> {
         }
         keys.push((word.into(), priority));
 
-        if trail.len() > 0 && word.len() >= trail.len() && word.ends_with(trail) {
+        if word.ends_with(trail) {
             let stem = {
                 let mut stem: String = word.into();
                 stem.truncate(word.len() - trail.len());
@@ -528,195 +3520,179 @@ fn generate_lookup_keys(jm_entry: &WordEntry) -> Vec<(String, u32)> {
     forms.dedup();
 
     use ConjugationClass::*;
+    let rules = inflection_rules.for_word(jm_entry);
     for word in forms.iter() {
-        match jm_entry.conj {
-            // We include the ～あない ending even though it should be covered by ～あ because
-            // there are some entries for exactly ～あない, and they prevent the verb entries
-            // from showing up.
-            IchidanVerb => {
-                end_replace_push(word, "る", &["", "ない", "られ", "させ", "ろ", "て", "た"]);
-            }
-
-            GodanVerbU => {
-                end_replace_push(
-                    word,
-                    "う",
-                    &["わない", "わ", "い", "え", "お", "って", "った"],
-                );
-            }
+        if rules.is_empty() {
+            end_replace_push(word, "", &[]);
+        }
+        for rule in rules.iter() {
+            let endings: Vec<&str> = rule.endings.iter().map(|e| e.as_str()).collect();
+            end_replace_push(word, &rule.trail, &endings);
+        }
+    }
 
-            GodanVerbTsu => {
-                end_replace_push(
-                    word,
-                    "つ",
-                    &["たない", "た", "ち", "て", "と", "って", "った"],
-                );
+    // Counters with numbers attached, e.g. 三匹.  These come after any
+    // entries of their own, like 一本.
+    if jm_entry.tags.contains("pos:ctr") {
+        for writing in jm_entry.writings.iter() {
+            for numeral in counters::COUNTER_NUMERALS.iter() {
+                keys.push((format!("{}{}", numeral, writing), jm_priority + 100000));
             }
+        }
+    }
 
-            GodanVerbRu => {
-                end_replace_push(
-                    word,
-                    "る",
-                    &["らない", "ら", "り", "れ", "ろ", "って", "った"],
-                );
+    // The word with honorific prefixes, e.g. お土産, if it takes them.  Like
+    // counters, these come after any entries of their own.
+    for form in forms.iter() {
+        for (prefix, reading) in honorific_prefixes.get(*form).into_iter().flatten() {
+            // Kana forms are too ambiguous unless the word is usually
+            // written in kana.
+            if !jm_entry.readings.contains(reading) || (is_all_kana(form) && !jm_entry.usually_kana)
+            {
+                continue;
             }
-
-            GodanVerbKu => {
-                end_replace_push(
-                    word,
-                    "く",
-                    &["かない", "か", "き", "け", "こ", "いて", "いた"],
-                );
+            let prefixed = format!("{}{}", prefix, form);
+            if !forms.iter().any(|f| **f == prefixed) {
+                if is_all_kana(&prefixed) {
+                    keys.push((hiragana_to_katakana(&prefixed), jm_priority + 100000));
+                }
+                keys.push((prefixed, jm_priority + 100000));
             }
+        }
+    }
 
-            GodanVerbGu => {
-                end_replace_push(
-                    word,
-                    "ぐ",
-                    &["がない", "が", "ぎ", "げ", "ご", "いで", "いだ"],
-                );
+    // Common spelling variants of katakana words, e.g. バイオリン for
+    // ヴァイオリン.  Like counters, these come after any entries of their
+    // own.
+    for form in forms.iter() {
+        for variant in katakana_variants(form) {
+            if !forms.iter().any(|f| **f == variant) {
+                keys.push((variant, jm_priority + 100000));
             }
+        }
+    }
 
-            GodanVerbNu => {
-                end_replace_push(
-                    word,
-                    "ぬ",
-                    &["なない", "な", "に", "ね", "の", "んで", "んだ"],
-                );
-            }
+    // Words with the iteration mark 々 can also be written out in full, and
+    // vice versa, e.g. 人々 and 人人.
+    let variants: Vec<_> = keys
+        .iter()
+        .filter_map(|(key, priority)| iteration_mark_variant(key).map(|v| (v, *priority)))
+        .collect();
+    keys.extend(variants);
 
-            GodanVerbBu => {
-                end_replace_push(
-                    word,
-                    "ぶ",
-                    &["ばない", "ば", "び", "べ", "ぼ", "んで", "んだ"],
-                );
-            }
+    keys.sort_by_key(|a| (a.1, a.0.len(), a.0.clone()));
+    keys.dedup();
+    keys
+}
 
-            GodanVerbMu => {
-                end_replace_push(
-                    word,
-                    "む",
-                    &["まない", "ま", "み", "め", "も", "んで", "んだ"],
-                );
-            }
+/// Returns common alternate spellings of a katakana word: with or without
+/// a final long vowel mark (コンピューター / コンピュータ), with ヴ written
+/// as the corresponding バ-row kana, and with the small vowel kana after ウ
+/// written full-size (ウィスキー / ウイスキー).
+fn katakana_variants(word: &str) -> Vec<String> {
+    let chars: Vec<char> = word.chars().collect();
+    if chars.len() < 3
+        || !chars
+            .iter()
+            .all(|c| (is_kana(*c) && !is_hiragana(*c)) || *c == 'ー')
+    {
+        return Vec::new();
+    }
 
-            GodanVerbSu => {
-                end_replace_push(
-                    word,
-                    "す",
-                    &["さない", "さ", "し", "せ", "そ", "して", "した"],
-                );
-            }
+    let mut variants = Vec::new();
 
-            IkuVerb => {
-                end_replace_push(
-                    word,
-                    "く",
-                    &["かない", "か", "き", "け", "こ", "って", "った"],
-                );
-            }
+    // Final long vowel mark, which is often dropped in technical terms.
+    if chars[chars.len() - 1] == 'ー' {
+        variants.push(chars[..(chars.len() - 1)].iter().collect());
+    } else if "タダラサザカガ".contains(chars[chars.len() - 1]) {
+        variants.push(format!("{}ー", word));
+    }
 
-            KuruVerb => {
-                end_replace_push(
-                    word,
-                    "くる",
-                    &[
-                        "こない",
-                        "こなかった",
-                        "こなくて",
-                        "きて",
-                        "きた",
-                        "こられ",
-                        "こさせ",
-                        "こい",
-                        "きます",
-                        "きません",
-                        "きました",
-                    ],
-                );
-                end_replace_push(
-                    word,
-                    "来る",
-                    &[
-                        "来ない",
-                        "来なかった",
-                        "来なくて",
-                        "来て",
-                        "来た",
-                        "来られ",
-                        "来させ",
-                        "来い",
-                        "来ます",
-                        "来ません",
-                        "来ました",
-                    ],
-                );
-            }
+    // ヴ.
+    if word.contains('ヴ') {
+        variants.push(
+            word.replace("ヴァ", "バ")
+                .replace("ヴィ", "ビ")
+                .replace("ヴェ", "ベ")
+                .replace("ヴォ", "ボ")
+                .replace("ヴ", "ブ"),
+        );
+    }
 
-            SuruVerb => {
-                end_replace_push(
-                    word,
-                    "する",
-                    &[
-                        "しな",
-                        "しろ",
-                        "させ",
-                        "され",
-                        "でき",
-                        "した",
-                        "して",
-                        "しない",
-                        "します",
-                        "しません",
-                    ],
-                );
-            }
+    // Small vowel kana after ウ.
+    if word.contains("ウィ") || word.contains("ウェ") || word.contains("ウォ") {
+        variants.push(
+            word.replace("ウィ", "ウイ")
+                .replace("ウェ", "ウエ")
+                .replace("ウォ", "ウオ"),
+        );
+    }
 
-            IAdjective => {
-                end_replace_push(word, "い", &["", "く", "け", "かった", "かって"]);
-            }
+    variants
+}
 
-            _ => {
-                end_replace_push(word, "", &[]);
+/// Returns the word with its iteration marks (々) written out as the
+/// repeated kanji, or if it doesn't have any, with its repeated kanji
+/// written as iteration marks.  Returns `None` if neither applies.
+fn iteration_mark_variant(word: &str) -> Option<String> {
+    let mut variant = String::new();
+    let mut prev: Option<char> = None;
+    if word.contains('々') {
+        for ch in word.chars() {
+            let ch = match (ch, prev) {
+                ('々', Some(p)) => p,
+                _ => ch,
+            };
+            variant.push(ch);
+            prev = Some(ch);
+        }
+    } else {
+        for ch in word.chars() {
+            if Some(ch) == prev && is_kanji(ch) {
+                variant.push('々');
+            } else {
+                variant.push(ch);
             }
-        };
+            prev = Some(ch);
+        }
     }
 
-    keys.sort_by_key(|a| (a.1, a.0.len(), a.0.clone()));
-    keys.dedup();
-    keys
+    if variant != word {
+        Some(variant)
+    } else {
+        None
+    }
 }
 
 fn generate_name_entry_text(
     use_katakana: bool,
     lang_mode: LangMode,
     entry: &yomichan::TermEntry,
+    styles: &Styles,
 ) -> String {
     let mut text = String::new();
 
     if !entry.reading.trim().is_empty() {
-        text.push_str(&if use_katakana {
+        let reading = if use_katakana {
             hiragana_to_katakana(&entry.reading)
         } else {
             katakana_to_hiragana(&entry.reading)
-        });
+        };
+        text.push_str(&styles.wrap("reading", &html::escape_text(&reading)));
         text.push_str(" &nbsp;&nbsp;&mdash; ");
     }
 
     text.push_str("【");
-    text.push_str(&entry.writing);
+    text.push_str(&html::escape_text(&entry.writing));
     text.push_str("】");
 
-    const WORD_TYPE_START: &'static str =
-        " <span style=\"font-size: 0.8em; font-style: italic; margin-left: 0; white-space: nowrap;\">";
     const WORD_TYPE_END: &'static str = "</span>";
-    text.push_str(WORD_TYPE_START);
+    text.push_str(&format!(" <span style=\"{}\">", styles.get("word-type")));
     text.push_str(HEADER_TERMS["name"][lang_mode.idx()]);
     if !entry.tags.is_empty() {
         text.push_str(": ");
         for tag in entry.tags.iter() {
-            text.push_str(tag);
+            text.push_str(&html::escape_text(tag));
             text.push_str(", ");
         }
         text.pop();
@@ -735,15 +3711,20 @@ fn generate_name_entry_text(
     text
 }
 
-fn generate_kanji_entry_text(entry: &yomichan::KanjiEntry) -> String {
+fn generate_kanji_entry_text(entry: &yomichan::KanjiEntry, styles: &Styles) -> String {
     let mut text = String::new();
 
-    text.push_str("<p style=\"margin-left: 2.5em; margin-bottom: 1.0em; text-indent: -2.5em;\"><span style=\"font-size: 2.0em;\">");
-    text.push_str(&entry.kanji);
+    text.push_str(&format!(
+        "<p style=\"{}\"><span style=\"{}\">",
+        styles.get("kanji-heading"),
+        styles.get("kanji")
+    ));
+    text.push_str(&html::escape_text(&entry.kanji));
+    text.push_str("</span>");
     if !entry.meanings.is_empty() {
-        text.push_str("</span>　");
+        text.push('　');
         for meaning in entry.meanings.iter() {
-            text.push_str(meaning);
+            text.push_str(&html::escape_text(meaning));
             text.push_str(", ");
         }
         text.pop();
@@ -752,9 +3733,12 @@ fn generate_kanji_entry_text(entry: &yomichan::KanjiEntry) -> String {
     text.push_str("</p>");
 
     if !entry.onyomi.is_empty() {
-        text.push_str("<p style=\"margin-left: 2.5em; text-indent: -2.5em;\">音:　");
+        text.push_str(&format!(
+            "<p style=\"{}\">音:　",
+            styles.get("kanji-reading")
+        ));
         for onyomi in entry.onyomi.iter() {
-            text.push_str(onyomi);
+            text.push_str(&html::escape_text(onyomi));
             text.push_str("／");
         }
         text.pop();
@@ -762,9 +3746,12 @@ fn generate_kanji_entry_text(entry: &yomichan::KanjiEntry) -> String {
     }
 
     if !entry.kunyomi.is_empty() {
-        text.push_str("<p style=\"margin-left: 2.5em; text-indent: -2.5em;\">訓:　");
+        text.push_str(&format!(
+            "<p style=\"{}\">訓:　",
+            styles.get("kanji-reading")
+        ));
         for kunyomi in entry.kunyomi.iter() {
-            text.push_str(kunyomi);
+            text.push_str(&html::escape_text(kunyomi));
             text.push_str("／");
         }
         text.pop();
@@ -799,6 +3786,14 @@ fn is_kana(ch: char) -> bool {
     || (c >= 0x30fd && c <= 0x30fe) // Iterating marks.
 }
 
+fn is_kanji(ch: char) -> bool {
+    ('\u{3400}'..='\u{4dbf}').contains(&ch) // CJK Unified Ideographs Extension A.
+    || ('\u{4e00}'..='\u{9fff}').contains(&ch) // CJK Unified Ideographs.
+    || ('\u{f900}'..='\u{faff}').contains(&ch) // CJK Compatibility Ideographs.
+    || ('\u{20000}'..='\u{2ffff}').contains(&ch) // Supplementary ideographic planes.
+    || ch == '\u{3005}' // Iteration mark (々).
+}
+
 fn is_hiragana(ch: char) -> bool {
     let c = ch as u32;
 
@@ -0,0 +1,1136 @@
+//! A pure-Rust builder for MARISA tries.
+//!
+//! Kobo e-readers use a MARISA trie (https://github.com/s-yata/marisa-trie)
+//! as the word index of their dictionaries.  This produces the same
+//! serialized format as `marisa-build` with its default settings (three
+//! nested tries, text tail, normal cache, weight-ordered nodes), so that
+//! we don't need the external tool installed.
+//!
+//! The structure closely follows marisa's own `LoudsTrie` implementation,
+//! including its quirks, since the on-disk layout needs to match exactly.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+
+const HEADER: &[u8; 16] = b"We love Marisa.\0";
+
+const NUM_TRIES: usize = 3;
+const TEXT_TAIL: u32 = 0x01000;
+const BINARY_TAIL: u32 = 0x02000;
+const WEIGHT_ORDER: u32 = 0x20000;
+const NORMAL_CACHE: u32 = 0x00200;
+
+const INVALID_EXTRA: u32 = u32::MAX >> 8;
+
+/// Builds a MARISA trie from the given keys and weights, returning the
+/// serialized trie data.
+///
+/// Higher weights indicate more frequently looked-up keys, which affects
+/// node ordering and caching in the trie, but not which keys it contains.
+pub fn build<'a, I>(keys: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = (&'a [u8], f32)>,
+{
+    let mut keys: Vec<Key> = keys
+        .into_iter()
+        .map(|(bytes, weight)| Key {
+            bytes: bytes.into(),
+            weight,
+            id: 0,
+            terminal: 0,
+        })
+        .collect();
+
+    let mut trie = LoudsTrie::new();
+    let mut terminals = Vec::new();
+    trie.build_trie(&mut keys, &mut terminals, 1);
+
+    // Only the top-level trie has terminal flags.
+    let mut pairs: Vec<(u32, u32)> = terminals
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (*t, i as u32))
+        .collect();
+    pairs.sort_unstable();
+    let mut node_id = 0;
+    for (terminal, _) in pairs.iter() {
+        while node_id < *terminal {
+            trie.terminal_flags.push(false);
+            node_id += 1;
+        }
+        if node_id == *terminal {
+            trie.terminal_flags.push(true);
+            node_id += 1;
+        }
+    }
+    while (node_id as usize) < trie.bases.len() {
+        trie.terminal_flags.push(false);
+        node_id += 1;
+    }
+    trie.terminal_flags.push(false);
+    trie.terminal_flags.build_index(false, true);
+
+    let mut data = Vec::new();
+    data.extend_from_slice(HEADER);
+    trie.write(&mut data);
+    data
+}
+
+//----------------------------------------------------------------
+
+struct Key {
+    // The key's bytes, in the order the current trie sees them.  Every
+    // trie after the first one works on reversed strings.
+    bytes: Vec<u8>,
+    weight: f32,
+    id: usize,
+    terminal: u32,
+}
+
+struct Range {
+    begin: usize,
+    end: usize,
+    key_pos: usize,
+}
+
+struct WeightedRange {
+    range: Range,
+    weight: f32,
+}
+
+#[derive(Copy, Clone)]
+struct Cache {
+    parent: u32,
+    child: u32,
+    // Holds the weight during building, and the base/extra link after
+    // `fill_cache()`.
+    link: u32,
+}
+
+struct LoudsTrie {
+    louds: BitVector,
+    terminal_flags: BitVector,
+    link_flags: BitVector,
+    bases: Vec<u8>,
+    extras: FlatVector,
+    tail: Tail,
+    next_trie: Option<Box<LoudsTrie>>,
+    cache: Vec<Cache>,
+    cache_mask: usize,
+    num_l1_nodes: usize,
+    num_tries: usize,
+}
+
+impl LoudsTrie {
+    fn new() -> LoudsTrie {
+        LoudsTrie {
+            louds: BitVector::new(),
+            terminal_flags: BitVector::new(),
+            link_flags: BitVector::new(),
+            bases: Vec::new(),
+            extras: FlatVector::new(),
+            tail: Tail::new(),
+            next_trie: None,
+            cache: Vec::new(),
+            cache_mask: 0,
+            num_l1_nodes: 0,
+            num_tries: 1,
+        }
+    }
+
+    fn build_trie(&mut self, keys: &mut [Key], terminals: &mut Vec<u32>, trie_id: usize) {
+        let mut next_keys = self.build_current_trie(keys, terminals, trie_id);
+
+        let mut next_terminals = Vec::new();
+        if !next_keys.is_empty() {
+            if trie_id == NUM_TRIES {
+                self.tail.build(&next_keys, &mut next_terminals);
+            } else {
+                let mut next_trie = LoudsTrie::new();
+                next_trie.build_trie(&mut next_keys, &mut next_terminals, trie_id + 1);
+                self.num_tries = next_trie.num_tries + 1;
+                self.next_trie = Some(Box::new(next_trie));
+            }
+        }
+
+        self.link_flags.build_index(false, false);
+        let mut node_id = 0;
+        for terminal in next_terminals.iter_mut() {
+            while !self.link_flags.get(node_id) {
+                node_id += 1;
+            }
+            self.bases[node_id] = (*terminal % 256) as u8;
+            *terminal /= 256;
+            node_id += 1;
+        }
+        self.extras = FlatVector::build(&next_terminals);
+        self.fill_cache();
+    }
+
+    /// Builds the nodes of this trie, and returns the keys for the next
+    /// trie (the strings of the multi-byte links).
+    fn build_current_trie(
+        &mut self,
+        keys: &mut [Key],
+        terminals: &mut Vec<u32>,
+        trie_id: usize,
+    ) -> Vec<Key> {
+        for (i, key) in keys.iter_mut().enumerate() {
+            key.id = i;
+        }
+        keys.sort_by(|a, b| a.bytes.cmp(&b.bytes));
+        let num_unique_keys = if keys.is_empty() {
+            0
+        } else {
+            1 + keys.windows(2).filter(|w| w[0].bytes != w[1].bytes).count()
+        };
+        self.reserve_cache(trie_id, num_unique_keys);
+
+        self.louds.push(true);
+        self.louds.push(false);
+        self.bases.push(0);
+        self.link_flags.push(false);
+
+        let mut next_keys = Vec::new();
+        let mut queue = VecDeque::new();
+        let mut w_ranges: Vec<WeightedRange> = Vec::new();
+
+        queue.push_back(Range {
+            begin: 0,
+            end: keys.len(),
+            key_pos: 0,
+        });
+        while let Some(mut range) = queue.pop_front() {
+            let node_id = self.link_flags.len() - (queue.len() + 1);
+
+            while range.begin < range.end && keys[range.begin].bytes.len() == range.key_pos {
+                keys[range.begin].terminal = node_id as u32;
+                range.begin += 1;
+            }
+
+            if range.begin == range.end {
+                self.louds.push(false);
+                continue;
+            }
+
+            w_ranges.clear();
+            let mut weight = keys[range.begin].weight as f64;
+            for i in (range.begin + 1)..range.end {
+                if keys[i - 1].bytes[range.key_pos] != keys[i].bytes[range.key_pos] {
+                    w_ranges.push(WeightedRange {
+                        range: Range {
+                            begin: range.begin,
+                            end: i,
+                            key_pos: range.key_pos,
+                        },
+                        weight: weight as f32,
+                    });
+                    range.begin = i;
+                    weight = 0.0;
+                }
+                weight += keys[i].weight as f64;
+            }
+            w_ranges.push(WeightedRange {
+                range: Range {
+                    begin: range.begin,
+                    end: range.end,
+                    key_pos: range.key_pos,
+                },
+                weight: weight as f32,
+            });
+            w_ranges.sort_by(|a, b| b.weight.partial_cmp(&a.weight).unwrap());
+
+            if node_id == 0 {
+                self.num_l1_nodes = w_ranges.len();
+            }
+
+            for w_range in w_ranges.drain(..) {
+                let mut range = w_range.range;
+                let first = &keys[range.begin].bytes;
+
+                // Find how far the keys in this range share a common prefix.
+                let mut key_pos = range.key_pos + 1;
+                'outer: while key_pos < first.len() {
+                    for j in (range.begin + 1)..range.end {
+                        if keys[j - 1].bytes[key_pos] != keys[j].bytes[key_pos] {
+                            break 'outer;
+                        }
+                    }
+                    key_pos += 1;
+                }
+
+                let label = first[range.key_pos];
+                let child_id = self.bases.len();
+                self.cache(node_id, child_id, w_range.weight, label);
+
+                if key_pos == range.key_pos + 1 {
+                    self.bases.push(label);
+                    self.link_flags.push(false);
+                } else {
+                    self.bases.push(0);
+                    self.link_flags.push(true);
+                    let link = &first[range.key_pos..key_pos];
+                    next_keys.push(Key {
+                        bytes: if trie_id == 1 {
+                            link.iter().rev().copied().collect()
+                        } else {
+                            link.into()
+                        },
+                        weight: w_range.weight,
+                        id: 0,
+                        terminal: 0,
+                    });
+                }
+                range.key_pos = key_pos;
+                queue.push_back(range);
+                self.louds.push(true);
+            }
+            self.louds.push(false);
+        }
+
+        self.louds.push(false);
+        self.louds.build_index(trie_id == 1, true);
+
+        terminals.clear();
+        terminals.resize(keys.len(), 0);
+        for key in keys.iter() {
+            terminals[key.id] = key.terminal;
+        }
+
+        next_keys
+    }
+
+    fn reserve_cache(&mut self, trie_id: usize, num_keys: usize) {
+        let mut cache_size = if trie_id == 1 { 256 } else { 1 };
+        while cache_size < num_keys / NORMAL_CACHE as usize {
+            cache_size *= 2;
+        }
+        self.cache = vec![
+            Cache {
+                parent: 0,
+                child: 0,
+                link: f32::MIN_POSITIVE.to_bits(),
+            };
+            cache_size
+        ];
+        self.cache_mask = cache_size - 1;
+    }
+
+    fn cache(&mut self, parent: usize, child: usize, weight: f32, label: u8) {
+        let cache_id = (parent ^ (parent << 5) ^ label as usize) & self.cache_mask;
+        let entry = &mut self.cache[cache_id];
+        if weight > f32::from_bits(entry.link) {
+            entry.parent = parent as u32;
+            entry.child = child as u32;
+            entry.link = weight.to_bits();
+        }
+    }
+
+    fn fill_cache(&mut self) {
+        for i in 0..self.cache.len() {
+            let node_id = self.cache[i].child as usize;
+            if node_id != 0 {
+                let base = self.bases[node_id] as u32;
+                let extra = if self.link_flags.get(node_id) {
+                    self.extras.get(self.link_flags.rank1(node_id))
+                } else {
+                    INVALID_EXTRA
+                };
+                self.cache[i].link = base | (extra << 8);
+            } else {
+                self.cache[i].parent = u32::MAX;
+                self.cache[i].child = u32::MAX;
+            }
+        }
+    }
+
+    fn config_flags(&self) -> u32 {
+        let tail_mode = match self.next_trie {
+            Some(ref next_trie) => next_trie.tail_mode_recursive(),
+            None => self.tail.mode(),
+        };
+        self.num_tries as u32 | tail_mode | WEIGHT_ORDER | NORMAL_CACHE
+    }
+
+    fn tail_mode_recursive(&self) -> u32 {
+        match self.next_trie {
+            Some(ref next_trie) => next_trie.tail_mode_recursive(),
+            None => self.tail.mode(),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        self.louds.write(out);
+        self.terminal_flags.write(out);
+        self.link_flags.write(out);
+        write_vector(out, &self.bases);
+        self.extras.write(out);
+        self.tail.write(out);
+        if let Some(ref next_trie) = self.next_trie {
+            next_trie.write(out);
+        }
+        let mut cache_data = Vec::with_capacity(self.cache.len() * 12);
+        for c in self.cache.iter() {
+            cache_data.extend_from_slice(&c.parent.to_le_bytes());
+            cache_data.extend_from_slice(&c.child.to_le_bytes());
+            cache_data.extend_from_slice(&c.link.to_le_bytes());
+        }
+        write_vector(out, &cache_data);
+        out.extend_from_slice(&(self.num_l1_nodes as u32).to_le_bytes());
+        out.extend_from_slice(&self.config_flags().to_le_bytes());
+    }
+}
+
+//----------------------------------------------------------------
+
+/// Suffix storage for the strings of the last trie's links.
+struct Tail {
+    buf: Vec<u8>,
+    end_flags: BitVector,
+}
+
+impl Tail {
+    fn new() -> Tail {
+        Tail {
+            buf: Vec::new(),
+            end_flags: BitVector::new(),
+        }
+    }
+
+    fn mode(&self) -> u32 {
+        if self.end_flags.len() == 0 {
+            TEXT_TAIL
+        } else {
+            BINARY_TAIL
+        }
+    }
+
+    /// `entries` are in the (reversed) order that the last trie sees them.
+    /// Entries that are suffixes of other entries share their storage.
+    fn build(&mut self, entries: &[Key], offsets: &mut Vec<u32>) {
+        let binary = entries.iter().any(|e| e.bytes.contains(&0));
+
+        let mut order: Vec<usize> = (0..entries.len()).collect();
+        order.sort_by(|a, b| entries[*a].bytes.cmp(&entries[*b].bytes));
+
+        offsets.clear();
+        offsets.resize(entries.len(), 0);
+
+        let mut last: Option<usize> = None;
+        for &i in order.iter().rev() {
+            let current = &entries[i].bytes;
+            assert!(!current.is_empty());
+            let (match_len, last_len) = match last {
+                Some(l) => {
+                    let last_bytes = &entries[l].bytes;
+                    let m = current
+                        .iter()
+                        .zip(last_bytes.iter())
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    (m, last_bytes.len())
+                }
+                None => (0, 0),
+            };
+            if match_len == current.len() && last_len != 0 {
+                offsets[i] = offsets[last.unwrap()] + (last_len - match_len) as u32;
+            } else {
+                offsets[i] = self.buf.len() as u32;
+                self.buf.extend(current.iter().rev());
+                if binary {
+                    for _ in 1..current.len() {
+                        self.end_flags.push(false);
+                    }
+                    self.end_flags.push(true);
+                } else {
+                    self.buf.push(0);
+                }
+            }
+            last = Some(i);
+        }
+
+        if binary {
+            self.end_flags.build_index(false, false);
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        write_vector(out, &self.buf);
+        self.end_flags.write(out);
+    }
+}
+
+//----------------------------------------------------------------
+
+/// A bit vector with marisa's rank/select indices.
+struct BitVector {
+    units: Vec<u64>,
+    size: usize,
+    num_1s: usize,
+    ranks: Vec<[u32; 3]>, // (abs, rel_lo, rel_hi)
+    select0s: Vec<u32>,
+    select1s: Vec<u32>,
+}
+
+impl BitVector {
+    fn new() -> BitVector {
+        BitVector {
+            units: Vec::new(),
+            size: 0,
+            num_1s: 0,
+            ranks: Vec::new(),
+            select0s: Vec::new(),
+            select1s: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.size
+    }
+
+    fn push(&mut self, bit: bool) {
+        if self.size == self.units.len() * 64 {
+            self.units.push(0);
+        }
+        if bit {
+            self.units[self.size / 64] |= 1 << (self.size % 64);
+            self.num_1s += 1;
+        }
+        self.size += 1;
+    }
+
+    fn get(&self, i: usize) -> bool {
+        (self.units[i / 64] >> (i % 64)) & 1 != 0
+    }
+
+    /// Number of 1s before position `i`.  Requires `build_index()` to
+    /// have been called.
+    fn rank1(&self, i: usize) -> usize {
+        let rank = &self.ranks[i / 512];
+        let mut offset = rank[0] as usize + get_rel(rank, (i / 64) % 8) as usize;
+        if i % 64 != 0 {
+            offset += (self.units[i / 64] & ((1u64 << (i % 64)) - 1)).count_ones() as usize;
+        }
+        offset
+    }
+
+    fn build_index(&mut self, enables_select0: bool, enables_select1: bool) {
+        let num_bits = self.size;
+        self.ranks = vec![[0; 3]; num_bits.div_ceil(512) + 1];
+        self.select0s.clear();
+        self.select1s.clear();
+
+        let mut num_0s = 0usize;
+        let mut num_1s = 0usize;
+        for i in 0..num_bits {
+            if i % 64 == 0 {
+                let rank = &mut self.ranks[i / 512];
+                let sub_block = (i / 64) % 8;
+                if sub_block == 0 {
+                    rank[0] = num_1s as u32;
+                } else {
+                    set_rel(rank, sub_block, num_1s as u32 - rank[0]);
+                }
+            }
+
+            if self.get(i) {
+                if enables_select1 && num_1s % 512 == 0 {
+                    self.select1s.push(i as u32);
+                }
+                num_1s += 1;
+            } else {
+                if enables_select0 && num_0s % 512 == 0 {
+                    self.select0s.push(i as u32);
+                }
+                num_0s += 1;
+            }
+        }
+
+        // Fill in the remaining relative ranks of the last block.
+        if num_bits % 512 != 0 {
+            let rank = &mut self.ranks[(num_bits - 1) / 512];
+            for sub_block in ((((num_bits - 1) / 64) % 8) + 1)..8 {
+                set_rel(rank, sub_block, num_1s as u32 - rank[0]);
+            }
+        }
+
+        self.ranks.last_mut().unwrap()[0] = num_1s as u32;
+        if enables_select0 {
+            self.select0s.push(num_bits as u32);
+        }
+        if enables_select1 {
+            self.select1s.push(num_bits as u32);
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let mut units = Vec::with_capacity(self.units.len() * 8);
+        for unit in self.units.iter() {
+            units.extend_from_slice(&unit.to_le_bytes());
+        }
+        write_vector(out, &units);
+        out.extend_from_slice(&(self.size as u32).to_le_bytes());
+        out.extend_from_slice(&(self.num_1s as u32).to_le_bytes());
+
+        let mut ranks = Vec::with_capacity(self.ranks.len() * 12);
+        for rank in self.ranks.iter() {
+            for n in rank.iter() {
+                ranks.extend_from_slice(&n.to_le_bytes());
+            }
+        }
+        write_vector(out, &ranks);
+        write_vector(out, &u32s_to_bytes(&self.select0s));
+        write_vector(out, &u32s_to_bytes(&self.select1s));
+    }
+}
+
+/// Bit layout of the relative ranks of the 64-bit sub-blocks within a
+/// 512-bit rank block, as (index, shift, mask).  This matches marisa's
+/// `RankIndex`.
+fn rel_layout(sub_block: usize) -> (usize, u32, u32) {
+    match sub_block {
+        1 => (1, 0, 0x7F),
+        2 => (1, 7, 0xFF),
+        3 => (1, 15, 0xFF),
+        4 => (1, 23, 0x1FF),
+        5 => (2, 0, 0x1FF),
+        6 => (2, 9, 0x1FF),
+        7 => (2, 18, 0x1FF),
+        _ => unreachable!(),
+    }
+}
+
+fn set_rel(rank: &mut [u32; 3], sub_block: usize, value: u32) {
+    let (idx, shift, mask) = rel_layout(sub_block);
+    rank[idx] = (rank[idx] & !(mask << shift)) | ((value & mask) << shift);
+}
+
+fn get_rel(rank: &[u32; 3], sub_block: usize) -> u32 {
+    if sub_block == 0 {
+        return 0;
+    }
+    let (idx, shift, mask) = rel_layout(sub_block);
+    (rank[idx] >> shift) & mask
+}
+
+//----------------------------------------------------------------
+
+/// A bit-packed vector of integers.
+struct FlatVector {
+    units: Vec<u64>,
+    value_size: usize,
+    mask: u64,
+    size: usize,
+}
+
+impl FlatVector {
+    fn new() -> FlatVector {
+        FlatVector {
+            units: Vec::new(),
+            value_size: 0,
+            mask: 0,
+            size: 0,
+        }
+    }
+
+    fn build(values: &[u32]) -> FlatVector {
+        let max_value = values.iter().copied().max().unwrap_or(0);
+        let value_size = (32 - max_value.leading_zeros()) as usize;
+        let num_units = if value_size != 0 {
+            (value_size * values.len()).div_ceil(64)
+        } else if values.is_empty() {
+            0
+        } else {
+            1
+        };
+        let mask = if value_size != 0 {
+            u64::MAX >> (64 - value_size)
+        } else {
+            0
+        };
+
+        let mut fv = FlatVector {
+            units: vec![0; num_units],
+            value_size,
+            mask,
+            size: values.len(),
+        };
+        if value_size != 0 {
+            for (i, value) in values.iter().enumerate() {
+                let pos = i * value_size;
+                let unit_id = pos / 64;
+                let unit_offset = pos % 64;
+                let value = *value as u64 & mask;
+                fv.units[unit_id] |= value << unit_offset;
+                if unit_offset + value_size > 64 {
+                    fv.units[unit_id + 1] |= value >> (64 - unit_offset);
+                }
+            }
+        }
+        fv
+    }
+
+    fn get(&self, i: usize) -> u32 {
+        if self.value_size == 0 {
+            return 0;
+        }
+        let pos = i * self.value_size;
+        let unit_id = pos / 64;
+        let unit_offset = pos % 64;
+        let mut value = self.units[unit_id] >> unit_offset;
+        if unit_offset + self.value_size > 64 {
+            value |= self.units[unit_id + 1] << (64 - unit_offset);
+        }
+        (value & self.mask) as u32
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let mut units = Vec::with_capacity(self.units.len() * 8);
+        for unit in self.units.iter() {
+            units.extend_from_slice(&unit.to_le_bytes());
+        }
+        write_vector(out, &units);
+        out.extend_from_slice(&(self.value_size as u32).to_le_bytes());
+        out.extend_from_slice(&(self.mask as u32).to_le_bytes());
+        out.extend_from_slice(&(self.size as u32).to_le_bytes());
+    }
+}
+
+//----------------------------------------------------------------
+
+/// Writes a vector the way marisa does: its byte size as a u64, followed
+/// by the data, padded with zeros to a multiple of 8 bytes.
+fn write_vector(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    out.extend_from_slice(data);
+    for _ in 0..((8 - (data.len() % 8)) % 8) {
+        out.push(0);
+    }
+}
+
+fn u32s_to_bytes(values: &[u32]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(values.len() * 4);
+    for v in values.iter() {
+        bytes.extend_from_slice(&v.to_le_bytes());
+    }
+    bytes
+}
+
+//----------------------------------------------------------------
+// Reading.
+
+/// Reads serialized MARISA trie data, returning all of its keys in key id
+/// order.
+///
+/// This is only as much of a reader as is needed to check that a built
+/// trie decodes correctly, and returns an error describing the problem if
+/// it doesn't.
+pub fn read_keys(data: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    if data.len() < HEADER.len() + 8 || &data[..HEADER.len()] != HEADER {
+        return Err("missing marisa header".into());
+    }
+    let flags = u32::from_le_bytes(data[(data.len() - 4)..].try_into().unwrap());
+    let num_tries = (flags & 0x7F) as usize;
+    if num_tries == 0 {
+        return Err("invalid number of tries".into());
+    }
+
+    let mut reader = Reader {
+        data,
+        pos: HEADER.len(),
+    };
+    let trie = LoudsTrie::read(&mut reader, num_tries)?;
+    if reader.pos != data.len() {
+        return Err(format!(
+            "{} bytes of unexpected trailing data",
+            data.len() - reader.pos
+        ));
+    }
+    if trie.terminal_flags.len() < trie.bases.len() {
+        return Err("terminal flags don't cover all nodes".into());
+    }
+
+    let mut keys = Vec::with_capacity(trie.terminal_flags.num_1s);
+    for id in 0..trie.terminal_flags.num_1s {
+        keys.push(trie.reverse_lookup(id)?);
+    }
+    Ok(keys)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < len {
+            return Err("unexpected end of data".into());
+        }
+        let bytes = &self.data[self.pos..(self.pos + len)];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a vector written by `write_vector()`.
+    fn vector(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u64()? as usize;
+        if len > self.data.len() {
+            return Err("invalid vector size".into());
+        }
+        let bytes = self.bytes(len)?;
+        self.bytes((8 - (len % 8)) % 8)?;
+        Ok(bytes)
+    }
+
+    fn u32s(&mut self) -> Result<Vec<u32>, String> {
+        Ok(self
+            .vector()?
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect())
+    }
+
+    fn u64s(&mut self) -> Result<Vec<u64>, String> {
+        Ok(self
+            .vector()?
+            .chunks_exact(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+            .collect())
+    }
+}
+
+impl LoudsTrie {
+    fn read(reader: &mut Reader, num_tries: usize) -> Result<LoudsTrie, String> {
+        let mut trie = LoudsTrie::new();
+        trie.louds = BitVector::read(reader)?;
+        trie.terminal_flags = BitVector::read(reader)?;
+        trie.link_flags = BitVector::read(reader)?;
+        trie.bases = reader.vector()?.into();
+        trie.extras = FlatVector::read(reader)?;
+        trie.tail = Tail::read(reader)?;
+        if num_tries > 1 {
+            trie.next_trie = Some(Box::new(LoudsTrie::read(reader, num_tries - 1)?));
+        }
+        let cache = reader.u32s()?;
+        trie.cache = cache
+            .chunks_exact(3)
+            .map(|c| Cache {
+                parent: c[0],
+                child: c[1],
+                link: c[2],
+            })
+            .collect();
+        trie.num_l1_nodes = reader.u32()? as usize;
+        reader.u32()?; // Config flags.
+        trie.num_tries = num_tries;
+
+        if trie.link_flags.len() != trie.bases.len()
+            || trie.extras.size != trie.link_flags.num_1s
+            || trie.louds.num_1s < trie.bases.len()
+        {
+            return Err("inconsistent trie node counts".into());
+        }
+
+        Ok(trie)
+    }
+
+    fn reverse_lookup(&self, key_id: usize) -> Result<Vec<u8>, String> {
+        let mut node_id = self.terminal_flags.select1(key_id)?;
+        let mut key = Vec::new();
+        if node_id == 0 {
+            return Ok(key);
+        }
+        loop {
+            if node_id >= self.bases.len() {
+                return Err("node id out of range".into());
+            }
+            if self.link_flags.get(node_id) {
+                let start = key.len();
+                self.restore_link(self.get_link(node_id)?, &mut key)?;
+                key[start..].reverse();
+            } else {
+                key.push(self.bases[node_id]);
+            }
+            if node_id <= self.num_l1_nodes {
+                key.reverse();
+                return Ok(key);
+            }
+            node_id = self.parent(node_id)?;
+        }
+    }
+
+    /// Appends the (forward) string of the given link to `key`.
+    fn restore_link(&self, link: usize, key: &mut Vec<u8>) -> Result<(), String> {
+        match self.next_trie {
+            Some(ref next_trie) => next_trie.restore(link, key),
+            None => self.tail.restore(link, key),
+        }
+    }
+
+    fn restore(&self, mut node_id: usize, key: &mut Vec<u8>) -> Result<(), String> {
+        loop {
+            if node_id >= self.bases.len() {
+                return Err("link out of range".into());
+            }
+            if self.link_flags.get(node_id) {
+                self.restore_link(self.get_link(node_id)?, key)?;
+            } else {
+                key.push(self.bases[node_id]);
+            }
+            if node_id <= self.num_l1_nodes {
+                return Ok(());
+            }
+            node_id = self.parent(node_id)?;
+        }
+    }
+
+    fn get_link(&self, node_id: usize) -> Result<usize, String> {
+        let extra_id = self.link_flags.rank1(node_id);
+        if extra_id >= self.extras.size {
+            return Err("link flags don't match link extras".into());
+        }
+        Ok(self.bases[node_id] as usize | ((self.extras.get(extra_id) as usize) << 8))
+    }
+
+    fn parent(&self, node_id: usize) -> Result<usize, String> {
+        if node_id >= self.louds.num_1s {
+            return Err("node id out of range".into());
+        }
+        let pos = self.louds.select1(node_id)?;
+        if pos <= node_id || pos > node_id * 2 {
+            return Err("invalid louds structure".into());
+        }
+        // Parents always have smaller ids than their children, which also
+        // guarantees that restoring terminates.
+        Ok(pos - node_id - 1)
+    }
+}
+
+impl Tail {
+    fn read(reader: &mut Reader) -> Result<Tail, String> {
+        Ok(Tail {
+            buf: reader.vector()?.into(),
+            end_flags: BitVector::read(reader)?,
+        })
+    }
+
+    fn restore(&self, offset: usize, key: &mut Vec<u8>) -> Result<(), String> {
+        if offset >= self.buf.len() {
+            return Err("tail offset out of range".into());
+        }
+        if self.end_flags.len() == 0 {
+            match self.buf[offset..].iter().position(|b| *b == 0) {
+                Some(len) => key.extend_from_slice(&self.buf[offset..(offset + len)]),
+                None => return Err("unterminated tail string".into()),
+            }
+        } else {
+            if self.end_flags.len() != self.buf.len() {
+                return Err("tail end flags don't match tail size".into());
+            }
+            for i in offset..self.buf.len() {
+                key.push(self.buf[i]);
+                if self.end_flags.get(i) {
+                    return Ok(());
+                }
+            }
+            return Err("unterminated tail string".into());
+        }
+        Ok(())
+    }
+}
+
+impl BitVector {
+    fn read(reader: &mut Reader) -> Result<BitVector, String> {
+        let units = reader.u64s()?;
+        let size = reader.u32()? as usize;
+        let num_1s = reader.u32()? as usize;
+        let ranks: Vec<[u32; 3]> = reader
+            .u32s()?
+            .chunks_exact(3)
+            .map(|c| [c[0], c[1], c[2]])
+            .collect();
+        let select0s = reader.u32s()?;
+        let select1s = reader.u32s()?;
+
+        if size > units.len() * 64 || num_1s > size {
+            return Err("invalid bit vector size".into());
+        }
+        if size != 0 && ranks.len() != size.div_ceil(512) + 1 {
+            return Err("invalid bit vector rank index".into());
+        }
+        if ranks.last().map(|r| r[0] as usize).unwrap_or(0) != num_1s {
+            return Err("bit vector rank index doesn't match its contents".into());
+        }
+
+        Ok(BitVector {
+            units,
+            size,
+            num_1s,
+            ranks,
+            select0s,
+            select1s,
+        })
+    }
+
+    /// Position of the `i`th 1.
+    ///
+    /// Returns an error if there is no `i`th 1, or if the rank index
+    /// doesn't lead to it, which only happens with corrupt data.
+    fn select1(&self, i: usize) -> Result<usize, String> {
+        let invalid = || String::from("bit vector rank index doesn't match its contents");
+        if i >= self.num_1s {
+            return Err("bit index out of range".into());
+        }
+
+        // Find the rank block, and then the sub-block within it.
+        let block = self
+            .ranks
+            .partition_point(|r| r[0] as usize <= i)
+            .checked_sub(1)
+            .ok_or_else(invalid)?;
+        let rank = &self.ranks[block];
+        let mut sub_block = 0;
+        while sub_block < 7 && rank[0] as usize + get_rel(rank, sub_block + 1) as usize <= i {
+            sub_block += 1;
+        }
+
+        // Find the bit within the unit.
+        let mut remaining = i
+            .checked_sub(rank[0] as usize + get_rel(rank, sub_block) as usize)
+            .ok_or_else(invalid)?;
+        let unit_id = block * 8 + sub_block;
+        let mut unit = *self.units.get(unit_id).ok_or_else(invalid)?;
+        while remaining > 0 && unit != 0 {
+            unit &= unit - 1;
+            remaining -= 1;
+        }
+        let pos = unit_id * 64 + unit.trailing_zeros() as usize;
+        if unit == 0 || pos >= self.size {
+            return Err(invalid());
+        }
+        Ok(pos)
+    }
+}
+
+impl FlatVector {
+    fn read(reader: &mut Reader) -> Result<FlatVector, String> {
+        let units = reader.u64s()?;
+        let value_size = reader.u32()? as usize;
+        let mask = reader.u32()? as u64;
+        let size = reader.u32()? as usize;
+        if value_size > 32 || value_size * size > units.len() * 64 {
+            return Err("invalid flat vector size".into());
+        }
+        Ok(FlatVector {
+            units,
+            value_size,
+            mask,
+            size,
+        })
+    }
+}
+
+//----------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a trie and reads its keys back, in key id order.
+    fn round_trip(keys: &[(String, u32)]) -> Vec<String> {
+        let data = build(keys.iter().map(|(key, w)| (key.as_bytes(), *w as f32)));
+        read_keys(&data)
+            .unwrap()
+            .into_iter()
+            .map(|key| String::from_utf8(key).unwrap())
+            .collect()
+    }
+
+    fn keys(keys: &[(&str, u32)]) -> Vec<(String, u32)> {
+        keys.iter().map(|(key, w)| (key.to_string(), *w)).collect()
+    }
+
+    /// Several thousand keys with shared prefixes and long suffixes, so
+    /// that the nested tries, the tail, and multiple rank blocks of the
+    /// bit vectors all get used.
+    fn many_keys() -> Vec<(String, u32)> {
+        let prefixes = ["た", "たべ", "たべもの", "食べ", "かんがえ", "考え", "x"];
+        let suffixes = ["", "る", "ます", "なかった", "させられなかったでしょう"];
+        let mut keys = Vec::new();
+        for i in 0..1500 {
+            for (j, suffix) in suffixes.iter().enumerate() {
+                let key = format!("{}{}{}", prefixes[(i + j) % prefixes.len()], i, suffix);
+                keys.push((key, (i * 7 + j) as u32 % 100));
+            }
+        }
+        keys
+    }
+
+    #[test]
+    fn key_ids_follow_weight_order() {
+        assert_eq!(
+            round_trip(&keys(&[("a", 1), ("b", 2), ("c", 3)])),
+            ["c", "b", "a"]
+        );
+
+        // Ties keep the keys' byte order.
+        assert_eq!(round_trip(&keys(&[("b", 1), ("a", 1)])), ["a", "b"]);
+    }
+
+    #[test]
+    fn key_ids_are_breadth_first() {
+        // "a" weighs more than "b", since "ab" is under it.
+        assert_eq!(
+            round_trip(&keys(&[("ab", 1), ("b", 1), ("a", 1)])),
+            ["a", "b", "ab"]
+        );
+    }
+
+    #[test]
+    fn round_trip_many_keys() {
+        let keys = many_keys();
+        let mut read = round_trip(&keys);
+        assert_eq!(read.len(), keys.len());
+        read.sort();
+        let mut expected: Vec<String> = keys.into_iter().map(|(key, _)| key).collect();
+        expected.sort();
+        assert_eq!(read, expected);
+    }
+
+    /// Compares the output byte for byte with the reference
+    /// implementation.  Skipped if `marisa-build` isn't installed.
+    #[test]
+    fn matches_marisa_build() {
+        let keys = many_keys();
+        let dir = tempfile::tempdir().unwrap();
+        let words_path = dir.path().join("words.txt");
+        let marisa_path = dir.path().join("words.marisa");
+        let words: String = keys
+            .iter()
+            .map(|(key, w)| format!("{}\t{}\n", key, w))
+            .collect();
+        std::fs::write(&words_path, words).unwrap();
+        let status = std::process::Command::new("marisa-build")
+            .arg("-o")
+            .arg(&marisa_path)
+            .arg(&words_path)
+            .stderr(std::process::Stdio::null())
+            .status();
+        if !status.map(|s| s.success()).unwrap_or(false) {
+            eprintln!("marisa-build isn't installed, so the output isn't compared with it.");
+            return;
+        }
+
+        let data = build(keys.iter().map(|(key, w)| (key.as_bytes(), *w as f32)));
+        assert!(std::fs::read(&marisa_path).unwrap() == data);
+    }
+}
@@ -0,0 +1,39 @@
+//! The formats that dictionaries can be written in.
+//!
+//! Each format has a writer that implements `OutputWriter`, and is listed
+//! in `FORMATS` under the name it's selected by with `--format`.  Adding a
+//! format takes a module with its writer, and an entry in `FORMATS`.
+
+use std::path::Path;
+
+use crate::error::Error;
+use crate::kobo::{Entry, KoboWriter, WriteSettings};
+use crate::zim::ZimWriter;
+
+pub trait OutputWriter {
+    /// Writes the entries as a dictionary file.
+    fn write(&self, entries: &[Entry], path: &Path) -> Result<(), Error>;
+
+    /// The size in bytes of the file that `write()` would write, for
+    /// `--dry_run`, if the format can tell without writing it.
+    fn size(&self, _entries: &[Entry]) -> Option<Result<u64, Error>> {
+        None
+    }
+}
+
+/// Creates a format's writer for the given settings.
+pub type NewWriter = fn(&WriteSettings) -> Box<dyn OutputWriter>;
+
+/// The formats by name, with functions that create their writers.
+pub const FORMATS: &[(&str, NewWriter)] = &[
+    ("kobo", |settings| Box::new(KoboWriter(settings.clone()))),
+    ("zim", |settings| Box::new(ZimWriter::new(&settings.lang))),
+];
+
+/// Creates the writer of the format with the given name.
+pub fn writer(format: &str, settings: &WriteSettings) -> Option<Box<dyn OutputWriter>> {
+    FORMATS
+        .iter()
+        .find(|(name, _)| *name == format)
+        .map(|(_, new_writer)| new_writer(settings))
+}
@@ -0,0 +1,90 @@
+//! Project files (.kjd): the merged entries of a build, saved with
+//! `--save_project` so that output can later be generated from them with
+//! `--project`, without parsing and merging the input dictionaries again.
+//!
+//! A project file is gzipped json, with the entries as they are before the
+//! output options (word lists, prefix sizes, the format, etc.) are applied,
+//! along with where they came from.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Context, Error};
+use crate::kobo::Entry;
+
+/// Bump this whenever the format changes in a way that older versions of
+/// the tool can't read.
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct Project {
+    format_version: u32,
+
+    /// The version of the tool that wrote the project.
+    pub tool_version: String,
+
+    /// The language code of the headwords, like `--lang`.
+    pub lang: String,
+
+    /// The input files the entries were built from, as in the manifest.
+    pub inputs: Vec<serde_json::Value>,
+
+    /// How the entries of each Yomichan dictionary matched JMDict entries,
+    /// as in the manifest.
+    pub dictionaries: Vec<serde_json::Value>,
+
+    pub entries: Vec<Entry>,
+}
+
+impl Project {
+    pub fn new(
+        lang: &str,
+        inputs: Vec<serde_json::Value>,
+        dictionaries: Vec<serde_json::Value>,
+        entries: Vec<Entry>,
+    ) -> Project {
+        Project {
+            format_version: FORMAT_VERSION,
+            tool_version: clap::crate_version!().into(),
+            lang: lang.into(),
+            inputs,
+            dictionaries,
+            entries,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut out = GzEncoder::new(
+            BufWriter::new(File::create(path)?),
+            flate2::Compression::default(),
+        );
+        serde_json::to_writer(&mut out, self)?;
+
+        // Dropping the `BufWriter` would flush it but ignore any error, so
+        // flush it explicitly.
+        let file = out.finish()?.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()
+    }
+
+    pub fn load(path: &Path) -> Result<Project, Error> {
+        let context = || format!("failed to read project file '{}'", path.display());
+        let file = File::open(path).context(context)?;
+        let project: Project =
+            serde_json::from_reader(GzDecoder::new(BufReader::new(file))).context(context)?;
+        if project.format_version > FORMAT_VERSION {
+            return Err(Error::invalid(
+                format!("project file '{}' can't be read", path.display()),
+                format!(
+                    "it was written by a newer version of this tool ({})",
+                    project.tool_version
+                ),
+            ));
+        }
+        Ok(project)
+    }
+}
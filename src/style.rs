@@ -0,0 +1,344 @@
+//! Styling and templating of the generated entry html.
+//!
+//! Kobo's dictionary renderer doesn't reliably support stylesheets, so all
+//! styling ends up as inline `style` attributes.  The styles of the named
+//! elements below can be overridden with a simple css file, where each
+//! rule's selector is one of the element names (optionally prefixed with a
+//! `.`), and its declarations are merged into the element's default style.
+
+use std::collections::HashMap;
+
+/// The default header template.  See `render_header()`.
+pub const DEFAULT_HEADER_TEMPLATE: &str =
+    "{reading} {pitch_accent} &nbsp;&nbsp;&mdash; 【{writings}】{word_type}";
+
+/// Named elements and their default inline styles.
+///
+/// Elements with an empty style aren't wrapped in any markup unless a style
+/// is given for them.
+const DEFAULT_STYLES: &[(&str, &str)] = &[
+    // The pronunciation at the start of entry headers.
+    ("reading", ""),
+    // The pitch accent numbers in entry headers.
+    ("pitch-accent", ""),
+    // Grammatical information in entry headers.
+    (
+        "word-type",
+        "font-size: 0.8em; font-style: italic; margin-left: 0; white-space: nowrap;",
+    ),
+    // The part of speech and usage notes before each JMDict sense.
+    ("sense-label", "font-size: 0.8em; font-style: italic;"),
+    // The block of definitions following an entry header.
+    ("definitions", "margin-top: 0.7em;"),
+    // The trailing section of English definitions, with --english_section.
+    (
+        "english",
+        "font-size: 0.85em; margin-top: 0.7em; padding-top: 0.3em; border-top: 1px solid #999;",
+    ),
+    // The conjugation table at the end of verb and adjective entries, with
+    // --conjugation_table.
+    ("conjugations", "font-size: 0.85em; margin-top: 0.7em;"),
+    // The first line of kanji entries, with the kanji and its meanings.
+    (
+        "kanji-heading",
+        "margin-left: 2.5em; margin-bottom: 1.0em; text-indent: -2.5em;",
+    ),
+    // The kanji itself in kanji entries.
+    ("kanji", "font-size: 2.0em;"),
+    // The on'yomi and kun'yomi lines of kanji entries.
+    ("kanji-reading", "margin-left: 2.5em; text-indent: -2.5em;"),
+];
+
+/// Built-in style presets, as css in the same format as `apply_css()`.
+pub const PRESETS: &[(&str, &str)] = &[
+    (
+        "compact",
+        "definitions { margin-top: 0.3em; line-height: 1.15; }
+         word-type { font-size: 0.75em; }
+         kanji-heading { margin-bottom: 0.4em; }
+         kanji { font-size: 1.5em; }",
+    ),
+    (
+        "large-print",
+        "reading { font-size: 1.4em; font-weight: bold; }
+         pitch-accent { font-size: 1.3em; }
+         definitions { font-size: 1.2em; }
+         kanji { font-size: 2.6em; }
+         kanji-reading { font-size: 1.2em; }",
+    ),
+    (
+        "high-contrast",
+        "reading { font-weight: bold; }
+         pitch-accent { font-weight: bold; }
+         word-type { font-style: normal; font-weight: bold; }
+         sense-label { font-style: normal; font-weight: bold; }
+         kanji { font-weight: bold; }",
+    ),
+];
+
+#[derive(Debug, Clone)]
+pub struct Styles {
+    styles: HashMap<String, Vec<(String, String)>>, // element -> [(property, value)]
+    header_template: String,
+    pitch_accent_graphs: bool,
+}
+
+impl Styles {
+    pub fn new() -> Styles {
+        Styles {
+            styles: DEFAULT_STYLES
+                .iter()
+                .map(|(name, style)| (name.to_string(), parse_declarations(style)))
+                .collect(),
+            header_template: DEFAULT_HEADER_TEMPLATE.into(),
+            pitch_accent_graphs: false,
+        }
+    }
+
+    /// Merges the rules of the given css text into the styles.
+    ///
+    /// Returns the selectors that didn't match any known element.
+    pub fn apply_css(&mut self, css: &str) -> Vec<String> {
+        let mut unknown = Vec::new();
+
+        // Strip comments.
+        let mut text = String::new();
+        let mut rest = css;
+        while let Some(start) = rest.find("/*") {
+            text.push_str(&rest[..start]);
+            rest = match rest[start..].find("*/") {
+                Some(end) => &rest[(start + end + 2)..],
+                None => "",
+            };
+        }
+        text.push_str(rest);
+
+        for rule in text.split('}') {
+            let (selectors, declarations) = match rule.find('{') {
+                Some(i) => (&rule[..i], &rule[(i + 1)..]),
+                None => continue,
+            };
+            let declarations = parse_declarations(declarations);
+            for selector in selectors.split(',') {
+                let name = selector.trim().trim_start_matches('.');
+                match self.styles.get_mut(name) {
+                    Some(style) => {
+                        for (property, value) in declarations.iter() {
+                            match style.iter_mut().find(|(p, _)| p == property) {
+                                Some(existing) => existing.1 = value.clone(),
+                                None => style.push((property.clone(), value.clone())),
+                            }
+                        }
+                    }
+                    None => unknown.push(name.into()),
+                }
+            }
+        }
+
+        unknown
+    }
+
+    /// Applies one of the built-in `PRESETS`.  Returns false if there is
+    /// no preset with the given name.
+    pub fn apply_preset(&mut self, name: &str) -> bool {
+        match PRESETS.iter().find(|(n, _)| *n == name) {
+            Some((_, css)) => {
+                self.apply_css(css);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_header_template(&mut self, template: &str) {
+        self.header_template = template.trim().into();
+    }
+
+    /// Sets whether pitch accents are drawn as graphs rather than written
+    /// as numbers.
+    pub fn set_pitch_accent_graphs(&mut self, enabled: bool) {
+        self.pitch_accent_graphs = enabled;
+    }
+
+    /// Returns the inline style text for the named element, escaped for
+    /// use in a `style` attribute.
+    pub fn get(&self, name: &str) -> String {
+        let mut text = String::new();
+        for (property, value) in self.styles[name].iter() {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+            text.push_str(&format!("{}: {};", property, value));
+        }
+        crate::html::escape_attr(&text)
+    }
+
+    /// Wraps the (already html-formatted) text in a span with the named
+    /// element's style, or returns it as-is if the element has no style.
+    pub fn wrap(&self, name: &str, text: &str) -> String {
+        if self.styles[name].is_empty() || text.is_empty() {
+            text.into()
+        } else {
+            format!("<span style=\"{}\">{}</span>", self.get(name), text)
+        }
+    }
+
+    /// Renders the pitch accents of a word with the given (kana) reading,
+    /// either as numbers in brackets or as graphs.
+    pub fn render_pitch_accent(&self, reading: &str, accents: &[u32]) -> String {
+        let mut text = String::new();
+        for accent in accents.iter() {
+            let graph = if self.pitch_accent_graphs {
+                pitch_accent_graph(mora_count(reading), *accent)
+            } else {
+                None
+            };
+            match graph {
+                Some(graph) => text.push_str(&graph),
+                None => text.push_str(&format!("[{}]", accent)),
+            }
+        }
+        text
+    }
+
+    /// Renders the entry header template.
+    ///
+    /// The template placeholders `{reading}`, `{pitch_accent}`,
+    /// `{writings}`, and `{word_type}` are replaced with the respective
+    /// (already html-formatted) parts of the header.
+    pub fn render_header(
+        &self,
+        reading: &str,
+        pitch_accent: &str,
+        writings: &str,
+        word_type: &str,
+    ) -> String {
+        self.header_template
+            .replace("{reading}", reading)
+            .replace("{pitch_accent}", pitch_accent)
+            .replace("{writings}", writings)
+            .replace("{word_type}", word_type)
+    }
+}
+
+fn parse_declarations(text: &str) -> Vec<(String, String)> {
+    text.split(';')
+        .filter_map(|decl| {
+            let mut parts = decl.splitn(2, ':');
+            let property = parts.next()?.trim();
+            let value = parts.next()?.trim();
+            if property.is_empty() || value.is_empty() {
+                None
+            } else {
+                Some((property.into(), value.into()))
+            }
+        })
+        .collect()
+}
+
+/// Counts the morae of a kana word.  Small kana other than っ/ッ are part
+/// of the preceding mora.
+fn mora_count(reading: &str) -> usize {
+    reading
+        .chars()
+        .filter(|c| !"ゃゅょぁぃぅぇぉゎャュョァィゥェォヮ".contains(*c))
+        .count()
+}
+
+/// Draws a small inline svg graph of a pitch accent pattern, as a line of
+/// high and low dots for each mora plus a hollow dot for a following
+/// particle.
+///
+/// Returns `None` if the accent doesn't fit the word, e.g. for an empty
+/// reading or an accent past its last mora, in which case it's written as
+/// a number instead.
+fn pitch_accent_graph(morae: usize, accent: u32) -> Option<String> {
+    const STEP: usize = 10;
+    const HIGH: usize = 4;
+    const LOW: usize = 12;
+
+    let accent = accent as usize;
+    if morae == 0 || accent > morae {
+        return None;
+    }
+    let is_high = |i: usize| match accent {
+        // Heiban: low then high, including the particle.
+        0 => i > 0,
+        // Atamadaka: high then low.
+        1 => i == 0,
+        // Nakadaka and odaka: low, then high up to the accented mora.
+        _ => i > 0 && i < accent,
+    };
+
+    let width = STEP * (morae + 1);
+    let mut points = String::new();
+    let mut dots = String::new();
+    for i in 0..=morae {
+        let x = STEP / 2 + i * STEP;
+        let y = if is_high(i) { HIGH } else { LOW };
+        if !points.is_empty() {
+            points.push(' ');
+        }
+        points.push_str(&format!("{},{}", x, y));
+        let fill = if i < morae { "black" } else { "white" };
+        dots.push_str(&format!(
+            "<circle cx=\"{}\" cy=\"{}\" r=\"2.5\" fill=\"{}\" stroke=\"black\"/>",
+            x, y, fill
+        ));
+    }
+
+    Some(format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"16\" viewBox=\"0 0 {} 16\" style=\"vertical-align: middle;\"><polyline points=\"{}\" fill=\"none\" stroke=\"black\"/>{}</svg>",
+        width, width, points, dots
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The points of a graph's line, as (x, y) pairs.
+    fn graph_points(morae: usize, accent: u32) -> String {
+        let graph = pitch_accent_graph(morae, accent).unwrap();
+        let start = graph.find("points=\"").unwrap() + "points=\"".len();
+        let end = start + graph[start..].find('"').unwrap();
+        graph[start..end].into()
+    }
+
+    #[test]
+    fn one_mora_heiban() {
+        // Low, then a high particle.
+        assert_eq!(graph_points(1, 0), "5,12 15,4");
+    }
+
+    #[test]
+    fn one_mora_atamadaka() {
+        // High, then a low particle.
+        assert_eq!(graph_points(1, 1), "5,4 15,12");
+    }
+
+    #[test]
+    fn odaka() {
+        assert_eq!(graph_points(2, 2), "5,12 15,4 25,12");
+    }
+
+    #[test]
+    fn nakadaka() {
+        assert_eq!(graph_points(3, 2), "5,12 15,4 25,12 35,12");
+    }
+
+    #[test]
+    fn accent_that_doesnt_fit() {
+        assert_eq!(pitch_accent_graph(0, 0), None);
+        assert_eq!(pitch_accent_graph(2, 3), None);
+    }
+
+    #[test]
+    fn graphs_survive_sanitizing() {
+        // Entries are sanitized before they're written.
+        for &(morae, accent) in [(1, 0), (1, 1), (3, 0), (3, 2), (3, 3)].iter() {
+            let graph = pitch_accent_graph(morae, accent).unwrap();
+            assert_eq!(crate::html::sanitize(&graph), graph);
+        }
+    }
+}
@@ -1,14 +1,21 @@
 //! Parses Yomichan .zip dictionaries.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::path::Path;
 
+use rayon::prelude::*;
 use regex::Regex;
+use serde::de::{Deserializer, SeqAccess, Visitor};
 use serde_json::Value;
 
+use crate::archive;
+use crate::config::Config;
+use crate::error::{Context, Error};
+use crate::html;
+
 //----------------------------------------------------------------
 // Entry type for words.
 #[derive(Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
@@ -62,6 +69,33 @@ impl Definition {
         }
     }
 
+    /// The number of items in the list as displayed, i.e. looking within
+    /// lists that only wrap a single other list.
+    pub fn list_len(&self) -> usize {
+        match self {
+            &Definition::List((ref header, ref list))
+                if header.trim().is_empty() && list.len() == 1 =>
+            {
+                list[0].list_len()
+            }
+            _ => self.len(),
+        }
+    }
+
+    /// Truncates the list to its first `n` items, adding an ellipsis item
+    /// if any were removed.  Lists that only wrap a single other list are
+    /// truncated within, since that's how they're displayed.
+    pub fn truncate(&mut self, n: usize) {
+        if let Definition::List((ref header, ref mut list)) = self {
+            if header.trim().is_empty() && list.len() == 1 {
+                list[0].truncate(n);
+            } else if list.len() > n {
+                list.truncate(n);
+                list.push(Definition::Def("…".into()));
+            }
+        }
+    }
+
     pub fn def_text(&self) -> &str {
         if let &Definition::Def(ref text) = self {
             text
@@ -81,6 +115,14 @@ pub enum InflectionType {
     None,
 }
 
+/// How entry headers are stripped from the start of definitions.
+#[derive(Clone, Debug)]
+pub enum HeaderStripping {
+    Off,
+    FirstLine,    // Drop the first line if it contains the word.
+    Regex(Regex), // Drop the regex's match at the start of the definition.
+}
+
 //----------------------------------------------------------------
 // Entry type for kanji.
 #[derive(Clone, Debug)]
@@ -94,35 +136,82 @@ pub struct KanjiEntry {
 
 //----------------------------------------------------------------
 
-pub fn parse(path: &Path) -> std::io::Result<(Vec<TermEntry>, Vec<TermEntry>, Vec<KanjiEntry>)> // (words, names, kanji)
-{
-    let mut zip_in = zip::ZipArchive::new(BufReader::new(File::open(path)?))?;
+/// The word, name, and kanji entries of a dictionary.
+pub type Entries = (Vec<TermEntry>, Vec<TermEntry>, Vec<KanjiEntry>);
+
+/// A parsed row of a term bank.
+struct TermRow {
+    entry: TermEntry,
+
+    /// (sequence, bank number, row), which the definitions of the same
+    /// word are sorted by.
+    order: (i64, u32, usize),
+
+    /// The processed definitions.  (The entry's own are left empty.)
+    definitions: Vec<Definition>,
+}
+
+/// The rows of a bank file, as parsed by `parse()`.
+#[derive(Default)]
+struct Bank {
+    terms: Vec<TermRow>,
+    names: Vec<TermEntry>,
+    kanji: Vec<KanjiEntry>,
+    skipped_rows: usize,
+}
+
+pub fn parse(
+    path: &Path,
+    config: &Config,
+    embed_images: bool,
+    is_name_dict: bool,
+) -> Result<Entries, Error> {
+    let read_error = || format!("failed to read Yomichan dictionary '{}'", path.display());
+    let invalid = |message: &str| {
+        Error::invalid(
+            format!("Yomichan dictionary '{}' isn't valid", path.display()),
+            message,
+        )
+    };
+    let bank_error = |filename: &str, e: serde_json::Error| match Error::from(e) {
+        Error::Invalid { message, .. } => invalid(&format!("{}: {}", filename, message)),
+        e => e,
+    };
+
+    let mut zip_in = archive::open(path)
+        .context(|| format!("failed to open Yomichan dictionary '{}'", path.display()))?;
 
     let mut text = String::new();
 
     // Load index.json for meta-data about the dictionary.
     let index_json: Value = {
         text.clear();
+        let index = archive::find_member(&mut zip_in, "index.json")
+            .context(read_error)?
+            .ok_or_else(|| invalid("no index.json"))?;
         zip_in
-            .by_name("index.json")
-            .expect("Yomichan dictionary isn't valid: no index.json.")
+            .by_index(index)
+            .context(read_error)?
             .read_to_string(&mut text)
-            .expect("Yomichan dictionary isn't valid: invalid json.");
-        serde_json::from_str(&text).expect("Yomichan dictionary isn't valid: invalid json.")
+            .context(read_error)?;
+        serde_json::from_str(&text).map_err(|_| invalid("index.json isn't valid json"))?
     };
 
     // Check the format version.
     match index_json.get("format") {
         Some(Value::Number(version)) if version.as_i64() == Some(3) => {}
-        _ => panic!("Yomichan dictionaries other than format version 3 are not supported."),
+        _ => {
+            return Err(invalid(
+                "Yomichan dictionaries other than format version 3 are not supported",
+            ))
+        }
     }
 
     // Get the normalized dictionary title.
     let dictionary_title: String = index_json
         .get("title")
-        .expect("Yomichan dictionary isn't valid: index in unexpected format.")
-        .as_str()
-        .expect("Yomichan dictionary isn't valid: index in unexpected format.")
+        .and_then(|title| title.as_str())
+        .ok_or_else(|| invalid("index.json has no title"))?
         .to_lowercase()
         .split("(")
         .nth(0)
@@ -130,166 +219,305 @@ pub fn parse(path: &Path) -> std::io::Result<(Vec<TermEntry>, Vec<TermEntry>, Ve
         .trim()
         .into();
 
-    // Is this a name dictionary?
-    let is_name_dict = match dictionary_title.as_str() {
-        "jmnedict" => true,
-        _ => false,
-    };
+    let dict_config = config.dictionary(&dictionary_title);
+    let header_stripping = dict_config.header.unwrap_or(HeaderStripping::FirstLine);
+    let dividers = dict_config
+        .dividers
+        .unwrap_or_else(|| built_in_dividers(&dictionary_title));
 
-    // Loop through the bank-json files in the zip and build our entry list(s).
-    let mut term_entries: HashMap<_, TermEntry> = HashMap::new();
-    let mut name_entries = Vec::new();
-    let mut kanji_entries = Vec::new();
-    for i in 0..zip_in.len() {
-        // Open the file.
-        let mut f = zip_in.by_index(i).unwrap();
-        let filename: String = std::str::from_utf8(f.name_raw()).unwrap().into();
-        if !filename.ends_with(".json") {
-            continue;
-        }
+    // Is this a name dictionary?  JMnedict is recognized by its title,
+    // and others have to be given with `is_name_dict`.
+    let is_name_dict = is_name_dict || dictionary_title == "jmnedict";
 
-        // Load the json data.
-        text.clear();
-        f.read_to_string(&mut text)
-            .expect("Yomichan dictionary isn't valid: invalid json.");
-        let json: Value =
-            serde_json::from_str(&text).expect("Yomichan dictionary isn't valid: invalid json.");
-
-        // Parse the json into entries.
-        if filename.starts_with("term_bank_") {
-            // It's a term bank.
-
-            // Dividers for the 三省堂　スーパー大辞林 dictionary.
-            // But probably works for some other native Japanese
-            // dictionaries as well.
-            let dividers = &[
-                // The (?m) puts the regex into multi-line mode, so
-                // that ^ will match both newlines and start of text.
-                Regex::new("(?m)^■[一二三四五六七八九十]+■").unwrap(),
-                Regex::new("(?m)^[❶❷❸❹❺❻❼❽❾❿]+").unwrap(),
-                Regex::new("(?m)^（[０１２３４５６７８９]+）").unwrap(),
-            ];
-
-            for item in json.as_array().unwrap().iter() {
-                let mut tags: Vec<String> = item
-                    .get(2)
-                    .unwrap()
-                    .as_str()
-                    .unwrap()
-                    .split(" ")
-                    .chain(item.get(7).unwrap().as_str().unwrap().split(" "))
-                    .map(|s| s.trim().into())
-                    .filter(|s: &String| !s.is_empty())
-                    .collect();
-                tags.sort();
-                tags.dedup();
-
-                let mut entry = TermEntry {
-                    dict_name: dictionary_title.clone(),
-                    writing: item.get(0).unwrap().as_str().unwrap().trim().into(),
-                    reading: item.get(1).unwrap().as_str().unwrap().trim().into(),
-                    infl: match item.get(3).unwrap().as_str().unwrap().trim() {
-                        "v1" => InflectionType::VerbIchidan,
-                        "v5" => InflectionType::VerbGodan,
-                        "vs" => InflectionType::VerbSuru,
-                        "vk" => InflectionType::VerbKuru,
-                        "adj-i" => InflectionType::IAdjective,
-                        _ => InflectionType::None,
-                    },
-                    commonness: item.get(4).unwrap().as_i64().unwrap() as i32,
-                    definitions: Definition::List((
-                        "".into(),
-                        vec![Definition::Def(
-                            item.get(5)
-                                .unwrap()
-                                .as_array()
-                                .unwrap()
-                                .iter()
-                                .map(|d| {
-                                    if let Some(s) = d.as_str() {
-                                        s.trim()
-                                    } else {
-                                        // Ignore the complex structured defintions for now.
-                                        // TODO: handle this properly.
-                                        ""
-                                    }
-                                })
-                                .collect::<Vec<&str>>()
-                                .join("; "),
-                        )],
-                    )),
-                    tags: tags,
-                };
+    // The bank files are parsed in parallel, each through its own handle
+    // to the zip, and their rows are then gathered in archive order.
+    let bank_files: Vec<(usize, String)> = archive::member_names(&mut zip_in)
+        .context(read_error)?
+        .into_iter()
+        .enumerate()
+        .filter(|(_, filename)| {
+            filename.ends_with(".json")
+                && (filename.starts_with("term_bank_") || filename.starts_with("kanji_bank_"))
+        })
+        .collect();
+    let banks = bank_files
+        .par_iter()
+        .map(|(i, filename)| -> Result<Bank, Error> {
+            let mut zip = archive::open(path).context(read_error)?;
 
-                if is_name_dict {
-                    name_entries.push(entry);
-                } else {
-                    // We do some extra work here to merge the definitions from
-                    // multiple entries for the same word.
-                    let key = (entry.writing.clone(), entry.reading.clone());
-                    let e = term_entries.entry(key.clone()).or_insert(TermEntry {
-                        dict_name: dictionary_title.clone(),
-                        writing: entry.writing.clone(),
-                        reading: entry.reading.clone(),
-                        definitions: Definition::List(("".into(), Vec::new())),
-                        infl: entry.infl,
-                        tags: Vec::new(),
-                        commonness: entry.commonness,
-                    });
-                    assert!(e.definitions.is_list());
-                    if let Definition::List((_, ref mut list_to)) = e.definitions {
-                        match entry.definitions {
-                            Definition::List((_, mut list_from)) => {
-                                list_to.extend(list_from.drain(..).filter_map(|d| {
-                                    process_definition(&key.0, &key.1, dividers, d)
-                                }))
-                            }
-                            Definition::Def(s) => list_to.push(Definition::Def(s)),
-                        }
+            // Images are loaded from another handle to the zip, since the
+            // bank file is read while its definitions are processed.
+            let mut images = if embed_images {
+                let mut zip = archive::open(path).context(read_error)?;
+                let index = archive::member_index(&mut zip).context(read_error)?;
+                Some(Images { zip, index })
+            } else {
+                None
+            };
+
+            // Parse the json into entries.  The rows are parsed one at a
+            // time, so that large bank files don't need to be in memory all
+            // at once.
+            let reader = BufReader::new(zip.by_index(*i).context(read_error)?);
+            let mut bank = Bank::default();
+            if filename.starts_with("term_bank_") {
+                // It's a term bank.
+                let bank_number: u32 = filename
+                    ["term_bank_".len()..(filename.len() - ".json".len())]
+                    .parse()
+                    .unwrap_or(0);
+                let mut row = 0usize;
+                for_each_row(reader, |item| {
+                    row += 1;
+                    if !is_valid_term_row(&item) {
+                        verbose!(
+                            "Skipping malformed row {} of {} in {}.",
+                            row,
+                            filename,
+                            path.display()
+                        );
+                        bank.skipped_rows += 1;
+                        return;
                     }
-                    e.tags.extend(entry.tags.drain(..));
-                    e.tags.sort_unstable();
-                    e.tags.dedup();
-                }
-            }
-        } else if filename.starts_with("kanji_bank_") {
-            // It's a kanji bank.
-            for item in json.as_array().unwrap().iter() {
-                let entry = KanjiEntry {
-                    dict_name: dictionary_title.clone(),
-                    kanji: item.get(0).unwrap().as_str().unwrap().trim().into(),
-                    onyomi: item
-                        .get(1)
-                        .unwrap()
-                        .as_str()
-                        .unwrap()
-                        .split(" ")
-                        .map(|s| s.trim().into())
-                        .filter(|s: &String| !s.is_empty())
-                        .collect(),
-                    kunyomi: item
+                    let mut tags: Vec<String> = item
                         .get(2)
                         .unwrap()
                         .as_str()
                         .unwrap()
                         .split(" ")
+                        .chain(item.get(7).unwrap().as_str().unwrap().split(" "))
                         .map(|s| s.trim().into())
                         .filter(|s: &String| !s.is_empty())
-                        .collect(),
-                    meanings: item
-                        .get(4)
-                        .unwrap()
-                        .as_array()
-                        .unwrap()
-                        .iter()
-                        .map(|s| s.as_str().unwrap().trim().into())
-                        .filter(|s: &String| !s.is_empty())
-                        .collect(),
-                };
-                kanji_entries.push(entry);
+                        .collect();
+                    tags.sort();
+                    tags.dedup();
+
+                    let mut entry = TermEntry {
+                        dict_name: dictionary_title.clone(),
+                        writing: item.get(0).unwrap().as_str().unwrap().trim().into(),
+                        reading: item.get(1).unwrap().as_str().unwrap().trim().into(),
+                        infl: match item.get(3).unwrap().as_str().unwrap().trim() {
+                            "v1" => InflectionType::VerbIchidan,
+                            "v5" => InflectionType::VerbGodan,
+                            "vs" => InflectionType::VerbSuru,
+                            "vk" => InflectionType::VerbKuru,
+                            "adj-i" => InflectionType::IAdjective,
+                            _ => InflectionType::None,
+                        },
+                        commonness: item.get(4).unwrap().as_f64().unwrap() as i32,
+                        definitions: Definition::List((
+                            "".into(),
+                            vec![Definition::Def(
+                                item.get(5)
+                                    .unwrap()
+                                    .as_array()
+                                    .unwrap()
+                                    .iter()
+                                    .map(|d| glossary_text(d, &mut images))
+                                    .filter(|d| !d.is_empty())
+                                    .collect::<Vec<String>>()
+                                    .join("; "),
+                            )],
+                        )),
+                        tags: tags,
+                    };
+
+                    if is_name_dict {
+                        bank.names.push(entry);
+                    } else {
+                        let definitions: Vec<Definition> =
+                            match std::mem::replace(&mut entry.definitions, Definition::new()) {
+                                Definition::List((_, mut list_from)) => list_from
+                                    .drain(..)
+                                    .filter_map(|d| {
+                                        process_definition(
+                                            &entry.writing,
+                                            &entry.reading,
+                                            &header_stripping,
+                                            &dividers,
+                                            d,
+                                        )
+                                    })
+                                    .collect(),
+                                Definition::Def(s) => vec![Definition::Def(s)],
+                            };
+                        let sequence = item.get(6).and_then(|s| s.as_i64()).unwrap_or(0);
+                        bank.terms.push(TermRow {
+                            entry,
+                            order: (sequence, bank_number, row),
+                            definitions,
+                        });
+                    }
+                })
+                .map_err(|e| bank_error(filename, e))
+                .context(read_error)?;
+            } else {
+                // It's a kanji bank.
+                let mut row = 0usize;
+                for_each_row(reader, |item| {
+                    row += 1;
+                    if !is_valid_kanji_row(&item) {
+                        verbose!(
+                            "Skipping malformed row {} of {} in {}.",
+                            row,
+                            filename,
+                            path.display()
+                        );
+                        bank.skipped_rows += 1;
+                        return;
+                    }
+                    let entry = KanjiEntry {
+                        dict_name: dictionary_title.clone(),
+                        kanji: item.get(0).unwrap().as_str().unwrap().trim().into(),
+                        onyomi: item
+                            .get(1)
+                            .unwrap()
+                            .as_str()
+                            .unwrap()
+                            .split(" ")
+                            .map(|s| s.trim().into())
+                            .filter(|s: &String| !s.is_empty())
+                            .collect(),
+                        kunyomi: item
+                            .get(2)
+                            .unwrap()
+                            .as_str()
+                            .unwrap()
+                            .split(" ")
+                            .map(|s| s.trim().into())
+                            .filter(|s: &String| !s.is_empty())
+                            .collect(),
+                        meanings: item
+                            .get(4)
+                            .unwrap()
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .map(|s| s.as_str().unwrap().trim().into())
+                            .filter(|s: &String| !s.is_empty())
+                            .collect(),
+                    };
+                    bank.kanji.push(entry);
+                })
+                .map_err(|e| bank_error(filename, e))
+                .context(read_error)?;
             }
+            Ok(bank)
+        })
+        .collect::<Result<Vec<Bank>, Error>>()?;
+
+    // Build our entry list(s) from the banks' rows.
+    let mut term_entries: HashMap<_, TermEntry> = HashMap::new();
+    let mut term_definitions: HashMap<_, Vec<_>> = HashMap::new(); // Key -> [(order, definitions)]
+    let mut name_entries = Vec::new();
+    let mut kanji_entries = Vec::new();
+    let mut skipped_rows = 0usize;
+    for bank in banks {
+        for TermRow {
+            entry,
+            order,
+            definitions,
+        } in bank.terms
+        {
+            // We do some extra work here to merge the definitions from
+            // multiple entries for the same word.
+            let key = (entry.writing.clone(), entry.reading.clone());
+            let e = term_entries.entry(key.clone()).or_insert(TermEntry {
+                dict_name: dictionary_title.clone(),
+                writing: entry.writing.clone(),
+                reading: entry.reading.clone(),
+                definitions: Definition::List(("".into(), Vec::new())),
+                infl: entry.infl,
+                tags: Vec::new(),
+                commonness: entry.commonness,
+            });
+
+            // The definitions are put in order once all the rows are
+            // read: by sequence number, and then by position in the
+            // dictionary.
+            term_definitions
+                .entry(key)
+                .or_insert(Vec::new())
+                .push((order, definitions));
+
+            e.tags.extend(entry.tags);
+            e.tags.sort_unstable();
+            e.tags.dedup();
+        }
+        name_entries.extend(bank.names);
+        kanji_entries.extend(bank.kanji);
+        skipped_rows += bank.skipped_rows;
+    }
+
+    if skipped_rows > 0 {
+        warning!(
+            "skipped {} malformed rows in {}.",
+            skipped_rows,
+            path.display()
+        );
+    }
+
+    // Fill in the term entries' definitions in order.
+    for (key, mut definitions) in term_definitions.drain() {
+        definitions.sort_by_key(|(order, _)| *order);
+        let e = term_entries.get_mut(&key).unwrap();
+        assert!(e.definitions.is_list());
+        if let Definition::List((_, ref mut list_to)) = e.definitions {
+            list_to.extend(definitions.drain(..).flat_map(|(_, d)| d));
+        }
+    }
+
+    // Resolve redirect entries, whose whole definition just refers to
+    // another word (e.g. "⇒ 猫"), by including that word's definitions.
+    // Only targets that match a single entry are resolved: a reading like
+    // "こうしょう" is shared by many words, and picking one of them would
+    // likely be wrong.  Ambiguous redirects are left as they are.
+    let mut writing_index: HashMap<String, Vec<&(String, String)>> = HashMap::new();
+    let mut reading_index: HashMap<String, Vec<&(String, String)>> = HashMap::new();
+    for (key, e) in term_entries.iter() {
+        if redirect_target(&e.definitions).is_none() {
+            writing_index
+                .entry(e.writing.clone())
+                .or_default()
+                .push(key);
+            reading_index
+                .entry(e.reading.clone())
+                .or_default()
+                .push(key);
         }
     }
+    let redirects: Vec<_> = term_entries
+        .iter()
+        .filter_map(|(key, e)| {
+            let target = redirect_target(&e.definitions)?;
+            let target_keys = writing_index
+                .get(&target)
+                .or_else(|| reading_index.get(&target))?;
+            match target_keys[..] {
+                [target_key] => Some((key.clone(), target, target_key.clone())),
+                _ => None,
+            }
+        })
+        .collect();
+    for (key, target, target_key) in redirects {
+        let header = format!("⇒ {}", target);
+        let mut target_definitions = term_entries[&target_key].definitions.clone();
+        let definitions = loop {
+            target_definitions = match target_definitions {
+                // Unwrap lists that only wrap a single other list.
+                Definition::List((h, mut list))
+                    if h.trim().is_empty() && list.len() == 1 && list[0].is_list() =>
+                {
+                    list.remove(0)
+                }
+                Definition::List((h, list)) if h.trim().is_empty() => {
+                    break Definition::List((header, list));
+                }
+                d => break Definition::List((header, vec![d])),
+            };
+        };
+        term_entries.get_mut(&key).unwrap().definitions = definitions;
+    }
 
     // Convert the term entries into a simple `Vec`.
     let mut term_entries: Vec<TermEntry> = term_entries.drain().map(|kv| kv.1).collect();
@@ -298,14 +526,376 @@ pub fn parse(path: &Path) -> std::io::Result<(Vec<TermEntry>, Vec<TermEntry>, Ve
     Ok((term_entries, name_entries, kanji_entries))
 }
 
+/// Checks that a term bank row has all of the fields that are used, with
+/// the right types: `[writing, reading, definition tags, rules, score,
+/// glossary, sequence, term tags]`.  The sequence number is optional.
+fn is_valid_term_row(item: &Value) -> bool {
+    let field = |i: usize| item.get(i).unwrap_or(&Value::Null);
+    [0, 1, 2, 3, 7].iter().all(|i| field(*i).is_string())
+        && field(4).is_number()
+        && field(5).is_array()
+}
+
+/// Checks that a kanji bank row has all of the fields that are used, with
+/// the right types: `[kanji, onyomi, kunyomi, tags, meanings, ...]`.
+fn is_valid_kanji_row(item: &Value) -> bool {
+    let field = |i: usize| item.get(i).unwrap_or(&Value::Null);
+    [0, 1, 2].iter().all(|i| field(*i).is_string())
+        && matches!(field(4), Value::Array(meanings) if meanings.iter().all(|m| m.is_string()))
+}
+
+/// Calls `f` with each row of a bank file, which is a json array of rows,
+/// without loading the whole file into memory.
+fn for_each_row<R: Read, F: FnMut(Value)>(reader: R, f: F) -> serde_json::Result<()> {
+    struct RowVisitor<F>(F);
+
+    impl<'de, F: FnMut(Value)> Visitor<'de> for RowVisitor<F> {
+        type Value = ();
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a list of rows")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error> {
+            while let Some(row) = seq.next_element::<Value>()? {
+                (self.0)(row);
+            }
+            Ok(())
+        }
+    }
+
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    deserializer.deserialize_seq(RowVisitor(f))?;
+    deserializer.end()
+}
+
+/// Marks an embedded image in definition text.  The image's data URI goes
+/// between the markers, and is turned into an `<img>` tag when the
+/// definition is converted to html.
+const IMAGE_START: char = '\u{E000}';
+const IMAGE_END: char = '\u{E001}';
+
+/// What images are replaced with when they aren't embedded.
+const IMAGE_PLACEHOLDER: &str = "[image]";
+
+lazy_static! {
+    static ref IMG_TAG: Regex =
+        Regex::new(r#"(?i)<img\b(?:[^>]*?\bsrc\s*=\s*["']([^"']*)["'])?[^>]*>"#).unwrap();
+}
+
+/// Converts an item of a term's glossary to text.
+///
+/// Besides plain strings, this handles the text and images of structured
+/// content, but without any of its formatting.
+fn glossary_text(gloss: &Value, images: &mut Option<Images>) -> String {
+    match gloss {
+        // Some dictionaries have html image tags in their plain text.
+        Value::String(s) => IMG_TAG
+            .replace_all(s.trim(), |caps: &regex::Captures| match caps.get(1) {
+                Some(path) => image_text(path.as_str(), images),
+                None => IMAGE_PLACEHOLDER.into(),
+            })
+            .into(),
+        Value::Object(object) => match object.get("type").and_then(|t| t.as_str()) {
+            Some("text") => object
+                .get("text")
+                .and_then(|t| t.as_str())
+                .unwrap_or("")
+                .trim()
+                .into(),
+            Some("image") => image_text(
+                object.get("path").and_then(|p| p.as_str()).unwrap_or(""),
+                images,
+            ),
+            Some("structured-content") => match object.get("content") {
+                Some(content) => structured_content_text(content, images).trim().into(),
+                None => String::new(),
+            },
+            _ => String::new(),
+        },
+        _ => String::new(),
+    }
+}
+
+fn structured_content_text(content: &Value, images: &mut Option<Images>) -> String {
+    match content {
+        Value::String(s) => s.clone(),
+        Value::Array(list) => list
+            .iter()
+            .map(|c| structured_content_text(c, images))
+            .collect(),
+        Value::Object(node) => {
+            let tag = node.get("tag").and_then(|t| t.as_str()).unwrap_or("");
+            let inner = match node.get("content") {
+                Some(c) => structured_content_text(c, images),
+                None => String::new(),
+            };
+            match tag {
+                "img" => image_text(
+                    node.get("path").and_then(|p| p.as_str()).unwrap_or(""),
+                    images,
+                ),
+                "br" => "\n".into(),
+                // Ruby readings would just clutter the plain text.
+                "rt" | "rp" => String::new(),
+                "div" | "ol" | "ul" | "li" | "table" | "tr" | "details" | "summary" => {
+                    format!("{}\n", inner.trim_end())
+                }
+                _ => inner,
+            }
+        }
+        _ => String::new(),
+    }
+}
+
+/// The images of a dictionary being parsed, for embedding in definitions.
+struct Images {
+    zip: archive::Archive,
+    index: HashMap<String, usize>, // Member name -> member index
+}
+
+/// Returns the definition text for an image in the dictionary zip: either
+/// the image itself, embedded as a data URI, or a placeholder if images
+/// aren't being embedded.
+fn image_text(path: &str, images: &mut Option<Images>) -> String {
+    let images = match images {
+        Some(images) => images,
+        None => return IMAGE_PLACEHOLDER.into(),
+    };
+
+    let extension = path.rsplit('.').next().unwrap_or("").to_lowercase();
+    let mime = match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        _ => return IMAGE_PLACEHOLDER.into(),
+    };
+    let mut data = Vec::new();
+    let loaded = match images.index.get(path) {
+        Some(&i) => match images.zip.by_index(i) {
+            Ok(mut f) => f.read_to_end(&mut data).is_ok(),
+            Err(_) => false,
+        },
+        None => false,
+    };
+    if loaded {
+        format!(
+            "{}{}{}",
+            IMAGE_START,
+            html::data_uri(mime, &data),
+            IMAGE_END
+        )
+    } else {
+        IMAGE_PLACEHOLDER.into()
+    }
+}
+
+/// Escapes definition text for html, turning embedded images into `<img>`
+/// tags.
+fn definition_text_to_html(text: &str) -> String {
+    let mut html = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(IMAGE_START) {
+        let end = match rest[start..].find(IMAGE_END) {
+            Some(end) => start + end,
+            None => break,
+        };
+        html.push_str(&html::escape_lines(&rest[..start]));
+        html.push_str(&format!(
+            "<img src=\"{}\" style=\"max-width: 100%;\"/>",
+            html::escape_attr(&rest[(start + IMAGE_START.len_utf8())..end])
+        ));
+        rest = &rest[(end + IMAGE_END.len_utf8())..];
+    }
+    html.push_str(&html::escape_lines(rest));
+    html
+}
+
+/// If a definition is just a reference to another word, like "⇒ 猫" or
+/// "→ねこ【猫】", returns that word.
+fn redirect_target(def: &Definition) -> Option<String> {
+    let text = match def {
+        Definition::List((header, list)) if header.trim().is_empty() && list.len() == 1 => {
+            match &list[0] {
+                Definition::Def(text) => text.trim(),
+                _ => return None,
+            }
+        }
+        Definition::Def(text) => text.trim(),
+        _ => return None,
+    };
+    let rest = text.strip_prefix(&['⇒', '⇨', '→', '＝'][..])?.trim();
+    if rest.is_empty() || rest.contains('\n') || rest.chars().count() > 20 {
+        return None;
+    }
+
+    // Prefer the written form in brackets, if any.
+    let target = match (rest.find('【'), rest.find('】')) {
+        (Some(a), Some(b)) if a < b => &rest[(a + '【'.len_utf8())..b],
+        _ => rest,
+    };
+    let target = target
+        .split(|c: char| c.is_whitespace() || "・（(".contains(c))
+        .next()
+        .unwrap()
+        .trim();
+
+    if target.is_empty() {
+        None
+    } else {
+        Some(target.into())
+    }
+}
+
+/// Reads the title and revision of a Yomichan dictionary from its
+/// index.json, as they're given there.
+pub fn read_title(path: &Path) -> Option<(String, String)> {
+    let mut zip_in = archive::open(path).ok()?;
+    let index = archive::find_member(&mut zip_in, "index.json").ok()??;
+    let mut text = String::new();
+    zip_in
+        .by_index(index)
+        .ok()?
+        .read_to_string(&mut text)
+        .ok()?;
+    let index_json: Value = serde_json::from_str(&text).ok()?;
+    let field = |name: &str| {
+        index_json
+            .get(name)
+            .and_then(|value| value.as_str())
+            .unwrap_or("")
+            .to_string()
+    };
+    Some((field("title"), field("revision")))
+}
+
+/// Parses the term frequencies from a Yomichan frequency dictionary.
+///
+/// Returns a map from (term, reading) to the term's frequency rank, where
+/// the reading is empty for frequencies that don't specify one.
+pub fn parse_frequencies(path: &Path) -> Result<HashMap<(String, String), u32>, Error> {
+    let read_error = || format!("failed to read frequency dictionary '{}'", path.display());
+    let invalid = |message: String| {
+        Error::invalid(
+            format!("frequency dictionary '{}' isn't valid", path.display()),
+            message,
+        )
+    };
+
+    let mut zip_in = archive::open(path)
+        .context(|| format!("failed to open frequency dictionary '{}'", path.display()))?;
+
+    let mut frequencies = HashMap::new();
+    for i in 0..zip_in.len() {
+        let mut f = zip_in.by_index(i).context(read_error)?;
+        let filename = archive::member_name(f.name_raw());
+        if !filename.starts_with("term_meta_bank_") || !filename.ends_with(".json") {
+            continue;
+        }
+
+        let mut text = String::new();
+        f.read_to_string(&mut text).context(read_error)?;
+        let json: Value = serde_json::from_str(&text)
+            .map_err(|e| invalid(format!("{}: invalid json ({})", filename, e)))?;
+
+        for item in json
+            .as_array()
+            .ok_or_else(|| invalid(format!("{} isn't a list of frequencies", filename)))?
+            .iter()
+        {
+            if item.get(1).and_then(|m| m.as_str()) != Some("freq") {
+                continue;
+            }
+            let (term, data) = match (item.get(0).and_then(|t| t.as_str()), item.get(2)) {
+                (Some(term), Some(data)) => (term, data),
+                _ => continue,
+            };
+
+            // The data is either the frequency itself, or an object with
+            // the reading and the frequency.
+            let (reading, frequency) = match data.get("frequency") {
+                Some(frequency) => (
+                    data.get("reading").and_then(|r| r.as_str()).unwrap_or(""),
+                    frequency,
+                ),
+                None => ("", data),
+            };
+
+            // And the frequency is either a number, or an object with the
+            // number as its value.
+            let rank = match frequency
+                .as_f64()
+                .or_else(|| frequency.get("value").and_then(|v| v.as_f64()))
+            {
+                Some(rank) => rank.max(0.0) as u32,
+                None => continue,
+            };
+
+            let e = frequencies
+                .entry((term.trim().into(), reading.trim().into()))
+                .or_insert(rank);
+            *e = (*e).min(rank);
+        }
+    }
+
+    Ok(frequencies)
+}
+
+/// Divider regexes for the definitions of common native Japanese
+/// dictionaries, keyed by a part of their title.  The first match is used.
+///
+/// The (?m) puts the regexes into multi-line mode, so that ^ will match
+/// both newlines and start of text.
+const BUILT_IN_DIVIDERS: &[(&str, &[&str])] = &[
+    ("明鏡", &["(?m)^[❶-❿]", "(?m)^[㋐-㋾]"]),
+    (
+        "新明解",
+        &[
+            "(?m)^［[一二三四五六七八九十]+］",
+            "(?m)^[①-⑳]",
+            "(?m)^[㋐-㋾]",
+        ],
+    ),
+    (
+        "大辞泉",
+        &["(?m)^[㊀-㊉]", "(?m)^[１-９][０-９]?　", "(?m)^[㋐-㋾]"],
+    ),
+    ("広辞苑", &["(?m)^[㊀-㊉]", "(?m)^[①-⑳]", "(?m)^[㋐-㋾]"]),
+    // 三省堂　スーパー大辞林, which is also the fallback for other
+    // dictionaries, since it probably works for some of them as well.
+    (
+        "",
+        &[
+            "(?m)^■[一二三四五六七八九十]+■",
+            "(?m)^[❶❷❸❹❺❻❼❽❾❿]+",
+            "(?m)^（[０１２３４５６７８９]+）",
+        ],
+    ),
+];
+
+/// Returns the built-in divider regexes for the dictionary with the given
+/// (normalized) title.
+fn built_in_dividers(title: &str) -> Vec<Regex> {
+    let (_, dividers) = BUILT_IN_DIVIDERS
+        .iter()
+        .find(|(name, _)| title.contains(name))
+        .unwrap();
+    dividers.iter().map(|d| Regex::new(d).unwrap()).collect()
+}
+
 /// Recursively process definitions.
 ///
+/// `header_stripping` is how to strip entry headers from the definitions.
 /// The `dividers` regex's are for further splitting definitions into a
 /// deeper hierarchy.  The first reghex in the list is used for the top
 /// level split, the second for the second level, and so on.
 fn process_definition(
     writing: &str,
     reading: &str,
+    header_stripping: &HeaderStripping,
     dividers: &[Regex],
     def: Definition,
 ) -> Option<Definition> {
@@ -313,7 +903,7 @@ fn process_definition(
         Definition::List((header, mut list)) => {
             let mut processed_list: Vec<_> = list
                 .drain(..)
-                .filter_map(|d| process_definition(writing, reading, dividers, d))
+                .filter_map(|d| process_definition(writing, reading, header_stripping, dividers, d))
                 .collect();
             if processed_list.is_empty() {
                 None
@@ -335,16 +925,23 @@ fn process_definition(
             // annoyingly present in most of the native Japanese dictionaries
             // converted to Yomichan format.
             //
-            // Our heuristic is that if there's multiple lines, and the first
-            // line contains the Japanese word itself, then the first line is
-            // probably a header and we can drop it.
-            s = {
-                let header_indicator_idx = s.find(writing).or_else(|| s.find(reading));
-                let first_line_break_idx = s.find("\n");
-                match (header_indicator_idx, first_line_break_idx) {
-                    (Some(a), Some(b)) if a < b && (b + 1) < s.len() => (&s[(b + 1)..]).into(),
-                    _ => s,
+            // Our default heuristic is that if there's multiple lines, and the
+            // first line contains the Japanese word itself, then the first line
+            // is probably a header and we can drop it.
+            s = match header_stripping {
+                HeaderStripping::Off => s,
+                HeaderStripping::FirstLine => {
+                    let header_indicator_idx = s.find(writing).or_else(|| s.find(reading));
+                    let first_line_break_idx = s.find("\n");
+                    match (header_indicator_idx, first_line_break_idx) {
+                        (Some(a), Some(b)) if a < b && (b + 1) < s.len() => (&s[(b + 1)..]).into(),
+                        _ => s,
+                    }
                 }
+                HeaderStripping::Regex(regex) => match regex.find(&s) {
+                    Some(m) if m.start() == 0 && m.end() < s.len() => (&s[m.end()..]).into(),
+                    _ => s,
+                },
             };
 
             Some(split_definition_text(&s, dividers))
@@ -380,7 +977,7 @@ fn split_definition_text(s: &str, dividers: &[Regex]) -> Definition {
     }
 
     // If none of the dividers matched, just return the text as-is.
-    Definition::Def(s.trim().replace("\n", "<br>"))
+    Definition::Def(s.trim().into())
 }
 
 /// Converts a defintion(s) to html.
@@ -398,7 +995,7 @@ pub fn definition_to_html(def: &Definition, total_depth: usize, ordered_list: bo
             } else {
                 if !header.trim().is_empty() {
                     html.push_str("<p>");
-                    html.push_str(header.trim());
+                    html.push_str(&definition_text_to_html(header.trim()));
                     html.push_str("</p>");
                 }
                 if ordered_list {
@@ -437,6 +1034,7 @@ pub fn definition_to_html(def: &Definition, total_depth: usize, ordered_list: bo
         }
 
         &Definition::Def(ref s) => {
+            let s = &definition_text_to_html(s);
             if total_depth == 0 {
                 if ordered_list {
                     html.push_str("<ol><li>");
@@ -455,3 +1053,252 @@ pub fn definition_to_html(def: &Definition, total_depth: usize, ordered_list: bo
 
     html
 }
+
+//----------------------------------------------------------------
+// Writing.
+
+/// Number of terms per term bank file, as in dictionaries made with
+/// Yomichan's own import tools.
+const TERM_BANK_SIZE: usize = 10000;
+
+/// Writes a (format 3) Yomichan dictionary .zip file.
+///
+/// Each entry is a list of terms and a plain-text definition, whose lines
+/// become separate glossary items.
+pub fn write_dictionary(
+    entries: &[(Vec<String>, String)],
+    title: &str,
+    output_path: &Path,
+) -> std::io::Result<()> {
+    let mut terms = Vec::new();
+    for (sequence, (words, definition)) in entries.iter().enumerate() {
+        let glossary: Vec<&str> = definition.lines().collect();
+        for word in words.iter() {
+            terms.push(serde_json::json!([
+                word,
+                "",
+                "",
+                "",
+                0,
+                glossary,
+                sequence + 1,
+                ""
+            ]));
+        }
+    }
+
+    let mut zip_out = zip::ZipWriter::new(std::io::BufWriter::new(File::create(output_path)?));
+    zip_out.start_file("index.json", zip::write::FileOptions::default())?;
+    zip_out.write_all(
+        serde_json::json!({
+            "title": title,
+            "revision": "kobo_jp_dict export",
+            "format": 3,
+            "sequenced": true,
+        })
+        .to_string()
+        .as_bytes(),
+    )?;
+    for (i, bank) in terms.chunks(TERM_BANK_SIZE).enumerate() {
+        zip_out.start_file(
+            format!("term_bank_{}.json", i + 1),
+            zip::write::FileOptions::default(),
+        )?;
+        zip_out.write_all(serde_json::to_string(bank)?.as_bytes())?;
+    }
+    zip_out.finish()?;
+
+    Ok(())
+}
+
+//----------------------------------------------------------------
+// Validation.
+
+/// The kinds of bank files in a Yomichan dictionary, by file name prefix.
+const BANK_KINDS: &[&str] = &[
+    "term_bank_",
+    "kanji_bank_",
+    "term_meta_bank_",
+    "kanji_meta_bank_",
+    "tag_bank_",
+];
+
+/// The inflection rules that are used for conjugating words.  Words with
+/// other rules aren't conjugated.
+const KNOWN_RULES: &[&str] = &["v1", "v5", "vs", "vk", "adj-i"];
+
+/// A report on the contents of a Yomichan dictionary, and on anything in
+/// it that won't be converted.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub title: String,
+    pub revision: String,
+
+    /// Problems that keep the dictionary from being used at all.
+    pub problems: Vec<String>,
+
+    /// (file name, row count, malformed row count) of each bank file, in
+    /// archive order.  Malformed rows are skipped when converting.
+    pub banks: Vec<(String, usize, usize)>,
+
+    /// Glossary items with structured content, whose formatting is lost,
+    /// and images (placeholders unless embedded).
+    pub structured_content: usize,
+    pub images: usize,
+
+    /// Term rows whose glossary has no text, e.g. because it's only
+    /// images or of an unsupported type.  (Writing, reading) examples.
+    pub empty_definitions: usize,
+    pub empty_definition_examples: Vec<(String, String)>,
+
+    /// Tags used by rows that no tag bank defines, and inflection rules
+    /// that aren't supported, with the number of rows using them.
+    pub undefined_tags: BTreeMap<String, usize>,
+    pub unknown_rules: BTreeMap<String, usize>,
+}
+
+/// Checks a Yomichan dictionary without converting it.
+///
+/// Only errors from reading the file are returned as errors.  Problems
+/// with the dictionary's contents go in the report.
+pub fn validate(path: &Path) -> Result<Report, Error> {
+    let read_error = || format!("failed to read Yomichan dictionary '{}'", path.display());
+
+    let mut zip_in = archive::open(path)
+        .context(|| format!("failed to open Yomichan dictionary '{}'", path.display()))?;
+    let mut report = Report::default();
+
+    // Check the index.
+    match archive::find_member(&mut zip_in, "index.json").context(read_error)? {
+        Some(index) => {
+            let mut text = String::new();
+            zip_in
+                .by_index(index)
+                .context(read_error)?
+                .read_to_string(&mut text)
+                .context(read_error)?;
+            match serde_json::from_str::<Value>(&text) {
+                Ok(index_json) => {
+                    let field = |name: &str| {
+                        index_json
+                            .get(name)
+                            .and_then(|v| v.as_str())
+                            .map(|v| v.to_string())
+                    };
+                    match field("title") {
+                        Some(title) => report.title = title,
+                        None => report.problems.push("index.json has no title".into()),
+                    }
+                    report.revision = field("revision").unwrap_or_default();
+                    match index_json.get("format") {
+                        Some(Value::Number(version)) if version.as_i64() == Some(3) => {}
+                        Some(version) => report.problems.push(format!(
+                            "format version {} isn't supported, only version 3",
+                            version
+                        )),
+                        None => report
+                            .problems
+                            .push("index.json has no format version".into()),
+                    }
+                }
+                Err(e) => report
+                    .problems
+                    .push(format!("index.json isn't valid json ({})", e)),
+            }
+        }
+        None => report.problems.push("there's no index.json".into()),
+    }
+
+    // Go through the bank files.
+    let mut defined_tags = HashSet::new();
+    let mut used_tags: BTreeMap<String, usize> = BTreeMap::new();
+    for i in 0..zip_in.len() {
+        let f = zip_in.by_index(i).context(read_error)?;
+        let filename = archive::member_name(f.name_raw());
+        if !filename.ends_with(".json") || !BANK_KINDS.iter().any(|k| filename.starts_with(k)) {
+            continue;
+        }
+
+        let mut rows = 0;
+        let mut malformed = 0;
+        let result = for_each_row(BufReader::new(f), |item| {
+            rows += 1;
+            let mut use_tags = |tags: Option<&Value>| {
+                let tags = tags.and_then(|t| t.as_str()).unwrap_or("");
+                for tag in tags.split_whitespace() {
+                    *used_tags.entry(tag.into()).or_insert(0) += 1;
+                }
+            };
+            if filename.starts_with("term_bank_") {
+                if !is_valid_term_row(&item) {
+                    malformed += 1;
+                    return;
+                }
+                use_tags(item.get(2));
+                use_tags(item.get(7));
+                for rule in item[3].as_str().unwrap().split_whitespace() {
+                    if !KNOWN_RULES.contains(&rule) {
+                        *report.unknown_rules.entry(rule.into()).or_insert(0) += 1;
+                    }
+                }
+
+                let glossary = item[5].as_array().unwrap();
+                for gloss in glossary.iter() {
+                    match gloss.get("type").and_then(|t| t.as_str()) {
+                        Some("structured-content") => report.structured_content += 1,
+                        Some("image") => report.images += 1,
+                        _ => {}
+                    }
+                }
+                if glossary
+                    .iter()
+                    .all(|gloss| glossary_text(gloss, &mut None).is_empty())
+                {
+                    report.empty_definitions += 1;
+                    if report.empty_definition_examples.len() < 5 {
+                        report.empty_definition_examples.push((
+                            item[0].as_str().unwrap().into(),
+                            item[1].as_str().unwrap().into(),
+                        ));
+                    }
+                }
+            } else if filename.starts_with("kanji_bank_") {
+                if !is_valid_kanji_row(&item) {
+                    malformed += 1;
+                    return;
+                }
+                use_tags(item.get(3));
+            } else if filename.starts_with("tag_bank_") {
+                match item.get(0).and_then(|t| t.as_str()) {
+                    Some(tag) => {
+                        defined_tags.insert(tag.to_string());
+                    }
+                    None => malformed += 1,
+                }
+            }
+        });
+        if let Err(e) = result {
+            report
+                .problems
+                .push(format!("{} isn't valid json ({})", filename, e));
+        }
+        report.banks.push((filename, rows, malformed));
+    }
+
+    if !report
+        .banks
+        .iter()
+        .any(|(name, _, _)| name.starts_with("term_bank_") || name.starts_with("kanji_bank_"))
+    {
+        report
+            .problems
+            .push("there are no term or kanji banks, so there's nothing to convert".into());
+    }
+
+    report.undefined_tags = used_tags
+        .into_iter()
+        .filter(|(tag, _)| !defined_tags.contains(tag))
+        .collect();
+
+    Ok(report)
+}
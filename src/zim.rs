@@ -0,0 +1,369 @@
+//! Types and functions for outputting a dictionary as a ZIM archive,
+//! readable offline in Kiwix.
+//!
+//! See https://wiki.openzim.org/wiki/ZIM_file_format for the format
+//! details.  We write the old-style (major version 5) namespace layout,
+//! with articles in the `A` namespace and metadata in the `M` namespace,
+//! since that's what the widest range of Kiwix versions can read.
+
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+
+use md5::{Digest, Md5};
+
+use crate::error::Error;
+use crate::kobo::Entry;
+use crate::output::OutputWriter;
+
+const ZIM_MAGIC: u32 = 72173914;
+const HEADER_SIZE: u64 = 80;
+
+/// Clusters are filled up to roughly this many bytes before starting a
+/// new one.
+const CLUSTER_TARGET_SIZE: usize = 1 << 20;
+
+/// Cluster compression type: no compression.
+const CLUSTER_UNCOMPRESSED: u8 = 1;
+
+const MIME_TYPES: &[&str] = &["text/html", "text/plain"];
+const MIME_HTML: u16 = 0;
+const MIME_TEXT: u16 = 1;
+
+const MAIN_PAGE_URL: &str = "index.html";
+
+struct DirEntry {
+    namespace: u8,
+    url: String,
+    title: String,
+    mime: u16,
+    content: Vec<u8>,
+}
+
+/// The writer of `--format zim`.
+pub struct ZimWriter {
+    metadata: Metadata,
+}
+
+impl ZimWriter {
+    pub fn new(lang: &str) -> ZimWriter {
+        ZimWriter {
+            metadata: Metadata::new(lang),
+        }
+    }
+}
+
+impl OutputWriter for ZimWriter {
+    fn write(&self, entries: &[Entry], path: &Path) -> Result<(), Error> {
+        Ok(write_dictionary(entries, &self.metadata, path)?)
+    }
+}
+
+/// The language-dependent parts of the ZIM metadata.
+pub struct Metadata {
+    pub title: String,
+    /// ISO 639-3 code, as the ZIM format requires.
+    pub language: String,
+    pub description: String,
+}
+
+/// ISO 639-1 code, ISO 639-3 code, English name.
+const LANGUAGES: &[(&str, &str, &str)] = &[
+    ("ja", "jpn", "Japanese"),
+    ("en", "eng", "English"),
+    ("de", "deu", "German"),
+    ("fr", "fra", "French"),
+    ("es", "spa", "Spanish"),
+    ("it", "ita", "Italian"),
+    ("nl", "nld", "Dutch"),
+    ("pt", "por", "Portuguese"),
+    ("ru", "rus", "Russian"),
+    ("zh", "zho", "Chinese"),
+    ("ko", "kor", "Korean"),
+];
+
+impl Metadata {
+    pub fn new(lang: &str) -> Metadata {
+        let known = LANGUAGES.iter().find(|l| l.0 == lang || l.1 == lang);
+        Metadata {
+            title: if lang == "ja" {
+                "日本語 - English".into()
+            } else {
+                format!("{} dictionary", lang)
+            },
+            // Unknown codes are passed through as given.
+            language: known.map_or(lang, |l| l.1).into(),
+            description: match known {
+                Some(l) => format!("{} dictionary", l.2),
+                None => format!("Dictionary for language \"{}\"", lang),
+            },
+        }
+    }
+}
+
+pub fn write_dictionary(
+    entries: &[Entry],
+    metadata: &Metadata,
+    output_path: &Path,
+) -> std::io::Result<()> {
+    let title = metadata.title.as_str();
+
+    //----------------------------------------------------------------
+    // Build the article list.
+
+    // key -> Vec<(entry, priority)>
+    let mut key_entries: HashMap<&str, Vec<(&Entry, u32)>> = HashMap::new();
+    for entry in entries.iter() {
+        for key in entry.keys.iter() {
+            key_entries.entry(&key.0).or_default().push((entry, key.1));
+        }
+    }
+
+    let mut dir_entries = Vec::new();
+    for (key, defs) in key_entries.iter_mut() {
+        // Same ordering as the Kobo prefix files: by priority, and then by
+        // inverse definition length.  Ties are broken by definition text,
+        // so that the output doesn't depend on the input order.
+        defs.sort_by(|a, b| {
+            (a.1, -(a.0.definition.len() as isize), &a.0.definition).cmp(&(
+                b.1,
+                -(b.0.definition.len() as isize),
+                &b.0.definition,
+            ))
+        });
+
+        let mut html = String::new();
+        html.push_str("<!DOCTYPE html><html><head><meta charset=\"utf-8\"/><title>");
+        html.push_str(&crate::html::escape_text(key));
+        html.push_str("</title></head><body>");
+        for (entry, _) in defs.iter() {
+            // JMDict entries get an anchor with their sequence number, so
+            // they can be linked to directly.
+            match entry.jmdict_seq {
+                Some(seq) => {
+                    html.push_str(&format!("<div id=\"jmdict-{}\">", seq));
+                    html.push_str(&entry.definition);
+                    html.push_str("</div>");
+                }
+                None => html.push_str(&entry.definition),
+            }
+        }
+        html.push_str("</body></html>");
+
+        dir_entries.push(DirEntry {
+            namespace: b'A',
+            url: (*key).into(),
+            title: String::new(),
+            mime: MIME_HTML,
+            content: html.into_bytes(),
+        });
+    }
+
+    // Main page.
+    dir_entries.push(DirEntry {
+        namespace: b'A',
+        url: MAIN_PAGE_URL.into(),
+        title: title.into(),
+        mime: MIME_HTML,
+        content: format!(
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\"/><title>{0}</title></head>\
+             <body><h1>{0}</h1><p>{1} entries.  Use the search bar to look up words.</p></body></html>",
+            crate::html::escape_text(title),
+            key_entries.len(),
+        )
+        .into_bytes(),
+    });
+
+    // Metadata.
+    for (name, value) in [
+        ("Title", title),
+        ("Language", metadata.language.as_str()),
+        ("Creator", "kobo_jp_dict"),
+        ("Publisher", "kobo_jp_dict"),
+        ("Description", metadata.description.as_str()),
+        ("Name", "kobo_jp_dict"),
+    ]
+    .iter()
+    {
+        dir_entries.push(DirEntry {
+            namespace: b'M',
+            url: (*name).into(),
+            title: String::new(),
+            mime: MIME_TEXT,
+            content: value.as_bytes().into(),
+        });
+    }
+
+    // Directory entries must be sorted by full url (namespace + url).
+    dir_entries.sort_by(|a, b| (a.namespace, &a.url).cmp(&(b.namespace, &b.url)));
+    let main_page_idx = dir_entries
+        .iter()
+        .position(|e| e.namespace == b'A' && e.url == MAIN_PAGE_URL)
+        .unwrap() as u32;
+
+    //----------------------------------------------------------------
+    // Lay out clusters, assigning each entry a (cluster, blob) location.
+
+    let mut clusters: Vec<Vec<u8>> = Vec::new();
+    let mut locations = Vec::with_capacity(dir_entries.len());
+    {
+        let mut blobs: Vec<&[u8]> = Vec::new();
+        let mut size = 0;
+        for entry in dir_entries.iter() {
+            if size >= CLUSTER_TARGET_SIZE {
+                clusters.push(build_cluster(&blobs));
+                blobs.clear();
+                size = 0;
+            }
+            locations.push((clusters.len() as u32, blobs.len() as u32));
+            size += entry.content.len();
+            blobs.push(&entry.content);
+        }
+        if !blobs.is_empty() {
+            clusters.push(build_cluster(&blobs));
+        }
+    }
+
+    //----------------------------------------------------------------
+    // Serialize the directory entries.
+
+    let mut dirents_data = Vec::new();
+    let mut url_ptrs = Vec::with_capacity(dir_entries.len());
+    for (entry, (cluster, blob)) in dir_entries.iter().zip(locations.iter()) {
+        url_ptrs.push(dirents_data.len() as u64);
+        dirents_data.extend_from_slice(&entry.mime.to_le_bytes());
+        dirents_data.push(0); // Parameter length.
+        dirents_data.push(entry.namespace);
+        dirents_data.extend_from_slice(&0u32.to_le_bytes()); // Revision.
+        dirents_data.extend_from_slice(&cluster.to_le_bytes());
+        dirents_data.extend_from_slice(&blob.to_le_bytes());
+        dirents_data.extend_from_slice(entry.url.as_bytes());
+        dirents_data.push(0);
+        dirents_data.extend_from_slice(entry.title.as_bytes());
+        dirents_data.push(0);
+    }
+
+    // Title index: indices into the url-sorted list, sorted by title
+    // (which defaults to the url when empty).
+    let mut title_ptrs: Vec<u32> = (0..dir_entries.len() as u32).collect();
+    title_ptrs.sort_by(|a, b| {
+        let a = &dir_entries[*a as usize];
+        let b = &dir_entries[*b as usize];
+        let a_title = if a.title.is_empty() { &a.url } else { &a.title };
+        let b_title = if b.title.is_empty() { &b.url } else { &b.title };
+        (a.namespace, a_title).cmp(&(b.namespace, b_title))
+    });
+
+    //----------------------------------------------------------------
+    // Compute the file layout.
+
+    let mime_list = {
+        let mut mime_list = Vec::new();
+        for mime in MIME_TYPES.iter() {
+            mime_list.extend_from_slice(mime.as_bytes());
+            mime_list.push(0);
+        }
+        mime_list.push(0);
+        mime_list
+    };
+
+    let mime_list_pos = HEADER_SIZE;
+    let url_ptr_pos = mime_list_pos + mime_list.len() as u64;
+    let title_ptr_pos = url_ptr_pos + 8 * url_ptrs.len() as u64;
+    let dirents_pos = title_ptr_pos + 4 * title_ptrs.len() as u64;
+    let cluster_ptr_pos = dirents_pos + dirents_data.len() as u64;
+    let clusters_pos = cluster_ptr_pos + 8 * clusters.len() as u64;
+    let checksum_pos = clusters_pos + clusters.iter().map(|c| c.len() as u64).sum::<u64>();
+
+    //----------------------------------------------------------------
+    // Write the file.
+
+    let mut out = HashingWriter {
+        inner: BufWriter::new(std::fs::File::create(output_path)?),
+        hasher: Md5::new(),
+    };
+
+    // Header.
+    let uuid = {
+        let mut hasher = Md5::new();
+        hasher.update(title.as_bytes());
+        hasher.update(&dirents_data);
+        hasher.finalize()
+    };
+    out.write_all(&ZIM_MAGIC.to_le_bytes())?;
+    out.write_all(&5u16.to_le_bytes())?; // Major version.
+    out.write_all(&0u16.to_le_bytes())?; // Minor version.
+    out.write_all(&uuid)?;
+    out.write_all(&(dir_entries.len() as u32).to_le_bytes())?;
+    out.write_all(&(clusters.len() as u32).to_le_bytes())?;
+    out.write_all(&url_ptr_pos.to_le_bytes())?;
+    out.write_all(&title_ptr_pos.to_le_bytes())?;
+    out.write_all(&cluster_ptr_pos.to_le_bytes())?;
+    out.write_all(&mime_list_pos.to_le_bytes())?;
+    out.write_all(&main_page_idx.to_le_bytes())?;
+    out.write_all(&u32::MAX.to_le_bytes())?; // Layout page (none).
+    out.write_all(&checksum_pos.to_le_bytes())?;
+
+    // Lists and pointers.
+    out.write_all(&mime_list)?;
+    for ptr in url_ptrs.iter() {
+        out.write_all(&(dirents_pos + ptr).to_le_bytes())?;
+    }
+    for ptr in title_ptrs.iter() {
+        out.write_all(&ptr.to_le_bytes())?;
+    }
+    out.write_all(&dirents_data)?;
+    let mut cluster_pos = clusters_pos;
+    for cluster in clusters.iter() {
+        out.write_all(&cluster_pos.to_le_bytes())?;
+        cluster_pos += cluster.len() as u64;
+    }
+    for cluster in clusters.iter() {
+        out.write_all(cluster)?;
+    }
+
+    // Checksum of everything written so far.
+    let checksum = out.hasher.finalize();
+    out.inner.write_all(&checksum)?;
+    out.inner.flush()?;
+
+    Ok(())
+}
+
+/// Builds an uncompressed cluster from the given blobs.
+fn build_cluster(blobs: &[&[u8]]) -> Vec<u8> {
+    let mut cluster = vec![CLUSTER_UNCOMPRESSED];
+
+    // Offsets are relative to the start of the offset list, and there is
+    // one more offset than there are blobs, marking the end of the data.
+    let mut offset = 4 * (blobs.len() as u32 + 1);
+    cluster.extend_from_slice(&offset.to_le_bytes());
+    for blob in blobs.iter() {
+        offset += blob.len() as u32;
+        cluster.extend_from_slice(&offset.to_le_bytes());
+    }
+    for blob in blobs.iter() {
+        cluster.extend_from_slice(blob);
+    }
+
+    cluster
+}
+
+/// A writer that computes the MD5 checksum of everything written through it.
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Md5,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}